@@ -1,26 +1,51 @@
 // Needed for static linking to work right on Linux.
 extern crate openssl_sys;
 
-use crossbeam::{self, thread::Scope};
+use crossbeam::{self, channel::RecvTimeoutError, thread::Scope};
 use falconeri_common::{
+    cast,
+    compression::Compression,
     prelude::*,
     rest_api::{Client, OutputFilePatch},
     storage::CloudStorage,
     tracing,
     tracing_support::initialize_tracing,
 };
+use sha2::{Digest, Sha256};
 use std::{
     env, fs,
     io::{self, prelude::*},
+    os::unix::process::CommandExt,
     process,
-    sync::{Arc, RwLock},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+mod output;
+
+use crate::output::{DatumOutput, LineTagger, Stream};
+
 /// Instructions on how to use this program.
 const USAGE: &str = "Usage: falconeri-worker <job id>";
 
+/// How often we renew our lease on the datum we're processing. This should be
+/// much shorter than `DATUM_LEASE_SECONDS`, so that we can miss a heartbeat or
+/// two without losing our lease.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often our watchdog checks whether we need to kill a datum's command.
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often we send new output from a datum's command to `falconerid`.
+const LOG_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The maximum size of a single log chunk sent to `falconerid`, in bytes.
+const MAX_LOG_CHUNK_BYTES: usize = 64 * 1024;
+
 /// Our main entry point.
 #[tracing::instrument(level = "trace")]
 fn main() -> Result<()> {
@@ -59,23 +84,59 @@ fn main() -> Result<()> {
         // Get the next datum and process it.
         if let Some((mut datum, files)) = client.reserve_next_datum(&job)? {
             // Process our datum, capturing its output.
-            let output = Arc::new(RwLock::new(vec![]));
-            let result = process_datum(
-                &client,
-                &job,
-                &datum,
-                &files,
-                &job.command,
-                output.clone(),
-            );
-            let output_str = String::from_utf8_lossy(
-                &output.read().expect("background thread panic"),
-            )
-            .into_owned();
+            let output = Arc::new(RwLock::new(DatumOutput::new()?));
+            let log = output.read().expect("background thread panic").reopen()?;
+            let (result, lease_lost) =
+                with_heartbeats(&client, &datum, |lease_lost| {
+                    with_log_streaming(&client, &datum, log, || {
+                        process_datum(
+                            &client,
+                            &job,
+                            &datum,
+                            &files,
+                            &job.command,
+                            output.clone(),
+                            lease_lost,
+                        )
+                    })
+                });
+            let output = output.read().expect("background thread panic");
+
+            // If we lost our lease, the babysitter has already given up on us,
+            // and another worker may be processing this datum. So don't
+            // report anything.
+            if lease_lost {
+                warn!(
+                    "discarding results for datum {}, because we lost our lease",
+                    datum.id,
+                );
+                continue;
+            }
+
+            // If our output is too long to store in the database, upload a
+            // full copy.
+            let output_log_uri = if output.is_truncated() {
+                upload_full_output(&job, &datum, &output).unwrap_or_else(|err| {
+                    warn!(
+                        "could not upload full output for datum {}: {}",
+                        datum.id,
+                        err.display_causes_without_backtrace(),
+                    );
+                    None
+                })
+            } else {
+                None
+            };
+            let output_str = output.inline_output(output_log_uri.as_deref());
 
             // Handle the processing results.
             match result {
-                Ok(()) => client.mark_datum_as_done(&mut datum, output_str)?,
+                Ok(()) => client.mark_datum_as_done(
+                    &mut datum,
+                    output_str,
+                    output_log_uri,
+                    OutputFormat::Tagged,
+                )?,
                 Err(err) => {
                     error!(
                         "failed to process datum {}: {}",
@@ -88,6 +149,8 @@ fn main() -> Result<()> {
                     client.mark_datum_as_error(
                         &mut datum,
                         output_str,
+                        output_log_uri,
+                        OutputFormat::Tagged,
                         error_message,
                         backtrace,
                     )?
@@ -117,8 +180,251 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-/// Process a single datum.
-#[tracing::instrument(skip(to_record), level = "trace")]
+/// Call `f`, renewing our lease on `datum` in a background thread until `f`
+/// returns. We pass `f` a flag which is set if we lose our lease, so that it
+/// can stop early. Returns the result of `f`, and whether we lost our lease.
+fn with_heartbeats<T, F>(client: &Client, datum: &Datum, f: F) -> (T, bool)
+where
+    F: FnOnce(&AtomicBool) -> T,
+{
+    let lease_lost = AtomicBool::new(false);
+    let (stop_tx, stop_rx) = crossbeam::channel::bounded::<()>(0);
+    let result = crossbeam::scope(|scope| {
+        scope.spawn(|_| {
+            // Send a heartbeat every `HEARTBEAT_INTERVAL`, until `stop_tx` is
+            // dropped.
+            while let Err(RecvTimeoutError::Timeout) =
+                stop_rx.recv_timeout(HEARTBEAT_INTERVAL)
+            {
+                match client.send_datum_heartbeat(datum) {
+                    Ok(true) => trace!("renewed lease on datum {}", datum.id),
+                    Ok(false) => {
+                        error!("lost our lease on datum {}", datum.id);
+                        lease_lost.store(true, Ordering::SeqCst);
+                        return;
+                    }
+                    Err(err) => warn!(
+                        "could not send heartbeat for datum {} (will retry): {}",
+                        datum.id,
+                        err.display_causes_without_backtrace(),
+                    ),
+                }
+            }
+        });
+        let result = f(&lease_lost);
+        drop(stop_tx);
+        result
+    })
+    .expect("background panic");
+    (result, lease_lost.load(Ordering::SeqCst))
+}
+
+/// Call `f`, sending any new output written to `log` to `falconerid` in a
+/// background thread until `f` returns. Once `f` returns, we make one last
+/// attempt to send any remaining output.
+fn with_log_streaming<T, F>(client: &Client, datum: &Datum, log: File, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let (stop_tx, stop_rx) = crossbeam::channel::bounded::<()>(0);
+    crossbeam::scope(|scope| {
+        scope.spawn(|_| {
+            let mut streamer = LogStreamer::new(client, datum, log);
+            while let Err(RecvTimeoutError::Timeout) =
+                stop_rx.recv_timeout(LOG_FLUSH_INTERVAL)
+            {
+                streamer.flush(false);
+            }
+            streamer.flush(true);
+        });
+        let result = f();
+        drop(stop_tx);
+        result
+    })
+    .expect("background panic")
+}
+
+/// Sends the output of a datum to `falconerid` in chunks.
+///
+/// Once we've sent the first `STREAMED_OUTPUT_HEAD_BYTES`, we skip any output
+/// older than the last `STREAMED_OUTPUT_TAIL_BYTES`, because `falconerid`
+/// won't keep it anyway. We leave a gap in `seq` when we skip, so that readers
+/// know something is missing.
+struct LogStreamer<'a> {
+    client: &'a Client,
+    datum: &'a Datum,
+    /// A file containing the output, which is still being written.
+    log: File,
+    /// How many bytes of `log` have been successfully sent or skipped.
+    sent: u64,
+    /// The `seq` of the next chunk to send.
+    seq: i32,
+    /// A chunk that we failed to send, and its length in bytes. We need to
+    /// resend exactly the same chunk, because it may actually have arrived.
+    pending: Option<(String, u64)>,
+}
+
+impl<'a> LogStreamer<'a> {
+    /// Create a new `LogStreamer`.
+    fn new(client: &'a Client, datum: &'a Datum, log: File) -> Self {
+        LogStreamer {
+            client,
+            datum,
+            log,
+            sent: 0,
+            seq: 0,
+            pending: None,
+        }
+    }
+
+    /// Send any unsent output. If `finished` is true, no more output will be
+    /// written, so we send everything, even an incomplete UTF-8 character.
+    /// If we fail to send a chunk, we log a warning and try again on the next
+    /// call.
+    fn flush(&mut self, finished: bool) {
+        loop {
+            let (data, len) = match self.pending.take() {
+                Some(pending) => pending,
+                None => match self.next_chunk(finished) {
+                    Ok(Some(chunk)) => chunk,
+                    Ok(None) => return,
+                    Err(err) => {
+                        warn!(
+                            "could not read output for datum {}: {}",
+                            self.datum.id,
+                            err.display_causes_without_backtrace(),
+                        );
+                        return;
+                    }
+                },
+            };
+            match self
+                .client
+                .append_datum_log_chunk(self.datum, self.seq, &data)
+            {
+                Ok(()) => {
+                    self.sent += len;
+                    self.seq += 1;
+                }
+                Err(err) => {
+                    warn!(
+                        "could not send output for datum {} (will retry): {}",
+                        self.datum.id,
+                        err.display_causes_without_backtrace(),
+                    );
+                    self.pending = Some((data, len));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Read the next chunk of unsent output from `log`, and its length in
+    /// bytes.
+    fn next_chunk(&mut self, finished: bool) -> Result<Option<(String, u64)>> {
+        // Skip to the tail of long output.
+        let log_len = self.log.metadata().context("error reading output")?.len();
+        if let Some(tail_start) = log_tail_start(self.sent, log_len) {
+            self.sent = tail_start;
+            self.seq += 1;
+        }
+
+        // Read one byte more than we need, so that `log_chunk_len` knows
+        // whether there's more output after this chunk.
+        let mut unsent = vec![];
+        self.log.seek(io::SeekFrom::Start(self.sent))?;
+        (&mut self.log)
+            .take(cast::u64(MAX_LOG_CHUNK_BYTES + 1))
+            .read_to_end(&mut unsent)
+            .context("error reading output")?;
+
+        // If we skipped into the middle of a UTF-8 character, skip the rest.
+        let skip = unsent
+            .iter()
+            .take(3)
+            .take_while(|&&byte| byte & 0b1100_0000 == 0b1000_0000)
+            .count();
+        let len = log_chunk_len(&unsent[skip..], finished);
+        if len == 0 {
+            return Ok(None);
+        }
+        let data = String::from_utf8_lossy(&unsent[skip..skip + len]).into_owned();
+        Ok(Some((data, cast::u64(skip + len))))
+    }
+}
+
+/// If we've already sent `sent` bytes of a log containing `log_len` bytes,
+/// should we skip ahead to the tail? If so, return where the tail starts.
+fn log_tail_start(sent: u64, log_len: u64) -> Option<u64> {
+    let tail_start = log_len.saturating_sub(cast::u64(STREAMED_OUTPUT_TAIL_BYTES));
+    if sent >= cast::u64(STREAMED_OUTPUT_HEAD_BYTES) && sent < tail_start {
+        Some(tail_start)
+    } else {
+        None
+    }
+}
+
+/// How many bytes of `unsent` should we send in the next log chunk? We avoid
+/// splitting UTF-8 characters between chunks unless `finished` is true.
+fn log_chunk_len(unsent: &[u8], finished: bool) -> usize {
+    let len = unsent.len().min(MAX_LOG_CHUNK_BYTES);
+    if finished && len == unsent.len() {
+        return len;
+    }
+    // Look for the first byte of the last character, and check whether the
+    // character is complete.
+    for back in 1..=len.min(4) {
+        let byte = unsent[len - back];
+        if byte & 0b1100_0000 != 0b1000_0000 {
+            let char_len = match byte {
+                0b1100_0000..=0b1101_1111 => 2,
+                0b1110_0000..=0b1110_1111 => 3,
+                0b1111_0000..=0b1111_0111 => 4,
+                _ => 1,
+            };
+            return if char_len > back { len - back } else { len };
+        }
+    }
+    len
+}
+
+/// Upload the full output of the command which processed `datum` under our
+/// job's `egress.log_uri`, and return its URI. If our job doesn't have a
+/// `log_uri`, do nothing.
+#[tracing::instrument(skip(output), level = "trace")]
+fn upload_full_output(
+    job: &Job,
+    datum: &Datum,
+    output: &DatumOutput,
+) -> Result<Option<String>> {
+    let log_uri = match job.log_uri()? {
+        Some(log_uri) => log_uri,
+        None => return Ok(None),
+    };
+    let uri = full_output_uri(&log_uri, job, datum);
+    let mut storage = <dyn CloudStorage>::for_uri(&uri, &job.egress_secrets()?)?;
+    storage.set_upload_options(job.upload_options()?)?;
+    let mut writer = storage.open_write(&uri)?;
+    io::copy(&mut output.reopen()?, &mut writer)
+        .with_context(|| format!("error uploading {}", uri))?;
+    writer.finish()?;
+    Ok(Some(uri))
+}
+
+/// Where to upload the full output of the current attempt to process `datum`.
+fn full_output_uri(log_uri: &str, job: &Job, datum: &Datum) -> String {
+    format!(
+        "{}/{}/{}/attempt-{}.log",
+        log_uri.trim_end_matches('/'),
+        job.job_name,
+        datum.id,
+        datum.attempted_run_count,
+    )
+}
+
+/// Process a single datum. If `lease_lost` is set, we stop early without
+/// uploading any outputs, because another worker may be processing `datum`.
+#[tracing::instrument(skip(to_record, lease_lost), level = "trace")]
 fn process_datum(
     client: &Client,
     job: &Job,
@@ -126,48 +432,121 @@ fn process_datum(
     files: &[InputFile],
     cmd: &[String],
     to_record: Arc<RwLock<dyn Write + Send + Sync>>,
+    lease_lost: &AtomicBool,
 ) -> Result<()> {
     debug!("processing datum {}", datum.id);
 
     // Download each file.
     reset_work_dirs()?;
     for file in files {
-        // We don't pass in any `secrets` here, because those are supposed to
-        // be specified in our Kubernetes job when it's created.
-        let storage = <dyn CloudStorage>::for_uri(&file.uri, &[])?;
-        storage.sync_down(&file.uri, Path::new(&file.local_path))?;
+        // If our input didn't specify any `secrets`, we'll use the credentials
+        // specified in `transform.secrets`, which are in our environment.
+        let storage =
+            <dyn CloudStorage>::for_uri(&file.uri, &file.storage_secrets()?)?;
+        let local_path = Path::new(&file.local_path);
+        if file.decompress {
+            download_decompressed(&*storage, &file.uri, local_path)?;
+        } else {
+            storage.sync_down(&file.uri, local_path)?;
+        }
     }
 
+    // Run our command.
+    if cmd.is_empty() {
+        return Err(format_err!("job {} command is empty", job.id));
+    }
+    run_command(cmd, to_record, job.datum_timeout()?, lease_lost)?;
+
+    // Finish up.
+    upload_outputs(client, job, datum, Path::new("/pfs/"), lease_lost)
+        .context("could not upload outputs")?;
+    reset_work_dirs()?;
+    Ok(())
+}
+
+/// Run `cmd`, copying its output to `to_record`. If `timeout` is specified and
+/// `cmd` runs for longer than that, or if `lease_lost` is set, we kill its
+/// entire process group, including any processes it started, and return an
+/// error.
+#[tracing::instrument(skip(to_record, lease_lost), level = "trace")]
+fn run_command(
+    cmd: &[String],
+    to_record: Arc<RwLock<dyn Write + Send + Sync>>,
+    timeout: Option<Duration>,
+    lease_lost: &AtomicBool,
+) -> Result<()> {
+    let started = Instant::now();
+    let timed_out = AtomicBool::new(false);
+    let (stop_tx, stop_rx) = crossbeam::channel::bounded::<()>(0);
+
     // Set up a worker thread scope so that we can handle background I/O.
     crossbeam::scope(|scope| -> Result<()> {
-        // Run our command.
-        if cmd.is_empty() {
-            return Err(format_err!("job {} command is empty", job.id));
-        }
+        // Make sure our watchdog stops when we return, even on error.
+        let _stop_tx = stop_tx;
+
+        // Run our command in a new process group, so that we can kill it and
+        // all its children if we need to stop early.
         let mut child = process::Command::new(&cmd[0])
             .args(&cmd[1..])
             .stdout(process::Stdio::piped())
             .stderr(process::Stdio::piped())
+            .process_group(0)
             .spawn()
             .with_context(|| format!("could not run {:?}", &cmd[0]))?;
 
+        // Start a watchdog which kills the process group if we time out or
+        // lose our lease.
+        let pgid = cast::i32(child.id())?;
+        let timed_out = &timed_out;
+        scope.spawn(move |_| {
+            while let Err(RecvTimeoutError::Timeout) =
+                stop_rx.recv_timeout(WATCHDOG_INTERVAL)
+            {
+                if lease_lost.load(Ordering::SeqCst) {
+                    warn!("lost our lease, killing process group {}", pgid);
+                } else if timeout.is_some_and(|t| started.elapsed() >= t) {
+                    warn!("command timed out, killing process group {}", pgid);
+                    timed_out.store(true, Ordering::SeqCst);
+                } else {
+                    continue;
+                }
+                // SAFETY: `kill` has no memory safety requirements. A
+                // negative PID refers to a process group.
+                if unsafe { libc::kill(-pgid, libc::SIGKILL) } != 0 {
+                    warn!(
+                        "could not kill process group {}: {}",
+                        pgid,
+                        io::Error::last_os_error(),
+                    );
+                }
+                return;
+            }
+        });
+
         // Listen on stdout.
         tee_child(scope, &mut child, to_record)?;
 
         let status = child
             .wait()
             .with_context(|| format!("error running {:?}", &cmd[0]))?;
-        if !status.success() {
+        if lease_lost.load(Ordering::SeqCst) {
+            return Err(format_err!(
+                "lost our lease while running command {:?}",
+                cmd,
+            ));
+        } else if timed_out.load(Ordering::SeqCst) {
+            return Err(format_err!(
+                "command {:?} timed out after {} seconds",
+                cmd,
+                timeout.map(|t| t.as_secs()).unwrap_or_default(),
+            ));
+        } else if !status.success() {
             return Err(format_err!(
                 "command {:?} failed with status {}",
                 cmd,
                 status
             ));
         }
-
-        // Finish up.
-        upload_outputs(client, job, datum).context("could not upload outputs")?;
-        reset_work_dirs()?;
         Ok(())
     })
     .expect("background panic")
@@ -190,7 +569,12 @@ fn tee_child<'a>(
         .expect("child should always have a stdout");
     let to_record_for_stdout = to_record.clone();
     let stdout_handle = scope.spawn(move |_| {
-        tee_output(&mut stdout, &mut io::stdout(), to_record_for_stdout)
+        tee_output(
+            &mut stdout,
+            &mut io::stdout(),
+            Stream::Stdout,
+            to_record_for_stdout,
+        )
     });
 
     // Tee `stderr`.
@@ -200,7 +584,12 @@ fn tee_child<'a>(
         .expect("child should always have a stderr");
     let to_record_for_stderr = to_record.clone();
     let stderr_handle = scope.spawn(move |_| {
-        tee_output(&mut stderr, &mut io::stderr(), to_record_for_stderr)
+        tee_output(
+            &mut stderr,
+            &mut io::stderr(),
+            Stream::Stderr,
+            to_record_for_stderr,
+        )
     });
 
     // Wait for our child process to close `stdout` and `stderr`, or at least
@@ -211,37 +600,47 @@ fn tee_child<'a>(
     Ok(())
 }
 
-/// Copy output from `from_child` to `to_console` and `to_record`.
+/// Copy output from `from_child` to `to_console`, and write a copy to
+/// `to_record`, with each line tagged with a timestamp and `stream`.
 #[tracing::instrument(skip(from_child, to_console, to_record), level = "trace")]
 fn tee_output(
     from_child: &mut dyn Read,
     to_console: &mut dyn Write,
+    stream: Stream,
     to_record: Arc<RwLock<dyn Write>>,
 ) -> Result<()> {
+    // Only record complete lines, so that they don't get mixed up with lines
+    // from the child's other output stream.
+    let mut tagger = LineTagger::new(stream);
+    let record = |lines: &[u8]| -> Result<()> {
+        if !lines.is_empty() {
+            to_record
+                .write()
+                .expect("background panic")
+                .write_all(lines)
+                .context("error writing to record")?;
+        }
+        Ok(())
+    };
+
     // Use a small buffer, because I/O performance doesn't matter for reading
     // output to the user.
     let mut buf = vec![0; 4 * 1024];
     loop {
         match from_child.read(&mut buf) {
-            // No more output, so give up.
-            Ok(0) => return Ok(()),
+            // No more output, so record any incomplete line and give up.
+            Ok(0) => return record(&tagger.finish()),
             // We have output, so print it.
             Ok(count) => {
                 let data = &buf[..count];
                 to_console.write(data).context("error writing to console")?;
-                to_record
-                    .write()
-                    .expect("background panic")
-                    .write(data)
-                    .context("error writing to record")?;
+                record(&tagger.push(data, Utc::now()))?;
             }
             // Retry if reading was interrupted by kernal shenigans.
             Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
             // An actual error occurred.
             Err(e) => {
-                return Err(e)
-                    .context("error reading from child process")
-                    .map_err(Into::into);
+                return Err(e).context("error reading from child process");
             }
         }
     }
@@ -292,14 +691,285 @@ fn reset_work_dir(work_dir: &Path) -> Result<()> {
     Ok(())
 }
 
-/// Upload `/pfs/out` to our output bucket.
-#[tracing::instrument(level = "debug")]
-fn upload_outputs(client: &Client, job: &Job, datum: &Datum) -> Result<()> {
-    // Create records describing the files we're going to upload.
+/// Download `uri` to `local_path`, decompressing it along the way. If `uri` is
+/// a directory, we decompress any compressed files it contains instead.
+/// Decompressed files lose their compression extension.
+fn download_decompressed(
+    storage: &dyn CloudStorage,
+    uri: &str,
+    local_path: &Path,
+) -> Result<()> {
+    if uri.ends_with('/') {
+        storage.sync_down(uri, local_path)?;
+        return decompress_dir(local_path);
+    }
+    let compression = match Compression::split_extension(uri) {
+        Some((compression, _)) => compression,
+        None => return storage.sync_down(uri, local_path),
+    };
+    if let Some(parent) = local_path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("cannot create {}", parent.display()))?;
+    }
+    let mut reader = storage.open_read(uri)?;
+    let mut file = File::create(local_path)
+        .with_context(|| format!("cannot create {}", local_path.display()))?;
+    compression
+        .decompress(&mut reader, &mut file)
+        .with_context(|| format!("cannot decompress {}", uri))?;
+    Ok(())
+}
+
+/// Replace each compressed file in `dir` with a decompressed copy, removing
+/// its compression extension. `dir` must end with `/`.
+fn decompress_dir(dir: &Path) -> Result<()> {
+    for (path, path_str) in files_in_dir(dir)? {
+        let (compression, stripped) = match Compression::split_extension(&path_str) {
+            Some(split) => split,
+            None => continue,
+        };
+        trace!("decompressing {}", path.display());
+        let mut from = File::open(&path)
+            .with_context(|| format!("cannot open {}", path.display()))?;
+        let mut to = create_new_file(Path::new(stripped))?;
+        compression
+            .decompress(&mut from, &mut to)
+            .with_context(|| format!("cannot decompress {}", path.display()))?;
+        fs::remove_file(&path)
+            .with_context(|| format!("cannot delete {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Copy each file in `from_dir` to `to_dir`, compressing it and adding the
+/// extension for `compression`. Files which already have that extension are
+/// moved as-is. Both directories must end with `/`.
+fn compress_dir(
+    compression: Compression,
+    from_dir: &Path,
+    to_dir: &Path,
+) -> Result<()> {
+    for (path, path_str) in files_in_dir(from_dir)? {
+        let rel_path = path.strip_prefix(from_dir)?;
+        if path_str.ends_with(compression.extension()) {
+            let dest = to_dir.join(rel_path);
+            create_parent_dir(&dest)?;
+            if dest.exists() {
+                return Err(format_err!("{} already exists", dest.display()));
+            }
+            fs::rename(&path, &dest)
+                .with_context(|| format!("cannot move {}", path.display()))?;
+            continue;
+        }
+        let mut dest = to_dir.join(rel_path).into_os_string();
+        dest.push(compression.extension());
+        let dest = PathBuf::from(dest);
+        create_parent_dir(&dest)?;
+        trace!("compressing {} to {}", path.display(), dest.display());
+        let mut from = File::open(&path)
+            .with_context(|| format!("cannot open {}", path.display()))?;
+        let mut to = create_new_file(&dest)?;
+        compression
+            .compress(&mut from, &mut to)
+            .with_context(|| format!("cannot compress {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// List the regular files in `dir`, recursively, along with their paths as
+/// strings. `dir` must end with `/`.
+fn files_in_dir(dir: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let pattern = format!("{}**/*", dir.display());
+    let mut files = vec![];
+    for path in glob::glob(&pattern)
+        .with_context(|| format!("error listing {}", dir.display()))?
+    {
+        let path = path.with_context(|| format!("error listing {}", dir.display()))?;
+        if !path.is_file() {
+            continue;
+        }
+        let path_str = path
+            .to_str()
+            .ok_or_else(|| format_err!("invalid characters in {:?}", path))?
+            .to_owned();
+        files.push((path, path_str));
+    }
+    Ok(files)
+}
+
+/// Create the directory containing `path`, if it doesn't already exist.
+fn create_parent_dir(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("cannot create {}", parent.display()))?;
+    }
+    Ok(())
+}
+
+/// Create a file at `path`, failing if it already exists. We use this when
+/// adding or removing compression extensions, so that two files which map to
+/// the same name can't silently overwrite each other.
+fn create_new_file(path: &Path) -> Result<File> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .with_context(|| format!("cannot create {}", path.display()))
+}
+
+/// The `falconerid` calls made by `upload_outputs`, so that we can test it
+/// without a server.
+trait OutputFileApi: fmt::Debug {
+    /// Record the output files we're about to upload.
+    fn create_output_files(&self, files: &[NewOutputFile]) -> Result<Vec<OutputFile>>;
+
+    /// Record whether our output files were uploaded.
+    fn patch_output_files(&self, patches: &[OutputFilePatch]) -> Result<()>;
+}
+
+impl OutputFileApi for Client {
+    fn create_output_files(&self, files: &[NewOutputFile]) -> Result<Vec<OutputFile>> {
+        Client::create_output_files(self, files)
+    }
+
+    fn patch_output_files(&self, patches: &[OutputFilePatch]) -> Result<()> {
+        Client::patch_output_files(self, patches)
+    }
+}
+
+/// Upload `out/` in `work_dir` (normally `/pfs/`) to our output bucket.
+///
+/// If we lose our lease on `datum`, we stop before creating any `OutputFile`
+/// records or uploading any more files, because another worker may be
+/// processing `datum`. `falconerid` also checks our lease when we create the
+/// records.
+#[tracing::instrument(skip(lease_lost), level = "debug")]
+fn upload_outputs(
+    client: &dyn OutputFileApi,
+    job: &Job,
+    datum: &Datum,
+    work_dir: &Path,
+    lease_lost: &AtomicBool,
+) -> Result<()> {
+    // If our job asks us to compress our outputs, do that first, and upload
+    // the compressed copies instead.
+    let mut out_dir = work_dir.join("out/");
+    if let Some(compression) = job.output_compression()? {
+        let compressed_dir = work_dir.join(".falconeri-compressed-out/");
+        fs::create_dir_all(&compressed_dir)
+            .with_context(|| format!("cannot create {}", compressed_dir.display()))?;
+        compress_dir(compression, &out_dir, &compressed_dir)
+            .context("could not compress outputs")?;
+        out_dir = compressed_dir;
+    }
+
+    // Create records describing the files we're going to upload. We do this
+    // before uploading anything, so that if we fail part way through, the
+    // babysitter knows which files to delete before retrying this datum.
+    let new_output_files = new_output_files(job, datum, &out_dir)?;
+    check_lease(datum, lease_lost)?;
+    let output_files = client.create_output_files(&new_output_files)?;
+
+    // Upload all our files in a batch, for maximum performance. If the job uses
+    // atomic egress, this uploads them to a staging prefix, and `falconerid`
+    // will move them into place when the job succeeds.
+    //
+    // If `falconerid` renamed any of our files to avoid colliding with another
+    // datum's output, we need to upload each file to the URI it chose instead.
+    let upload_uri = job.upload_uri()?;
+    let mut storage =
+        <dyn CloudStorage>::for_uri(&upload_uri, &job.egress_secrets()?)?;
+    storage.set_upload_options(job.upload_options()?)?;
+    let renamed = new_output_files
+        .iter()
+        .zip(&output_files)
+        .any(|(new_output_file, output_file)| new_output_file.uri != output_file.uri);
+    let result = if renamed {
+        upload_files_individually(
+            &*storage,
+            &out_dir,
+            &upload_uri,
+            &new_output_files,
+            &output_files,
+            &|| check_lease(datum, lease_lost),
+        )
+    } else {
+        check_lease(datum, lease_lost)
+            .and_then(|()| storage.sync_up(&out_dir, &upload_uri))
+    };
+    let status = match result {
+        Ok(()) => Status::Done,
+        Err(_) => Status::Error,
+    };
+
+    // Record what happened.
+    let patches = output_files
+        .iter()
+        .map(|f| OutputFilePatch { id: f.id, status })
+        .collect::<Vec<_>>();
+    client.patch_output_files(&patches)?;
+
+    result
+}
+
+/// Return an error if `lease_lost` says that we've lost our lease on `datum`.
+fn check_lease(datum: &Datum, lease_lost: &AtomicBool) -> Result<()> {
+    if lease_lost.load(Ordering::SeqCst) {
+        return Err(format_err!("lost our lease on datum {}", datum.id));
+    }
+    Ok(())
+}
+
+/// Upload each of the files we described in `new_output_files` to the URI of
+/// the corresponding record in `output_files`. We call `check_lease` before
+/// each upload, and stop if it fails.
+fn upload_files_individually(
+    storage: &dyn CloudStorage,
+    out_dir: &Path,
+    upload_uri: &str,
+    new_output_files: &[NewOutputFile],
+    output_files: &[OutputFile],
+    check_lease: &dyn Fn() -> Result<()>,
+) -> Result<()> {
+    let mut upload_dir = upload_uri.to_owned();
+    if !upload_dir.ends_with('/') {
+        upload_dir.push('/');
+    }
+    for (new_output_file, output_file) in new_output_files.iter().zip(output_files) {
+        let rel_path =
+            new_output_file
+                .uri
+                .strip_prefix(&upload_dir)
+                .ok_or_else(|| {
+                    format_err!("{} is not in {}", new_output_file.uri, upload_dir)
+                })?;
+        let local_path = out_dir.join(rel_path);
+        check_lease()?;
+        trace!("uploading {} to {}", local_path.display(), output_file.uri);
+        let mut file = File::open(&local_path)
+            .with_context(|| format!("cannot open {}", local_path.display()))?;
+        let mut writer = storage.open_write(&output_file.uri)?;
+        io::copy(&mut file, &mut writer)
+            .with_context(|| format!("cannot upload {}", local_path.display()))?;
+        writer.finish()?;
+    }
+    Ok(())
+}
+
+/// Build records describing the files in `out_dir` that we're going to upload.
+/// `out_dir` must end with `/`.
+fn new_output_files(
+    job: &Job,
+    datum: &Datum,
+    out_dir: &Path,
+) -> Result<Vec<NewOutputFile>> {
     let mut new_output_files = vec![];
-    let local_paths = glob::glob("/pfs/out/**/*").context("error listing /pfs/out")?;
+    let pattern = format!("{}**/*", out_dir.display());
+    let local_paths = glob::glob(&pattern)
+        .with_context(|| format!("error listing {}", out_dir.display()))?;
     for local_path in local_paths {
-        let local_path = local_path.context("error listing /pfs/out")?;
+        let local_path = local_path
+            .with_context(|| format!("error listing {}", out_dir.display()))?;
         let _span =
             debug_span!("upload_output", local_path = %local_path.display()).entered();
 
@@ -312,41 +982,490 @@ fn upload_outputs(client: &Client, job: &Job, datum: &Datum) -> Result<()> {
         }
 
         // Get our local path, and strip the prefix.
-        let rel_path = local_path.strip_prefix("/pfs/out/")?;
+        let rel_path = local_path.strip_prefix(out_dir)?;
         let rel_path_str = rel_path
             .to_str()
             .ok_or_else(|| format_err!("invalid characters in {:?}", rel_path))?;
 
         // Build the URI we want to upload to.
-        let mut uri = job.egress_uri.clone();
+        let mut uri = job.upload_uri()?;
         if !uri.ends_with('/') {
             uri.push('/');
         }
         uri.push_str(rel_path_str);
 
         // Create a database record for the file we're about to upload.
+        let (size_bytes, sha256) = size_and_sha256(&local_path)?;
         new_output_files.push(NewOutputFile {
             datum_id: datum.id,
             job_id: job.id,
             uri: uri.clone(),
+            size_bytes: Some(size_bytes),
+            sha256: Some(sha256),
         });
     }
-    let output_files = client.create_output_files(&new_output_files)?;
+    Ok(new_output_files)
+}
 
-    // Upload all our files in a batch, for maximum performance.
-    let storage = <dyn CloudStorage>::for_uri(&job.egress_uri, &[])?;
-    let result = storage.sync_up(Path::new("/pfs/out/"), &job.egress_uri);
-    let status = match result {
-        Ok(()) => Status::Done,
-        Err(_) => Status::Error,
+/// Compute the size of the file at `path`, and its SHA-256 hash as lowercase
+/// hex.
+fn size_and_sha256(path: &Path) -> Result<(i64, String)> {
+    let mut file =
+        File::open(path).with_context(|| format!("cannot open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("cannot read {}", path.display()))?;
+    Ok((cast::i64(size)?, format!("{:x}", hasher.finalize())))
+}
+
+#[test]
+fn output_files_match_uploaded_files() {
+    use falconeri_common::storage::mem::MemoryStorage;
+
+    let out_dir = env::temp_dir().join(format!("falconeri-{}/", Uuid::new_v4()));
+    fs::create_dir_all(out_dir.join("nested")).unwrap();
+    fs::write(out_dir.join("a.txt"), "a").unwrap();
+    fs::write(out_dir.join("nested/b.txt"), "b").unwrap();
+
+    let mut job = Job::factory();
+    job.egress_uri = "mem://worker-outputs/out".to_owned();
+    let datum = Datum::factory(&job);
+    let new_output_files = new_output_files(&job, &datum, &out_dir).unwrap();
+    let uris = new_output_files
+        .iter()
+        .map(|f| f.uri.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        uris,
+        &[
+            "mem://worker-outputs/out/a.txt",
+            "mem://worker-outputs/out/nested/b.txt",
+        ],
+    );
+    assert!(new_output_files
+        .iter()
+        .all(|f| f.datum_id == datum.id && f.job_id == job.id));
+    assert_eq!(new_output_files[0].size_bytes, Some(1));
+    assert_eq!(
+        new_output_files[0].sha256.as_deref(),
+        Some("ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb"),
+    );
+
+    // Make sure the files we upload are exactly the ones we recorded.
+    let storage = <dyn CloudStorage>::for_uri(&job.egress_uri, &[]).unwrap();
+    storage.sync_up(&out_dir, &job.egress_uri).unwrap();
+    assert_eq!(
+        MemoryStorage::uris_with_prefix("mem://worker-outputs/"),
+        uris
+    );
+    assert_eq!(
+        MemoryStorage::get("mem://worker-outputs/out/nested/b.txt").unwrap(),
+        b"b",
+    );
+
+    MemoryStorage::clear("mem://worker-outputs/");
+    fs::remove_dir_all(out_dir).unwrap();
+}
+
+#[test]
+fn uploads_renamed_output_files() {
+    use falconeri_common::storage::mem::MemoryStorage;
+
+    let out_dir = env::temp_dir().join(format!("falconeri-{}/", Uuid::new_v4()));
+    fs::create_dir_all(out_dir.join("nested")).unwrap();
+    fs::write(out_dir.join("a.txt"), "a").unwrap();
+    fs::write(out_dir.join("nested/b.txt"), "b").unwrap();
+
+    let mut job = Job::factory();
+    job.egress_uri = "mem://renamed-outputs/out".to_owned();
+    let datum = Datum::factory(&job);
+    let new_output_files = new_output_files(&job, &datum, &out_dir).unwrap();
+    let output_files = new_output_files
+        .iter()
+        .map(|new_output_file| {
+            let mut output_file = OutputFile::factory(&datum);
+            output_file.uri = new_output_file.uri.replace("b.txt", "b-2.txt");
+            output_file
+        })
+        .collect::<Vec<_>>();
+
+    let storage = <dyn CloudStorage>::for_uri(&job.egress_uri, &[]).unwrap();
+
+    // If we lose our lease, we stop uploading.
+    let lease_lost = AtomicBool::new(true);
+    let check = || check_lease(&datum, &lease_lost);
+    assert!(upload_files_individually(
+        &*storage,
+        &out_dir,
+        &job.egress_uri,
+        &new_output_files,
+        &output_files,
+        &check,
+    )
+    .is_err());
+    assert!(MemoryStorage::uris_with_prefix("mem://renamed-outputs/").is_empty());
+
+    lease_lost.store(false, Ordering::SeqCst);
+    upload_files_individually(
+        &*storage,
+        &out_dir,
+        &job.egress_uri,
+        &new_output_files,
+        &output_files,
+        &check,
+    )
+    .unwrap();
+    assert_eq!(
+        MemoryStorage::uris_with_prefix("mem://renamed-outputs/"),
+        &[
+            "mem://renamed-outputs/out/a.txt",
+            "mem://renamed-outputs/out/nested/b-2.txt",
+        ],
+    );
+    assert_eq!(
+        MemoryStorage::get("mem://renamed-outputs/out/nested/b-2.txt").unwrap(),
+        b"b",
+    );
+
+    MemoryStorage::clear("mem://renamed-outputs/");
+    fs::remove_dir_all(out_dir).unwrap();
+}
+
+#[test]
+fn atomic_egress_output_files_are_staged() {
+    use falconeri_common::storage::mem::MemoryStorage;
+
+    let out_dir = env::temp_dir().join(format!("falconeri-{}/", Uuid::new_v4()));
+    fs::create_dir_all(&out_dir).unwrap();
+    fs::write(out_dir.join("a.txt"), "a").unwrap();
+
+    let mut job = Job::factory();
+    job.egress_uri = "mem://staged-outputs/out/".to_owned();
+    job.pipeline_spec = falconeri_common::serde_json::json!({
+        "egress": { "URI": &job.egress_uri, "atomic": true },
+    });
+    let datum = Datum::factory(&job);
+    let new_output_files = new_output_files(&job, &datum, &out_dir).unwrap();
+    assert_eq!(new_output_files.len(), 1);
+    assert_eq!(
+        new_output_files[0].uri,
+        format!("mem://staged-outputs/_falconeri_staging/{}/a.txt", job.id),
+    );
+
+    // Nobody reading our egress sees anything until the files are promoted.
+    let upload_uri = job.upload_uri().unwrap();
+    let storage = <dyn CloudStorage>::for_uri(&upload_uri, &[]).unwrap();
+    storage.sync_up(&out_dir, &upload_uri).unwrap();
+    assert!(storage.list(&job.egress_uri).unwrap().is_empty());
+    assert_eq!(storage.list(&upload_uri).unwrap().len(), 1);
+
+    MemoryStorage::clear("mem://staged-outputs/");
+    fs::remove_dir_all(out_dir).unwrap();
+}
+
+#[test]
+fn upload_outputs_records_and_uploads_files() {
+    use falconeri_common::storage::mem::MemoryStorage;
+    use std::cell::RefCell;
+
+    /// Pretends to be `falconerid`, renaming any `b.txt.gz` to avoid a
+    /// collision.
+    #[derive(Debug)]
+    struct FakeApi<'a> {
+        datum: &'a Datum,
+        created: RefCell<Vec<NewOutputFile>>,
+        patches: RefCell<Vec<OutputFilePatch>>,
+    }
+
+    impl OutputFileApi for FakeApi<'_> {
+        fn create_output_files(
+            &self,
+            files: &[NewOutputFile],
+        ) -> Result<Vec<OutputFile>> {
+            self.created.borrow_mut().extend(files.iter().cloned());
+            Ok(files
+                .iter()
+                .map(|file| {
+                    let mut output_file = OutputFile::factory(self.datum);
+                    output_file.uri = file.uri.replace("b.txt.gz", "b-2.txt.gz");
+                    output_file
+                })
+                .collect())
+        }
+
+        fn patch_output_files(&self, patches: &[OutputFilePatch]) -> Result<()> {
+            self.patches
+                .borrow_mut()
+                .extend(patches.iter().map(|patch| OutputFilePatch {
+                    id: patch.id,
+                    status: patch.status,
+                }));
+            Ok(())
+        }
+    }
+
+    let work_dir = env::temp_dir().join(format!("falconeri-{}/", Uuid::new_v4()));
+    fs::create_dir_all(work_dir.join("out/nested")).unwrap();
+    fs::write(work_dir.join("out/a.txt"), "a").unwrap();
+    fs::write(work_dir.join("out/nested/b.txt"), "b").unwrap();
+
+    let mut job = Job::factory();
+    job.egress_uri = "mem://upload-outputs/out/".to_owned();
+    job.pipeline_spec = falconeri_common::serde_json::json!({
+        "egress": { "URI": &job.egress_uri, "atomic": true, "compress": "gzip" },
+    });
+    let datum = Datum::factory(&job);
+    let api = FakeApi {
+        datum: &datum,
+        created: RefCell::new(vec![]),
+        patches: RefCell::new(vec![]),
     };
 
-    // Record what happened.
-    let patches = output_files
+    // If we've lost our lease, we neither record nor upload anything.
+    let lease_lost = AtomicBool::new(true);
+    assert!(upload_outputs(&api, &job, &datum, &work_dir, &lease_lost).is_err());
+    assert!(api.created.borrow().is_empty());
+    assert!(MemoryStorage::uris_with_prefix("mem://upload-outputs/").is_empty());
+    fs::remove_dir_all(work_dir.join(".falconeri-compressed-out")).unwrap();
+
+    // Otherwise, we compress and stage our outputs, respecting renames.
+    lease_lost.store(false, Ordering::SeqCst);
+    upload_outputs(&api, &job, &datum, &work_dir, &lease_lost).unwrap();
+    let staging_uri = format!("mem://upload-outputs/_falconeri_staging/{}/", job.id);
+    let created = api
+        .created
+        .borrow()
         .iter()
-        .map(|f| OutputFilePatch { id: f.id, status })
+        .map(|f| f.uri.clone())
         .collect::<Vec<_>>();
-    client.patch_output_files(&patches)?;
+    assert_eq!(
+        created,
+        &[
+            format!("{}a.txt.gz", staging_uri),
+            format!("{}nested/b.txt.gz", staging_uri),
+        ],
+    );
+    assert_eq!(
+        MemoryStorage::uris_with_prefix("mem://upload-outputs/"),
+        &[
+            format!("{}a.txt.gz", staging_uri),
+            format!("{}nested/b-2.txt.gz", staging_uri),
+        ],
+    );
+    let mut b = vec![];
+    Compression::Gzip
+        .decompress(
+            &mut &MemoryStorage::get(&format!("{}nested/b-2.txt.gz", staging_uri))
+                .unwrap()[..],
+            &mut b,
+        )
+        .unwrap();
+    assert_eq!(b, b"b");
+    let patches = api.patches.borrow();
+    assert_eq!(patches.len(), 2);
+    assert!(patches.iter().all(|patch| patch.status == Status::Done));
 
-    result
+    MemoryStorage::clear("mem://upload-outputs/");
+    fs::remove_dir_all(work_dir).unwrap();
+}
+
+#[test]
+fn compresses_and_decompresses_directories() {
+    let dir = env::temp_dir().join(format!("falconeri-{}/", Uuid::new_v4()));
+    let out_dir = dir.join("out/");
+    let compressed_dir = dir.join("compressed/");
+    fs::create_dir_all(out_dir.join("nested")).unwrap();
+    fs::write(out_dir.join("a.txt"), "a").unwrap();
+    fs::write(out_dir.join("nested/b.txt"), "b").unwrap();
+    let mut already_compressed = vec![];
+    Compression::Gzip
+        .compress(&mut &b"c"[..], &mut already_compressed)
+        .unwrap();
+    fs::write(out_dir.join("c.txt.gz"), &already_compressed).unwrap();
+
+    compress_dir(Compression::Gzip, &out_dir, &compressed_dir).unwrap();
+    let mut names = files_in_dir(&compressed_dir)
+        .unwrap()
+        .into_iter()
+        .map(|(_, path_str)| path_str)
+        .collect::<Vec<_>>();
+    names.sort();
+    let compressed_dir_str = compressed_dir.to_str().unwrap();
+    assert_eq!(
+        names,
+        &[
+            format!("{}a.txt.gz", compressed_dir_str),
+            format!("{}c.txt.gz", compressed_dir_str),
+            format!("{}nested/b.txt.gz", compressed_dir_str),
+        ],
+    );
+
+    decompress_dir(&compressed_dir).unwrap();
+    assert_eq!(fs::read(compressed_dir.join("a.txt")).unwrap(), b"a");
+    assert_eq!(fs::read(compressed_dir.join("c.txt")).unwrap(), b"c");
+    assert_eq!(fs::read(compressed_dir.join("nested/b.txt")).unwrap(), b"b");
+    assert!(!compressed_dir.join("a.txt.gz").exists());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn compress_dir_detects_collisions() {
+    let dir = env::temp_dir().join(format!("falconeri-{}/", Uuid::new_v4()));
+    let out_dir = dir.join("out/");
+    fs::create_dir_all(&out_dir).unwrap();
+    fs::write(out_dir.join("a.txt"), "a").unwrap();
+    fs::write(out_dir.join("a.txt.zst"), "not really zstd").unwrap();
+    assert!(compress_dir(Compression::Zstd, &out_dir, &dir.join("zst/")).is_err());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn downloads_decompressed_files() {
+    use falconeri_common::storage::mem::MemoryStorage;
+
+    let mut compressed = vec![];
+    Compression::Zstd
+        .compress(&mut &b"hello"[..], &mut compressed)
+        .unwrap();
+    MemoryStorage::put("mem://decompress-inputs/a.txt.zst", compressed);
+    let dir = env::temp_dir().join(format!("falconeri-{}/", Uuid::new_v4()));
+    let local_path = dir.join("in/a.txt");
+
+    let storage =
+        <dyn CloudStorage>::for_uri("mem://decompress-inputs/", &[]).unwrap();
+    download_decompressed(&*storage, "mem://decompress-inputs/a.txt.zst", &local_path)
+        .unwrap();
+    assert_eq!(fs::read(&local_path).unwrap(), b"hello");
+
+    MemoryStorage::clear("mem://decompress-inputs/");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn run_command_reports_failures() {
+    let cmd = |args: &[&str]| args.iter().map(|&a| a.to_owned()).collect::<Vec<_>>();
+    let output = Arc::new(RwLock::new(vec![]));
+    let lease_lost = AtomicBool::new(false);
+    run_command(
+        &cmd(&["sh", "-c", "echo hello; echo oops >&2"]),
+        output.clone(),
+        None,
+        &lease_lost,
+    )
+    .unwrap();
+    let recorded = String::from_utf8(output.read().unwrap().clone()).unwrap();
+    assert_eq!(recorded.lines().count(), 2);
+    assert!(recorded.contains("Z stdout hello\n"));
+    assert!(recorded.contains("Z stderr oops\n"));
+    assert!(run_command(&cmd(&["false"]), output, None, &lease_lost).is_err());
+}
+
+#[test]
+fn run_command_kills_process_group_on_timeout() {
+    // Start a grandchild which would keep our output pipes open, to make sure
+    // we kill the whole process group and not just the child.
+    let cmd = ["sh", "-c", "sleep 30 & sleep 30"]
+        .iter()
+        .map(|&a| a.to_owned())
+        .collect::<Vec<_>>();
+    let output = Arc::new(RwLock::new(vec![]));
+    let started = Instant::now();
+    let lease_lost = AtomicBool::new(false);
+    let err = run_command(&cmd, output, Some(Duration::from_secs(1)), &lease_lost)
+        .unwrap_err();
+    assert!(started.elapsed() < Duration::from_secs(20));
+    assert!(format!("{}", err).contains("timed out after 1 seconds"));
+}
+
+#[test]
+fn run_command_kills_process_group_when_lease_is_lost() {
+    let cmd = ["sh", "-c", "sleep 30 & sleep 30"]
+        .iter()
+        .map(|&a| a.to_owned())
+        .collect::<Vec<_>>();
+    let output = Arc::new(RwLock::new(vec![]));
+    let lease_lost = AtomicBool::new(false);
+    let started = Instant::now();
+    let err = crossbeam::scope(|scope| {
+        scope.spawn(|_| {
+            sleep(Duration::from_secs(1));
+            lease_lost.store(true, Ordering::SeqCst);
+        });
+        run_command(&cmd, output, None, &lease_lost)
+    })
+    .unwrap()
+    .unwrap_err();
+    assert!(started.elapsed() < Duration::from_secs(20));
+    assert!(format!("{}", err).contains("lost our lease"));
+}
+
+#[test]
+fn long_logs_skip_to_the_tail() {
+    let head = cast::u64(STREAMED_OUTPUT_HEAD_BYTES);
+    let tail = cast::u64(STREAMED_OUTPUT_TAIL_BYTES);
+
+    // We always send the head, and anything close enough to the end.
+    assert_eq!(log_tail_start(0, head + tail * 10), None);
+    assert_eq!(log_tail_start(head - 1, head + tail * 10), None);
+    assert_eq!(log_tail_start(head, head + tail), None);
+
+    // Once we've sent the head, we skip anything older than the tail.
+    assert_eq!(
+        log_tail_start(head, head + tail * 10),
+        Some(head + tail * 9)
+    );
+    assert_eq!(
+        log_tail_start(head + tail * 9, head + tail * 11),
+        Some(head + tail * 10),
+    );
+}
+
+#[test]
+fn log_chunks_do_not_split_characters() {
+    // "é" is 2 bytes, and "😀" is 4 bytes.
+    let text = "abé😀".as_bytes();
+    assert_eq!(log_chunk_len(text, false), text.len());
+    assert_eq!(log_chunk_len(&text[..3], false), 2);
+    assert_eq!(log_chunk_len(&text[..3], true), 3);
+    assert_eq!(log_chunk_len(&text[..6], false), 4);
+    assert_eq!(log_chunk_len(&text[..7], false), 4);
+    assert_eq!(log_chunk_len(&text[..1], false), 1);
+    assert_eq!(log_chunk_len(b"", false), 0);
+    // Invalid UTF-8 can't be completed, so we send it as is.
+    assert_eq!(log_chunk_len(b"a\xff", false), 2);
+
+    // Long output is split into several chunks.
+    let long = vec![b'x'; MAX_LOG_CHUNK_BYTES + 1];
+    assert_eq!(log_chunk_len(&long, true), MAX_LOG_CHUNK_BYTES);
+}
+
+#[test]
+fn uploads_full_output() {
+    use falconeri_common::storage::mem::MemoryStorage;
+
+    let mut job = Job::factory();
+    job.egress_uri = "mem://worker-logs/out/".to_owned();
+    let mut datum = Datum::factory(&job);
+    datum.attempted_run_count = 2;
+    let mut output = DatumOutput::new().unwrap();
+    output.write_all(b"hello\n").unwrap();
+
+    // Without a `log_uri`, we don't upload anything.
+    assert_eq!(upload_full_output(&job, &datum, &output).unwrap(), None);
+
+    job.pipeline_spec = falconeri_common::serde_json::json!({
+        "egress": { "URI": &job.egress_uri, "log_uri": "mem://worker-logs/logs/" },
+    });
+    let uri = upload_full_output(&job, &datum, &output).unwrap().unwrap();
+    assert_eq!(
+        uri,
+        format!(
+            "mem://worker-logs/logs/{}/{}/attempt-2.log",
+            job.job_name, datum.id
+        ),
+    );
+    assert_eq!(MemoryStorage::get(&uri).unwrap(), b"hello\n");
+
+    MemoryStorage::clear("mem://worker-logs/");
 }
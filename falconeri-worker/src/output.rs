@@ -0,0 +1,259 @@
+//! Capturing the output of a datum's command.
+
+use falconeri_common::{
+    cast,
+    chrono::{DateTime, Utc},
+    prelude::*,
+};
+use std::{collections::VecDeque, io, mem};
+use tempfile::NamedTempFile;
+
+/// The longest line we'll tag, in bytes. Longer lines are split into several
+/// tagged lines.
+const MAX_LINE_BYTES: usize = 16 * 1024;
+
+/// The output of a datum's command. We only keep the beginning and end of the
+/// output in memory, and we write a full copy to a temporary file.
+pub struct DatumOutput {
+    /// A full copy of the output.
+    file: NamedTempFile,
+    /// Up to `INLINE_OUTPUT_HEAD_BYTES` from the beginning of the output.
+    head: Vec<u8>,
+    /// Up to `INLINE_OUTPUT_TAIL_BYTES` from the end of the output, not including
+    /// anything in `head`.
+    tail: VecDeque<u8>,
+    /// The total length of the output.
+    len: u64,
+}
+
+impl DatumOutput {
+    /// Create a new, empty `DatumOutput`.
+    pub fn new() -> Result<DatumOutput> {
+        Ok(DatumOutput {
+            file: NamedTempFile::new()
+                .context("could not create temporary file for output")?,
+            head: vec![],
+            tail: VecDeque::new(),
+            len: 0,
+        })
+    }
+
+    /// Is this output too long to store in the database?
+    pub fn is_truncated(&self) -> bool {
+        self.len > cast::u64(INLINE_OUTPUT_HEAD_BYTES + INLINE_OUTPUT_TAIL_BYTES)
+    }
+
+    /// Open a new handle to the full output, starting at the beginning.
+    pub fn reopen(&self) -> Result<File> {
+        self.file
+            .reopen()
+            .context("could not reopen temporary file for output")
+    }
+
+    /// The output to store in the database. If the output is too long, we
+    /// replace the middle with a note saying where to find the full output.
+    pub fn inline_output(&self, output_log_uri: Option<&str>) -> String {
+        let (tail_start, tail_end) = self.tail.as_slices();
+        if !self.is_truncated() {
+            let mut output = self.head.clone();
+            output.extend_from_slice(tail_start);
+            output.extend_from_slice(tail_end);
+            return String::from_utf8_lossy(&output).into_owned();
+        }
+
+        let omitted = self.len - cast::u64(self.head.len() + self.tail.len());
+        let location = match output_log_uri {
+            Some(uri) => format!("full output is at {}", uri),
+            None => "set egress.log_uri to keep the full output".to_owned(),
+        };
+        let mut tail = tail_start.to_owned();
+        tail.extend_from_slice(tail_end);
+        format!(
+            "{}\n[... {} bytes omitted; {} ...]\n{}",
+            String::from_utf8_lossy(&self.head),
+            omitted,
+            location,
+            String::from_utf8_lossy(&tail),
+        )
+    }
+}
+
+impl Write for DatumOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write_all(buf)?;
+        self.len += cast::u64(buf.len());
+
+        // Fill up `head` first, and put everything else in `tail`.
+        let head_len = buf.len().min(INLINE_OUTPUT_HEAD_BYTES - self.head.len());
+        self.head.extend_from_slice(&buf[..head_len]);
+        let rest = &buf[head_len..];
+        if rest.len() >= INLINE_OUTPUT_TAIL_BYTES {
+            self.tail.clear();
+            self.tail
+                .extend(&rest[rest.len() - INLINE_OUTPUT_TAIL_BYTES..]);
+        } else {
+            self.tail.extend(rest);
+            let excess = self.tail.len().saturating_sub(INLINE_OUTPUT_TAIL_BYTES);
+            self.tail.drain(..excess);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// An output stream of a command.
+#[derive(Clone, Copy, Debug)]
+pub enum Stream {
+    /// Standard output.
+    Stdout,
+    /// Standard error.
+    Stderr,
+}
+
+impl Stream {
+    /// The name we use for this stream when tagging lines.
+    fn name(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/// Splits the output of a stream into lines, and starts each line with the
+/// time we received it and the name of the stream, as in
+/// `2026-10-17T12:00:00.123Z stderr oops`.
+///
+/// We only return complete lines, so that lines from different streams aren't
+/// mixed together when we interleave them.
+pub struct LineTagger {
+    stream: Stream,
+    /// The current incomplete line, including its tag.
+    line: Vec<u8>,
+}
+
+impl LineTagger {
+    /// Create a new `LineTagger` for `stream`.
+    pub fn new(stream: Stream) -> LineTagger {
+        LineTagger {
+            stream,
+            line: vec![],
+        }
+    }
+
+    /// Add `data`, which was received at `now`, and return any lines which
+    /// are now complete.
+    pub fn push(&mut self, mut data: &[u8], now: DateTime<Utc>) -> Vec<u8> {
+        let mut complete = vec![];
+        while !data.is_empty() {
+            if self.line.is_empty() {
+                let tag = format!(
+                    "{} {} ",
+                    now.format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+                    self.stream.name(),
+                );
+                self.line.extend_from_slice(tag.as_bytes());
+            }
+            // Take the rest of the line, but no more than will fit.
+            let room = MAX_LINE_BYTES.saturating_sub(self.line.len()).max(1);
+            let available = &data[..data.len().min(room)];
+            let len = match available.iter().position(|&b| b == b'\n') {
+                Some(i) => i + 1,
+                None => available.len(),
+            };
+            self.line.extend_from_slice(&data[..len]);
+            data = &data[len..];
+            if self.line.ends_with(b"\n") {
+                complete.append(&mut self.line);
+            } else if self.line.len() >= MAX_LINE_BYTES {
+                complete.append(&mut self.finish());
+            }
+        }
+        complete
+    }
+
+    /// Return any incomplete line, adding a newline.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut line = mem::take(&mut self.line);
+        if !line.is_empty() {
+            line.push(b'\n');
+        }
+        line
+    }
+}
+
+#[test]
+fn tags_lines() {
+    let now = "2026-10-17T12:00:00.123456Z"
+        .parse::<DateTime<Utc>>()
+        .unwrap();
+    let later = "2026-10-17T12:00:01Z".parse::<DateTime<Utc>>().unwrap();
+    let mut tagger = LineTagger::new(Stream::Stderr);
+    assert_eq!(tagger.push(b"", now), b"");
+    assert_eq!(tagger.push(b"one", now), b"");
+    assert_eq!(
+        tagger.push(b"\ntwo\nthr", later),
+        &b"2026-10-17T12:00:00.123Z stderr one\n2026-10-17T12:00:01.000Z stderr two\n"
+            [..],
+    );
+    assert_eq!(
+        tagger.push(b"ee\n", later),
+        &b"2026-10-17T12:00:01.000Z stderr three\n"[..],
+    );
+    assert_eq!(tagger.finish(), b"");
+    assert_eq!(tagger.push(b"four", later), b"");
+    assert_eq!(
+        tagger.finish(),
+        &b"2026-10-17T12:00:01.000Z stderr four\n"[..],
+    );
+
+    // Very long lines are split.
+    let mut tagger = LineTagger::new(Stream::Stdout);
+    let tagged = tagger.push(&vec![b'x'; 2 * MAX_LINE_BYTES], now);
+    assert_eq!(tagged.iter().filter(|&&b| b == b'\n').count(), 2);
+    assert!(!tagger.finish().is_empty());
+}
+
+#[test]
+fn keeps_head_and_tail_of_long_output() {
+    use std::io::Read;
+
+    // Short output is stored in full.
+    let mut output = DatumOutput::new().unwrap();
+    output.write_all(b"hello\n").unwrap();
+    assert!(!output.is_truncated());
+    assert_eq!(output.inline_output(None), "hello\n");
+
+    // Output which just fits is also stored in full.
+    let mut output = DatumOutput::new().unwrap();
+    let mut expected = vec![];
+    for i in 0..(INLINE_OUTPUT_HEAD_BYTES + INLINE_OUTPUT_TAIL_BYTES) / 8 {
+        let line = format!("{:07}\n", i);
+        output.write_all(line.as_bytes()).unwrap();
+        expected.extend_from_slice(line.as_bytes());
+    }
+    assert!(!output.is_truncated());
+    assert_eq!(output.inline_output(None).as_bytes(), &expected[..]);
+
+    // Longer output has the middle replaced.
+    output.write_all(b"one more line\n").unwrap();
+    expected.extend_from_slice(b"one more line\n");
+    assert!(output.is_truncated());
+    let inline = output.inline_output(Some("gs://example-bucket/logs/1.log"));
+    assert!(inline.starts_with("0000000\n0000001\n"));
+    assert!(inline.contains(
+        "[... 14 bytes omitted; full output is at gs://example-bucket/logs/1.log ...]"
+    ));
+    assert!(inline.ends_with("0008191\none more line\n"));
+    assert!(output
+        .inline_output(None)
+        .contains("set egress.log_uri to keep the full output"));
+
+    // But we still have a full copy.
+    let mut full = vec![];
+    output.reopen().unwrap().read_to_end(&mut full).unwrap();
+    assert_eq!(full, expected);
+}
@@ -12,6 +12,7 @@ const DESCRIBE_TEMPLATE: &str = include_str!("describe.txt.hbs");
 struct Params {
     datum: Datum,
     input_files: Vec<InputFile>,
+    output_files: Vec<OutputFile>,
 }
 
 /// Run the `datum describe` subcommand.
@@ -20,9 +21,14 @@ pub fn run(id: Uuid) -> Result<()> {
     let mut conn = db::connect(ConnectVia::Proxy)?;
     let datum = Datum::find(id, &mut conn)?;
     let input_files = datum.input_files(&mut conn)?;
+    let output_files = OutputFile::for_datum(&datum, &mut conn)?;
 
     // Package into a params object.
-    let params = Params { datum, input_files };
+    let params = Params {
+        datum,
+        input_files,
+        output_files,
+    };
 
     // Print the description.
     print!("{}", render_description(DESCRIBE_TEMPLATE, &params)?);
@@ -32,9 +38,41 @@ pub fn run(id: Uuid) -> Result<()> {
 #[test]
 fn render_template() {
     let job = Job::factory();
-    let datum = Datum::factory(&job);
+    let mut datum = Datum::factory(&job);
+    datum.group_key = Some("2024-01-01".to_owned());
+    datum.output_log_uri =
+        Some("gs://example-bucket/logs/my-job/datum/attempt-1.log".to_owned());
+    datum.output = Some("2026-10-17T12:00:00.123Z stderr oops\n".to_owned());
+    datum.output_format = OutputFormat::Tagged;
     let input_file = InputFile::factory(&datum);
     let input_files = vec![input_file];
-    let params = Params { datum, input_files };
-    render_description(DESCRIBE_TEMPLATE, &params).expect("could not render template");
+    let mut old_output_file = OutputFile::factory(&datum);
+    old_output_file.uri = "gs://example-bucket/output/old.csv".to_owned();
+    old_output_file.size_bytes = None;
+    old_output_file.sha256 = None;
+    let mut empty_output_file = OutputFile::factory(&datum);
+    empty_output_file.uri = "gs://example-bucket/output/empty.csv".to_owned();
+    empty_output_file.size_bytes = Some(0);
+    let output_files = vec![
+        OutputFile::factory(&datum),
+        old_output_file,
+        empty_output_file,
+    ];
+    let params = Params {
+        datum,
+        input_files,
+        output_files,
+    };
+    let description = render_description(DESCRIBE_TEMPLATE, &params)
+        .expect("could not render template");
+    assert!(description.contains(
+        "gs://example-bucket/output/file.csv  done  3  ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    ));
+    assert!(description.contains("gs://example-bucket/output/old.csv  done  -  -"));
+    assert!(description.contains("gs://example-bucket/output/empty.csv  done  0  "));
+    assert!(description
+        .contains("Full Output: gs://example-bucket/logs/my-job/datum/attempt-1.log"));
+    assert!(description.contains(
+        "Output (TIMESTAMP STREAM LINE):\n2026-10-17T12:00:00.123Z stderr oops\n"
+    ));
 }
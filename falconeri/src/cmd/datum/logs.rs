@@ -0,0 +1,73 @@
+//! The `datum logs` subcommand.
+
+use falconeri_common::{db, prelude::*, storage::CloudStorage};
+use std::{
+    io::{self, stdout},
+    thread::sleep,
+    time::Duration,
+};
+
+/// How often we check for new output when following a datum's logs.
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Run the `datum logs` subcommand.
+pub fn run(id: Uuid, follow: bool) -> Result<()> {
+    let mut conn = db::connect(ConnectVia::Proxy)?;
+    let mut datum = Datum::find(id, &mut conn)?;
+    let mut attempt = datum.attempted_run_count;
+    let mut last_seq = None;
+    loop {
+        // Decide whether to stop _before_ fetching output, so that we don't
+        // miss anything sent just before the datum finished.
+        let done = !follow || datum.status.has_finished();
+
+        // Once an attempt has finished, its output is stored in
+        // `Datum::output`, and we only keep its log chunks for a few minutes,
+        // so that anyone following along can print the rest of them. So if we
+        // haven't printed any chunks, print the stored output instead. Datums
+        // processed by older workers never had any log chunks.
+        if datum.status.has_finished() && last_seq.is_none() {
+            return print_finished_output(&datum);
+        }
+
+        let chunks =
+            DatumLogChunk::for_datum_attempt(&datum, attempt, last_seq, &mut conn)?;
+        for chunk in chunks {
+            // The worker and `falconerid` skip the middle of long output.
+            if chunk.seq > last_seq.map_or(0, |seq| seq + 1) {
+                print!("\n[... output omitted ...]\n");
+            }
+            print!("{}", chunk.data);
+            last_seq = Some(chunk.seq);
+        }
+        stdout().flush().context("error writing to stdout")?;
+
+        // If the datum has been retried, switch to following the new attempt.
+        if datum.attempted_run_count != attempt {
+            attempt = datum.attempted_run_count;
+            last_seq = None;
+            eprintln!("--- attempt {} ---", attempt);
+            continue;
+        }
+
+        if done {
+            return Ok(());
+        }
+        sleep(FOLLOW_INTERVAL);
+        datum = Datum::find(id, &mut conn)?;
+    }
+}
+
+/// Print the output recorded when `datum` finished. If this was truncated, we
+/// download the full output, using the credentials in our environment.
+fn print_finished_output(datum: &Datum) -> Result<()> {
+    if let Some(uri) = &datum.output_log_uri {
+        let storage = <dyn CloudStorage>::for_uri(uri, &[])?;
+        let mut log = storage.open_read(uri)?;
+        io::copy(&mut log, &mut stdout())
+            .with_context(|| format!("error downloading {}", uri))?;
+    } else if let Some(output) = &datum.output {
+        print!("{}", output);
+    }
+    Ok(())
+}
@@ -4,6 +4,7 @@ use falconeri_common::prelude::*;
 use structopt::StructOpt;
 
 mod describe;
+mod logs;
 
 /// `datum` options.
 #[derive(Debug, StructOpt)]
@@ -15,11 +16,25 @@ pub enum Opt {
         #[structopt(parse(try_from_str))]
         id: Uuid,
     },
+
+    /// Print the output of a datum's command. This includes output sent while
+    /// the datum is still running.
+    #[structopt(name = "logs")]
+    Logs {
+        /// The UUID of the datum.
+        #[structopt(parse(try_from_str))]
+        id: Uuid,
+
+        /// Keep printing new output until the datum finishes.
+        #[structopt(long = "follow", short = "f")]
+        follow: bool,
+    },
 }
 
 /// Run the `job` subcommand.
 pub fn run(opt: &Opt) -> Result<()> {
     match opt {
         Opt::Describe { id } => describe::run(*id),
+        Opt::Logs { id, follow } => logs::run(*id, *follow),
     }
 }
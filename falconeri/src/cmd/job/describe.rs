@@ -12,6 +12,7 @@ const DESCRIBE_TEMPLATE: &str = include_str!("describe.txt.hbs");
 struct Params {
     job: Job,
     datum_status_counts: Vec<DatumStatusCount>,
+    output_file_summary: OutputFileSummary,
     running_datums: Vec<Datum>,
     error_datums: Vec<Datum>,
 }
@@ -22,11 +23,13 @@ pub fn run(job_name: &str) -> Result<()> {
     let mut conn = db::connect(ConnectVia::Proxy)?;
     let job = Job::find_by_job_name(job_name, &mut conn)?;
     let datum_status_counts = job.datum_status_counts(&mut conn)?;
+    let output_file_summary = job.output_file_summary(&mut conn)?;
     let running_datums = job.datums_with_status(Status::Running, &mut conn)?;
     let error_datums = job.datums_with_status(Status::Error, &mut conn)?;
     let params = Params {
         job,
         datum_status_counts,
+        output_file_summary,
         running_datums,
         error_datums,
     };
@@ -38,7 +41,8 @@ pub fn run(job_name: &str) -> Result<()> {
 
 #[test]
 fn render_template() {
-    let job = Job::factory();
+    let mut job = Job::factory();
+    job.promotion_failure_count = 2;
     let dsc = |status: Status, count: u64, rerunable_count: u64| DatumStatusCount {
         status,
         count,
@@ -56,12 +60,26 @@ fn render_template() {
     error_datum.status = Status::Error;
     error_datum.error_message = Some("Ooops.".to_owned());
     let error_datums = vec![error_datum];
+    let output_file_summary = OutputFileSummary {
+        count: 3,
+        empty_count: 1,
+        unknown_size_count: 1,
+        size_bytes: 1234,
+        staged_count: 2,
+    };
     let params = Params {
         job,
         datum_status_counts,
+        output_file_summary,
         running_datums,
         error_datums,
     };
 
-    render_description(DESCRIBE_TEMPLATE, &params).expect("could not render template");
+    let description = render_description(DESCRIBE_TEMPLATE, &params)
+        .expect("could not render template");
+    assert!(description.contains(
+        "Egress URI: gs://example-bucket/output/\nFailed Promotions: 2\n\n"
+    ));
+    assert!(description
+        .contains("Output files: 3 (1234 bytes, 1 empty, 1 of unknown size, 2 waiting to be promoted)"));
 }
@@ -27,7 +27,7 @@ pub enum Opt {
     #[structopt(name = "list")]
     List,
 
-    /// Retry failed datums.
+    /// Retry failed datums. Jobs using atomic egress retry every datum.
     #[structopt(name = "retry")]
     Retry {
         /// The name of the job for which to retry failed datums.
@@ -40,6 +40,15 @@ pub enum Opt {
         /// Path to a JSON pipeline spec.
         #[structopt(parse(from_os_str))]
         pipeline_json: PathBuf,
+
+        /// Run even if the egress URI already contains files, replacing any
+        /// files with the same names as our output.
+        #[structopt(long = "overwrite")]
+        overwrite: bool,
+
+        /// Delete any files in the egress URI before running.
+        #[structopt(long = "clear-egress", conflicts_with = "overwrite")]
+        clear_egress: bool,
     },
     // Disabled because `BsonSchema` doesn't handle recursive types.
     //
@@ -59,13 +68,19 @@ pub enum Opt {
 pub fn run(opt: &Opt) -> Result<()> {
     match opt {
         Opt::Describe { job_name } => describe::run(job_name),
-        Opt::List {} => list::run(),
+        Opt::List => list::run(),
         Opt::Retry { job_name } => retry::run(job_name),
-        Opt::Run { pipeline_json } => {
+        Opt::Run {
+            pipeline_json,
+            overwrite,
+            clear_egress,
+        } => {
             let f =
                 File::open(pipeline_json).context("can't open pipeline JSON file")?;
-            let pipeline_spec: PipelineSpec = serde_json::from_reader(f)
+            let mut pipeline_spec: PipelineSpec = serde_json::from_reader(f)
                 .context("can't parse pipeline JSON file")?;
+            pipeline_spec.egress.overwrite |= overwrite;
+            pipeline_spec.egress.clear |= clear_egress;
             run::run(&pipeline_spec)
         }
         // Disabled because it's broken by recurive `"input"` types.
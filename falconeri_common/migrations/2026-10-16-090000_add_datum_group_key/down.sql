@@ -0,0 +1 @@
+ALTER TABLE datums DROP COLUMN group_key;
@@ -0,0 +1,2 @@
+-- The group key for datums created by `Input::Group`.
+ALTER TABLE datums ADD COLUMN group_key text;
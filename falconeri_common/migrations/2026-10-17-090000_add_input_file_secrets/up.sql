@@ -0,0 +1,3 @@
+-- The Kubernetes secrets needed to download each input file, as a JSON array
+-- of `Secret` values. If empty, the worker uses its environment.
+ALTER TABLE input_files ADD COLUMN secrets jsonb NOT NULL DEFAULT '[]';
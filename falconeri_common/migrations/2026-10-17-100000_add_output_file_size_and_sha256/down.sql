@@ -0,0 +1,2 @@
+ALTER TABLE output_files DROP COLUMN sha256;
+ALTER TABLE output_files DROP COLUMN size_bytes;
@@ -0,0 +1,5 @@
+-- The size and SHA-256 hash (as lowercase hex) of each output file, computed by
+-- the worker before uploading it. These are NULL for files recorded by older
+-- workers.
+ALTER TABLE output_files ADD COLUMN size_bytes bigint;
+ALTER TABLE output_files ADD COLUMN sha256 text;
@@ -0,0 +1 @@
+ALTER TABLE input_files DROP COLUMN decompress;
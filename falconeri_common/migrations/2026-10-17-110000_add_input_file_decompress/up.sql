@@ -0,0 +1,3 @@
+-- Should the worker decompress this file (or the `.gz` and `.zst` files in this
+-- directory) as it downloads it?
+ALTER TABLE input_files ADD COLUMN decompress boolean NOT NULL DEFAULT false;
@@ -0,0 +1 @@
+ALTER TABLE datums DROP COLUMN lease_expires_at;
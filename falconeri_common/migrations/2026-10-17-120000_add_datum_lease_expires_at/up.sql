@@ -0,0 +1,5 @@
+-- When the lease held by the worker processing this datum expires. Workers
+-- renew their lease by sending heartbeats, and the babysitter reclaims running
+-- datums whose lease has expired. This is NULL for datums which aren't running,
+-- and for datums reserved by older workers which don't send heartbeats.
+ALTER TABLE datums ADD COLUMN lease_expires_at timestamp;
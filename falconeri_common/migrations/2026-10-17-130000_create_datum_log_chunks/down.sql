@@ -0,0 +1 @@
+DROP TABLE datum_log_chunks;
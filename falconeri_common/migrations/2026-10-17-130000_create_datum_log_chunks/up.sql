@@ -0,0 +1,13 @@
+-- Output from a datum's command, streamed by the worker while the command is
+-- still running. Each attempt to process a datum has its own series of chunks,
+-- numbered from 0. The UNIQUE constraint allows workers to safely resend a
+-- chunk if they don't know whether it arrived.
+CREATE TABLE datum_log_chunks (
+    id uuid NOT NULL DEFAULT uuid_generate_v4() PRIMARY KEY,
+    created_at timestamp NOT NULL DEFAULT now(),
+    datum_id uuid NOT NULL REFERENCES datums(id) ON DELETE CASCADE,
+    attempt int NOT NULL,
+    seq int NOT NULL CHECK (seq >= 0),
+    data text NOT NULL,
+    UNIQUE (datum_id, attempt, seq)
+);
@@ -0,0 +1 @@
+ALTER TABLE datums DROP COLUMN output_log_uri;
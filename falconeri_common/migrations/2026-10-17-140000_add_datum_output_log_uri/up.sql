@@ -0,0 +1,3 @@
+-- Where the worker uploaded the full output of this datum's command, if it was
+-- too long to store in `output`.
+ALTER TABLE datums ADD COLUMN output_log_uri text;
@@ -0,0 +1,2 @@
+ALTER TABLE datums DROP COLUMN output_format;
+DROP TYPE output_format;
@@ -0,0 +1,5 @@
+-- How each line of a datum's output is formatted. Older workers recorded
+-- plain output, and newer workers start each line with a timestamp and the
+-- name of the stream it was written to.
+CREATE TYPE output_format AS ENUM ('plain', 'tagged');
+ALTER TABLE datums ADD COLUMN output_format output_format NOT NULL DEFAULT 'plain';
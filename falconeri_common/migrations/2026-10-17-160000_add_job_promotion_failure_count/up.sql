@@ -0,0 +1,3 @@
+-- How many times the babysitter has failed to promote a job's staged output
+-- files. We give up and mark the job as failed after a few tries.
+ALTER TABLE jobs ADD COLUMN promotion_failure_count integer NOT NULL DEFAULT 0;
@@ -0,0 +1,115 @@
+//! Support for compressing and decompressing files.
+
+use flate2::{read::MultiGzDecoder, write::GzEncoder};
+use std::io::{self, Read};
+
+use crate::prelude::*;
+
+/// A supported compression format.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    /// gzip, with the extension `.gz`.
+    Gzip,
+    /// Zstandard, with the extension `.zst`.
+    Zstd,
+}
+
+impl Compression {
+    /// The file extension used by this format, including the leading `.`.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    /// If `path` ends with the extension of a compression format, return that
+    /// format and `path` without the extension. A file named just `.gz` is not
+    /// treated as compressed.
+    pub fn split_extension(path: &str) -> Option<(Compression, &str)> {
+        [Compression::Gzip, Compression::Zstd]
+            .iter()
+            .find_map(|&compression| {
+                let stripped = path.strip_suffix(compression.extension())?;
+                if stripped.is_empty() || stripped.ends_with('/') {
+                    None
+                } else {
+                    Some((compression, stripped))
+                }
+            })
+    }
+
+    /// Compress all of `from` and write it to `to`, returning the number of
+    /// uncompressed bytes.
+    pub fn compress(self, from: &mut dyn Read, to: &mut dyn Write) -> Result<u64> {
+        let count = match self {
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(to, flate2::Compression::default());
+                let count = io::copy(from, &mut encoder)?;
+                encoder.finish()?;
+                count
+            }
+            Compression::Zstd => {
+                let mut encoder = zstd::Encoder::new(to, 0)?;
+                let count = io::copy(from, &mut encoder)?;
+                encoder.finish()?;
+                count
+            }
+        };
+        Ok(count)
+    }
+
+    /// Decompress all of `from` and write it to `to`, returning the number of
+    /// uncompressed bytes. This handles files containing several concatenated
+    /// gzip members or Zstandard frames, as produced by many parallel tools.
+    pub fn decompress(self, from: &mut dyn Read, to: &mut dyn Write) -> Result<u64> {
+        let count = match self {
+            Compression::Gzip => io::copy(&mut MultiGzDecoder::new(from), to)?,
+            Compression::Zstd => io::copy(&mut zstd::Decoder::new(from)?, to)?,
+        };
+        Ok(count)
+    }
+}
+
+#[test]
+fn splits_extensions() {
+    assert_eq!(
+        Compression::split_extension("gs://b/a.csv.gz"),
+        Some((Compression::Gzip, "gs://b/a.csv")),
+    );
+    assert_eq!(
+        Compression::split_extension("/pfs/in/a.zst"),
+        Some((Compression::Zstd, "/pfs/in/a")),
+    );
+    assert_eq!(Compression::split_extension("/pfs/in/a.csv"), None);
+    assert_eq!(Compression::split_extension("/pfs/in/.gz"), None);
+    assert_eq!(Compression::split_extension("a.gzip"), None);
+}
+
+#[test]
+fn round_trips() {
+    let data = b"hello, hello, hello, hello\n".repeat(100);
+    for &compression in &[Compression::Gzip, Compression::Zstd] {
+        let mut compressed = vec![];
+        let count = compression
+            .compress(&mut &data[..], &mut compressed)
+            .unwrap();
+        assert_eq!(count, data.len() as u64);
+        assert!(compressed.len() < data.len());
+
+        // Concatenated members or frames should decompress to the
+        // concatenated data.
+        let doubled = [&compressed[..], &compressed[..]].concat();
+        let mut decompressed = vec![];
+        let count = compression
+            .decompress(&mut &doubled[..], &mut decompressed)
+            .unwrap();
+        assert_eq!(count, 2 * data.len() as u64);
+        assert_eq!(decompressed, [&data[..], &data[..]].concat());
+
+        assert!(compression
+            .decompress(&mut &b"not compressed"[..], &mut vec![])
+            .is_err());
+    }
+}
@@ -1,12 +1,13 @@
 //! Tools for talking to Kubernetes.
 
+use base64::{prelude::BASE64_STANDARD, Engine};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use serde::de::{Deserialize, DeserializeOwned};
 use serde_json;
 use std::collections::HashSet;
 use std::{
-    env, iter,
+    env, fs, iter,
     process::{Command, Stdio},
 };
 
@@ -107,6 +108,20 @@ pub mod base64_encoded_secret_string {
     }
 }
 
+/// Like `base64_encoded_secret_string`, but for optional fields. Use with
+/// `#[serde(default, with = "base64_encoded_optional_secret_string")]`.
+pub mod base64_encoded_optional_secret_string {
+    use serde::de::Deserializer;
+    use std::result;
+
+    /// Deserialize a secret represented as a Base64-encoded UTF-8 string.
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> result::Result<Option<String>, D::Error> {
+        super::base64_encoded_secret_string::deserialize(deserializer).map(Some)
+    }
+}
+
 /// Fetch a secret and deserialize it as the specified type.
 #[tracing::instrument(level = "trace")]
 pub fn kubectl_secret<T: DeserializeOwned>(secret: &str) -> Result<T> {
@@ -115,6 +130,55 @@ pub fn kubectl_secret<T: DeserializeOwned>(secret: &str) -> Result<T> {
     Ok(secret.data)
 }
 
+/// The directory where the job manifest mounts the secrets listed in our
+/// `Input::Atom` and `Egress` values, one subdirectory per secret.
+pub const STORAGE_SECRETS_DIR: &str = "/etc/falconeri/storage-secrets";
+
+/// Read a secret needed to access storage, and deserialize it as the specified
+/// type. Inside a worker, the secret will be mounted under
+/// `STORAGE_SECRETS_DIR`. Anywhere else, we fetch it using `kubectl_secret`.
+#[tracing::instrument(level = "trace")]
+pub fn storage_secret<T: DeserializeOwned>(secret: &str) -> Result<T> {
+    let dir = Path::new(STORAGE_SECRETS_DIR).join(secret);
+    if dir.is_dir() {
+        mounted_secret(&dir)
+    } else {
+        kubectl_secret(secret)
+    }
+}
+
+/// Read a secret mounted as a directory, and deserialize it as the specified
+/// type. We Base64-encode each value, so that we can use the same types we
+/// use with `kubectl_secret`.
+fn mounted_secret<T: DeserializeOwned>(dir: &Path) -> Result<T> {
+    let mut data = serde_json::Map::new();
+    let entries = dir
+        .read_dir()
+        .with_context(|| format!("could not list secret {}", dir.display()))?;
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("could not list secret {}", dir.display()))?
+            .path();
+        let key = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| format_err!("invalid secret key {:?}", path))?;
+        // Kubernetes uses hidden entries like `..data` to update secrets
+        // atomically.
+        if key.starts_with('.') || !path.is_file() {
+            continue;
+        }
+        let value = fs::read(&path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        data.insert(
+            key.to_owned(),
+            serde_json::Value::String(BASE64_STANDARD.encode(value)),
+        );
+    }
+    serde_json::from_value(serde_json::Value::Object(data))
+        .with_context(|| format!("could not parse secret {}", dir.display()))
+}
+
 /// A list of items returned by Kubernetes.
 #[derive(Deserialize)]
 struct ItemsJson<T> {
@@ -264,3 +328,25 @@ pub fn node_name() -> Result<String> {
 pub fn pod_name() -> Result<String> {
     env::var("FALCONERI_POD_NAME").context("couldn't get FALCONERI_POD_NAME")
 }
+
+#[test]
+fn reads_mounted_secrets() {
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "SCREAMING_SNAKE_CASE", deny_unknown_fields)]
+    struct TestSecretData {
+        #[serde(with = "base64_encoded_secret_string")]
+        aws_access_key_id: String,
+    }
+
+    let dir = env::temp_dir().join(format!("falconeri-{}", Uuid::new_v4()));
+    fs::create_dir_all(dir.join("..data")).unwrap();
+    fs::write(dir.join("AWS_ACCESS_KEY_ID"), "key").unwrap();
+    let secret = mounted_secret::<TestSecretData>(&dir).unwrap();
+    assert_eq!(
+        secret,
+        TestSecretData {
+            aws_access_key_id: "key".to_owned(),
+        },
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -20,6 +20,7 @@ pub use semver;
 pub use serde_json;
 pub use tracing;
 
+pub mod compression;
 pub mod connect_via;
 pub mod db;
 pub mod errors;
@@ -1,7 +1,76 @@
+use diesel::{
+    backend::Backend,
+    deserialize::{self, FromSql},
+    pg::Pg,
+    serialize::{self, ToSql},
+};
+use std::collections::HashSet;
+
 use crate::kubernetes;
+use crate::models::sql_types;
 use crate::prelude::*;
 use crate::schema::*;
 
+/// How long a worker's lease on a datum lasts, in seconds. Workers should renew
+/// their leases well before this, using `Datum::renew_lease`.
+pub const DATUM_LEASE_SECONDS: i64 = 5 * 60;
+
+/// How many bytes from the beginning of a datum's output we store in
+/// `Datum::output`.
+pub const INLINE_OUTPUT_HEAD_BYTES: usize = 32 * 1024;
+
+/// How many bytes from the end of a datum's output we store in
+/// `Datum::output`.
+pub const INLINE_OUTPUT_TAIL_BYTES: usize = 32 * 1024;
+
+/// How the output of a datum's command is formatted.
+#[derive(
+    AsExpression,
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Deserialize,
+    Eq,
+    FromSqlRow,
+    PartialEq,
+    Serialize,
+)]
+#[diesel(sql_type = sql_types::OutputFormat)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    /// Plain output, as recorded by older workers.
+    #[default]
+    Plain,
+    /// Each line starts with a timestamp and the name of the stream it was
+    /// written to, as in `2026-10-17T12:00:00.123Z stderr oops`.
+    Tagged,
+}
+
+impl ToSql<sql_types::OutputFormat, Pg> for OutputFormat {
+    fn to_sql(&self, out: &mut serialize::Output<'_, '_, Pg>) -> serialize::Result {
+        match *self {
+            OutputFormat::Plain => out.write_all(b"plain")?,
+            OutputFormat::Tagged => out.write_all(b"tagged")?,
+        }
+        Ok(serialize::IsNull::No)
+    }
+}
+
+impl FromSql<sql_types::OutputFormat, Pg> for OutputFormat {
+    fn from_sql(bytes: <Pg as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        match String::from_sql(bytes)?.as_str() {
+            "plain" => Ok(OutputFormat::Plain),
+            "tagged" => Ok(OutputFormat::Tagged),
+            val => Err(format!(
+                "Unrecognized output format value from database: {}",
+                val
+            )
+            .into()),
+        }
+    }
+}
+
 /// A single chunk of work, consisting of one or more files.
 #[derive(Associations, Debug, Deserialize, Identifiable, Queryable, Serialize)]
 #[diesel(belongs_to(Job, foreign_key = job_id))]
@@ -24,7 +93,8 @@ pub struct Datum {
     pub pod_name: Option<String>,
     /// The backtrace associated with `error_message`, if any.
     pub backtrace: Option<String>,
-    /// Combined stdout and stderr of the code which processed the datum.
+    /// Combined stdout and stderr of the code which processed the datum. If
+    /// this was too long, we only store the beginning and the end.
     pub output: Option<String>,
     /// How many times have we tried to process this datum (counting attempts in
     /// progress)?
@@ -36,6 +106,19 @@ pub struct Datum {
     /// several queries, and (2) it gives us the option of allowing extra
     /// retries on a particular datum someday.
     pub maximum_allowed_run_count: i32,
+    /// The key shared by all the input files in this datum, if it was created
+    /// by an `Input::Group`.
+    pub group_key: Option<String>,
+    /// When the lease held by the worker processing this datum expires. This
+    /// is `None` unless the datum is running, or if the datum was reserved by
+    /// an older worker which doesn't send heartbeats.
+    pub lease_expires_at: Option<NaiveDateTime>,
+    /// Where the worker uploaded the full output of the code which processed
+    /// this datum, if `output` was too long to store in full and the job has an
+    /// `egress.log_uri`.
+    pub output_log_uri: Option<String>,
+    /// How each line of `output` is formatted.
+    pub output_format: OutputFormat,
 }
 
 impl Datum {
@@ -66,23 +149,85 @@ impl Datum {
         Ok(datums)
     }
 
-    /// Find datums which claim to be running, but whose `pod_name` points to a
-    /// non-existant pod.
+    /// Find datums which claim to be running, but whose worker has stopped
+    /// renewing its lease, regardless of whether its pod still exists.
+    ///
+    /// Datums reserved by older workers, which don't send heartbeats, have no
+    /// lease. For those, we fall back to checking whether `pod_name` points to
+    /// a non-existant pod.
     #[tracing::instrument(skip(conn), level = "trace")]
     pub fn zombies(conn: &mut PgConnection) -> Result<Vec<Datum>> {
         let running = Self::active_with_status(Status::Running, conn)?;
         trace!("running datums: {:?}", running);
-        let running_pod_names = kubernetes::get_running_pod_names()?;
-        Ok(running
+        let now = Utc::now().naive_utc();
+        let (leased, unleased): (Vec<_>, Vec<_>) = running
+            .into_iter()
+            .partition(|datum| datum.lease_expires_at.is_some());
+        let mut zombies = leased
             .into_iter()
-            .filter(|datum| match &datum.pod_name {
-                Some(pod_name) => !running_pod_names.contains(pod_name),
-                None => {
-                    warn!("datum {} has status=\"running\" but no pod_name", datum.id);
-                    true
-                }
-            })
-            .collect::<Vec<_>>())
+            .filter(|datum| datum.lease_has_expired(now))
+            .collect::<Vec<_>>();
+        if !unleased.is_empty() {
+            let running_pod_names = kubernetes::get_running_pod_names()?;
+            zombies.extend(
+                unleased
+                    .into_iter()
+                    .filter(|datum| datum.pod_is_missing(&running_pod_names)),
+            );
+        }
+        Ok(zombies)
+    }
+
+    /// Has our lease expired as of `now`? Returns false if we have no lease.
+    pub fn lease_has_expired(&self, now: NaiveDateTime) -> bool {
+        matches!(self.lease_expires_at, Some(expires_at) if expires_at < now)
+    }
+
+    /// Is our `pod_name` missing from `running_pod_names`? This is how we
+    /// detect zombies among datums without a lease.
+    fn pod_is_missing(&self, running_pod_names: &HashSet<String>) -> bool {
+        match &self.pod_name {
+            Some(pod_name) => !running_pod_names.contains(pod_name),
+            None => {
+                warn!("datum {} has status=\"running\" but no pod_name", self.id);
+                true
+            }
+        }
+    }
+
+    /// Extend the lease on this datum, if it's still running on `pod_name`.
+    /// Returns false (and reloads the datum) if the datum has been reclaimed or
+    /// reassigned, in which case the worker should stop processing it.
+    #[tracing::instrument(skip(conn), level = "trace")]
+    pub fn renew_lease(
+        &mut self,
+        pod_name: &str,
+        conn: &mut PgConnection,
+    ) -> Result<bool> {
+        let lease_expires_at =
+            Utc::now().naive_utc() + chrono::Duration::seconds(DATUM_LEASE_SECONDS);
+        let renewed = diesel::update(
+            datums::table.filter(
+                datums::id
+                    .eq(&self.id)
+                    .and(datums::status.eq(Status::Running))
+                    .and(datums::pod_name.eq(pod_name)),
+            ),
+        )
+        .set(datums::lease_expires_at.eq(Some(lease_expires_at)))
+        .get_result(conn)
+        .optional()
+        .with_context(|| format!("can't renew lease on datum {}", self.id))?;
+        match renewed {
+            Some(renewed) => {
+                *self = renewed;
+                Ok(true)
+            }
+            None => {
+                *self = Datum::find(self.id, conn)?;
+                Ok(false)
+            }
+        }
     }
 
     /// Find all datums which have errored, but that we can re-run.
@@ -113,6 +258,11 @@ impl Datum {
             && self.attempted_run_count < self.maximum_allowed_run_count
     }
 
+    /// Is this datum currently being processed by `pod_name`?
+    pub fn is_running_on(&self, pod_name: &str) -> bool {
+        self.status == Status::Running && self.pod_name.as_deref() == Some(pod_name)
+    }
+
     /// Get the input files for this datum.
     #[tracing::instrument(skip(conn), level = "trace")]
     pub fn input_files(&self, conn: &mut PgConnection) -> Result<Vec<InputFile>> {
@@ -139,6 +289,8 @@ impl Datum {
     pub fn mark_as_done(
         &mut self,
         output: &str,
+        output_log_uri: Option<&str>,
+        output_format: OutputFormat,
         conn: &mut PgConnection,
     ) -> Result<()> {
         let now = Utc::now().naive_utc();
@@ -147,6 +299,9 @@ impl Datum {
                 datums::updated_at.eq(now),
                 datums::status.eq(&Status::Done),
                 datums::output.eq(output),
+                datums::output_log_uri.eq(output_log_uri),
+                datums::output_format.eq(output_format),
+                datums::lease_expires_at.eq(None::<NaiveDateTime>),
             ))
             .get_result(conn)
             .context("can't mark datum as done")?;
@@ -158,6 +313,8 @@ impl Datum {
     pub fn mark_as_error(
         &mut self,
         output: &str,
+        output_log_uri: Option<&str>,
+        output_format: OutputFormat,
         error_message: &str,
         backtrace: &str,
         conn: &mut PgConnection,
@@ -168,8 +325,11 @@ impl Datum {
                 datums::updated_at.eq(now),
                 datums::status.eq(&Status::Error),
                 datums::output.eq(output),
+                datums::output_log_uri.eq(output_log_uri),
+                datums::output_format.eq(output_format),
                 datums::error_message.eq(&error_message),
                 datums::backtrace.eq(&backtrace),
+                datums::lease_expires_at.eq(None::<NaiveDateTime>),
             ))
             .get_result(conn)
             .context("can't mark datum as having failed")?;
@@ -224,6 +384,10 @@ impl Datum {
             output: None,
             attempted_run_count: 0,
             maximum_allowed_run_count: 1,
+            group_key: None,
+            lease_expires_at: None,
+            output_log_uri: None,
+            output_format: OutputFormat::Plain,
         }
     }
 }
@@ -242,6 +406,9 @@ pub struct NewDatum {
     /// How many times are we allowed to attempt to process this datum before
     /// failing for good?
     pub maximum_allowed_run_count: i32,
+    /// The key shared by all the input files in this datum, if it was created
+    /// by an `Input::Group`.
+    pub group_key: Option<String>,
 }
 
 impl NewDatum {
@@ -255,3 +422,24 @@ impl NewDatum {
         Ok(())
     }
 }
+
+#[test]
+fn lease_expiry() {
+    let job = Job::factory();
+    let mut datum = Datum::factory(&job);
+    let now = Utc::now().naive_utc();
+    assert!(!datum.lease_has_expired(now));
+    datum.lease_expires_at = Some(now - chrono::Duration::seconds(1));
+    assert!(datum.lease_has_expired(now));
+    datum.lease_expires_at =
+        Some(now + chrono::Duration::seconds(DATUM_LEASE_SECONDS));
+    assert!(!datum.lease_has_expired(now));
+
+    let mut running_pod_names = HashSet::new();
+    running_pod_names.insert("worker-1".to_owned());
+    assert!(datum.pod_is_missing(&running_pod_names));
+    datum.pod_name = Some("worker-1".to_owned());
+    assert!(!datum.pod_is_missing(&running_pod_names));
+    datum.pod_name = Some("worker-2".to_owned());
+    assert!(datum.pod_is_missing(&running_pod_names));
+}
@@ -0,0 +1,243 @@
+use cast;
+use diesel::{
+    dsl,
+    sql_types::{Binary, Bool, Integer, Nullable},
+};
+
+use crate::prelude::*;
+use crate::schema::*;
+
+/// How long we keep the chunks of output from an attempt after it finishes, so
+/// that anyone following the datum's logs can print the rest of them. After
+/// that, the datum's output is only available from `Datum::output`.
+pub const FINISHED_LOG_CHUNK_SECONDS: i64 = 10 * 60;
+
+/// How much output from the beginning of an attempt we keep as log chunks.
+/// Beyond this, we only keep the most recent `STREAMED_OUTPUT_TAIL_BYTES`, so
+/// that a command which writes a lot of output can't fill up the database.
+pub const STREAMED_OUTPUT_HEAD_BYTES: usize = 1024 * 1024;
+
+/// How much output from the end of an attempt we keep as log chunks, once it
+/// has written more than `STREAMED_OUTPUT_HEAD_BYTES`.
+pub const STREAMED_OUTPUT_TAIL_BYTES: usize = 1024 * 1024;
+
+/// A chunk of output from a datum's command, streamed by the worker while the
+/// command is running.
+#[derive(Associations, Debug, Deserialize, Identifiable, Queryable, Serialize)]
+#[diesel(belongs_to(Datum, foreign_key = datum_id))]
+pub struct DatumLogChunk {
+    /// The unique ID of this chunk.
+    pub id: Uuid,
+    /// When this chunk was received.
+    pub created_at: NaiveDateTime,
+    /// The datum which produced this output.
+    pub datum_id: Uuid,
+    /// Which attempt to process the datum produced this output. This is the
+    /// datum's `attempted_run_count` at the time.
+    pub attempt: i32,
+    /// The position of this chunk in the output of `attempt`, starting at 0.
+    pub seq: i32,
+    /// The output itself.
+    pub data: String,
+}
+
+impl DatumLogChunk {
+    /// Fetch the chunks of output produced by `attempt` to process `datum`, in
+    /// order. If `after_seq` is specified, only return chunks after that one.
+    #[tracing::instrument(skip(conn), level = "trace")]
+    pub fn for_datum_attempt(
+        datum: &Datum,
+        attempt: i32,
+        after_seq: Option<i32>,
+        conn: &mut PgConnection,
+    ) -> Result<Vec<DatumLogChunk>> {
+        DatumLogChunk::belonging_to(datum)
+            .filter(datum_log_chunks::attempt.eq(attempt))
+            .filter(datum_log_chunks::seq.gt(after_seq.unwrap_or(-1)))
+            .order_by(datum_log_chunks::seq)
+            .load(conn)
+            .with_context(|| {
+                format!("could not load log chunks for datum {}", datum.id)
+            })
+    }
+
+    /// Combine the chunks of output produced by `attempt` to process `datum`,
+    /// keeping only the beginning and end of long output, like the worker
+    /// does. Returns `None` if there are no chunks. If some chunks were
+    /// skipped or trimmed, we can't say how much output we're omitting.
+    ///
+    /// We do this in the database, so that we never load all of a long
+    /// output into memory. We work with bytes, so that we get the same
+    /// results as the worker.
+    #[tracing::instrument(skip(conn), level = "trace")]
+    pub fn inline_output_for_datum_attempt(
+        datum: &Datum,
+        attempt: i32,
+        conn: &mut PgConnection,
+    ) -> Result<Option<String>> {
+        let output = "convert_to(string_agg(data, '' ORDER BY seq), 'UTF8')";
+        let select = format!(
+            concat!(
+                "octet_length({output}), ",
+                "substring({output} from 1 for {head}), ",
+                "substring({output} from greatest(octet_length({output}) - {tail} + 1, 1)), ",
+                "count(*) = max(seq) + 1",
+            ),
+            output = output,
+            head = INLINE_OUTPUT_HEAD_BYTES,
+            tail = INLINE_OUTPUT_TAIL_BYTES,
+        );
+        let (len, head, tail, complete) = DatumLogChunk::belonging_to(datum)
+            .filter(datum_log_chunks::attempt.eq(attempt))
+            .select(dsl::sql::<(
+                Nullable<Integer>,
+                Nullable<Binary>,
+                Nullable<Binary>,
+                Nullable<Bool>,
+            )>(&select))
+            .first::<(Option<i32>, Option<Vec<u8>>, Option<Vec<u8>>, Option<bool>)>(
+                conn,
+            )
+            .with_context(|| {
+                format!("could not combine log chunks for datum {}", datum.id)
+            })?;
+        let (len, head, tail, complete) = match (len, head, tail, complete) {
+            (Some(len), Some(head), Some(tail), Some(complete)) => {
+                (cast::usize(len)?, head, tail, complete)
+            }
+            _ => return Ok(None),
+        };
+        let inline_len = INLINE_OUTPUT_HEAD_BYTES + INLINE_OUTPUT_TAIL_BYTES;
+        if !complete {
+            Ok(Some(format!(
+                "{}\n[... output omitted ...]\n{}",
+                String::from_utf8_lossy(&head),
+                String::from_utf8_lossy(&tail),
+            )))
+        } else if len <= inline_len {
+            // `head` and `tail` overlap, so rebuild the whole output from
+            // `head` and the part of `tail` which comes after it.
+            let skip = (head.len() + tail.len()).saturating_sub(len);
+            let mut output = head;
+            output.extend_from_slice(&tail[skip..]);
+            Ok(Some(String::from_utf8_lossy(&output).into_owned()))
+        } else {
+            Ok(Some(format!(
+                "{}\n[... {} bytes omitted ...]\n{}",
+                String::from_utf8_lossy(&head),
+                len - inline_len,
+                String::from_utf8_lossy(&tail),
+            )))
+        }
+    }
+
+    /// Delete any chunks of output produced by `attempt` to process `datum`
+    /// which are neither in the first `STREAMED_OUTPUT_HEAD_BYTES` nor the last
+    /// `STREAMED_OUTPUT_TAIL_BYTES` of the chunks we have. We call this each
+    /// time we add a chunk, so the tail keeps moving forward.
+    #[tracing::instrument(skip(conn), level = "trace")]
+    pub fn trim_datum_attempt(
+        datum: &Datum,
+        attempt: i32,
+        conn: &mut PgConnection,
+    ) -> Result<()> {
+        // For each chunk, find how many bytes come before it and after it.
+        // Since we never delete the head, the offsets of the head's chunks
+        // never change.
+        let offsets = format!(
+            concat!(
+                "SELECT id, ",
+                "sum(octet_length(data)) OVER (ORDER BY seq) - octet_length(data) AS before, ",
+                "sum(octet_length(data)) OVER (ORDER BY seq DESC) - octet_length(data) AS after ",
+                "FROM datum_log_chunks WHERE datum_id = '{datum_id}' AND attempt = {attempt}",
+            ),
+            datum_id = datum.id,
+            attempt = attempt,
+        );
+        diesel::delete(datum_log_chunks::table.filter(dsl::sql::<Bool>(&format!(
+            "id IN (SELECT id FROM ({offsets}) AS offsets \
+             WHERE before >= {head} AND after >= {tail})",
+            offsets = offsets,
+            head = STREAMED_OUTPUT_HEAD_BYTES,
+            tail = STREAMED_OUTPUT_TAIL_BYTES,
+        ))))
+        .execute(conn)
+        .with_context(|| {
+            format!("could not trim log chunks for datum {}", datum.id)
+        })?;
+        Ok(())
+    }
+
+    /// Delete the chunks of output produced by attempts which have finished,
+    /// once they're more than `FINISHED_LOG_CHUNK_SECONDS` old. An attempt has
+    /// finished if its datum has finished, or if the datum has been retried
+    /// since. By then, its output has been stored in `Datum::output`, and
+    /// possibly uploaded somewhere else.
+    ///
+    /// We never delete the chunks of a running attempt, because the babysitter
+    /// needs them if the worker disappears.
+    #[tracing::instrument(skip(conn), level = "trace")]
+    pub fn delete_expired(conn: &mut PgConnection) -> Result<usize> {
+        let cutoff = Utc::now().naive_utc()
+            - chrono::Duration::seconds(FINISHED_LOG_CHUNK_SECONDS);
+        diesel::delete(
+            datum_log_chunks::table
+                .filter(datum_log_chunks::created_at.lt(cutoff))
+                .filter(dsl::sql::<Bool>(
+                    "EXISTS (SELECT 1 FROM datums \
+                     WHERE datums.id = datum_log_chunks.datum_id \
+                     AND (datums.status IN ('done', 'error', 'canceled') \
+                     OR datums.attempted_run_count > datum_log_chunks.attempt))",
+                )),
+        )
+        .execute(conn)
+        .context("could not delete expired log chunks")
+    }
+
+    /// Generate a sample value for testing.
+    pub fn factory(datum: &Datum) -> Self {
+        DatumLogChunk {
+            id: Uuid::new_v4(),
+            created_at: Utc::now().naive_utc(),
+            datum_id: datum.id,
+            attempt: datum.attempted_run_count,
+            seq: 0,
+            data: "Hello!\n".to_owned(),
+        }
+    }
+}
+
+/// Data required to create a new `DatumLogChunk`.
+#[derive(Debug, Insertable)]
+#[diesel(table_name = datum_log_chunks)]
+pub struct NewDatumLogChunk {
+    /// The datum which produced this output.
+    pub datum_id: Uuid,
+    /// Which attempt to process the datum produced this output.
+    pub attempt: i32,
+    /// The position of this chunk in the output of `attempt`.
+    pub seq: i32,
+    /// The output itself.
+    pub data: String,
+}
+
+impl NewDatumLogChunk {
+    /// Insert this chunk into the database. If we already have a chunk with
+    /// the same `seq`, we assume that this is a resend and ignore it.
+    #[tracing::instrument(skip(conn), level = "trace")]
+    pub fn insert(&self, conn: &mut PgConnection) -> Result<()> {
+        diesel::insert_into(datum_log_chunks::table)
+            .values(self)
+            .on_conflict((
+                datum_log_chunks::datum_id,
+                datum_log_chunks::attempt,
+                datum_log_chunks::seq,
+            ))
+            .do_nothing()
+            .execute(conn)
+            .with_context(|| {
+                format!("error inserting log chunk for datum {}", self.datum_id)
+            })?;
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 use crate::prelude::*;
 use crate::schema::*;
+use crate::secret::Secret;
 
 /// An input file which needs to be downloaded to the worker container.
 #[derive(Associations, Debug, Deserialize, Identifiable, Queryable, Serialize)]
@@ -17,9 +18,23 @@ pub struct InputFile {
     pub local_path: String,
     /// The job to which this input file belongs.
     pub job_id: Uuid,
+    /// The Kubernetes secrets needed to download this file, as a JSON array of
+    /// `Secret` values. Use `storage_secrets` to parse this.
+    pub secrets: serde_json::Value,
+    /// Should we decompress this file (or the compressed files in this
+    /// directory) as we download it?
+    pub decompress: bool,
 }
 
 impl InputFile {
+    /// The Kubernetes secrets needed to download this file. If this is empty,
+    /// the worker should use the credentials in its environment.
+    pub fn storage_secrets(&self) -> Result<Vec<Secret>> {
+        serde_json::from_value(self.secrets.clone()).with_context(|| {
+            format!("could not parse secrets for input file {}", self.id)
+        })
+    }
+
     /// Fetch all the input files corresponding to `datums`, returning grouped
     /// in the same order.
     #[tracing::instrument(skip(conn), level = "trace")]
@@ -43,6 +58,8 @@ impl InputFile {
             uri: "gs://example-bucket/input/file.csv".to_owned(),
             local_path: "/pfs/input/file.csv".to_owned(),
             job_id: datum.job_id,
+            secrets: serde_json::Value::Array(vec![]),
+            decompress: false,
         }
     }
 }
@@ -59,6 +76,11 @@ pub struct NewInputFile {
     pub local_path: String,
     /// The job to which this input file belongs.
     pub job_id: Uuid,
+    /// The Kubernetes secrets needed to download this file, as a JSON array of
+    /// `Secret` values.
+    pub secrets: serde_json::Value,
+    /// Should we decompress this file as we download it?
+    pub decompress: bool,
 }
 
 impl NewInputFile {
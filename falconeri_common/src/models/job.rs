@@ -1,9 +1,14 @@
 use cast;
 use diesel::dsl;
 use serde_json;
+use std::time::Duration;
 
+use crate::compression::Compression;
+use crate::pipeline::{CollisionPolicy, Egress};
 use crate::prelude::*;
 use crate::schema::*;
+use crate::secret::Secret;
+use crate::storage::{CloudStorage, UploadOptions};
 
 /// A distributed data processing job.
 #[derive(Debug, Deserialize, Identifiable, Queryable, Serialize)]
@@ -26,6 +31,9 @@ pub struct Job {
     pub command: Vec<String>,
     /// The output bucket or bucket path.
     pub egress_uri: String,
+    /// How many times we've failed to promote this job's staged output files,
+    /// if it uses atomic egress.
+    pub promotion_failure_count: i32,
 }
 
 impl Job {
@@ -59,6 +67,28 @@ impl Job {
             .with_context(|| format!("could not load jobs with status {}", status))
     }
 
+    /// Find running jobs whose datums have all finished successfully, but
+    /// which still have staged output files that need to be promoted by
+    /// [`Job::promote_staged_output_files`]. Staged files always have our job
+    /// ID in their path, so this may return a few extra jobs, but it never
+    /// misses any.
+    #[tracing::instrument(skip(conn), level = "trace")]
+    pub fn find_ready_to_promote(conn: &mut PgConnection) -> Result<Vec<Job>> {
+        jobs::table
+            .filter(jobs::status.eq(Status::Running))
+            .filter(dsl::sql::<diesel::sql_types::Bool>(
+                "EXISTS (SELECT 1 FROM output_files \
+                 WHERE output_files.job_id = jobs.id \
+                 AND output_files.status = 'done' \
+                 AND output_files.uri LIKE ('%/' || jobs.id || '/%')) \
+                 AND NOT EXISTS (SELECT 1 FROM datums \
+                 WHERE datums.job_id = jobs.id \
+                 AND datums.status <> 'done')",
+            ))
+            .load(conn)
+            .context("could not load jobs with staged output files")
+    }
+
     /// Get all known jobs.
     #[tracing::instrument(skip(conn), level = "trace")]
     pub fn list(conn: &mut PgConnection) -> Result<Vec<Job>> {
@@ -81,11 +111,12 @@ impl Job {
         // a reservation got lost somewhere between `falconeri-postgres` and
         // `falconeri-worker`), and if none exists, make a new one.
         let mut datum = self.find_already_reserved_datum(pod_name, conn)?;
-        if let Some(ref datum) = datum {
+        if let Some(ref mut datum) = datum {
             warn!(
                 "pod {} tried to reserve datum {} more than once",
                 pod_name, datum.id,
             );
+            datum.renew_lease(pod_name, conn)?;
         } else {
             datum = self.actually_reserve_next_datum(node_name, pod_name, conn)?;
         }
@@ -150,12 +181,15 @@ impl Job {
             if let Some(datum_id) = datum_id {
                 let to_update = datums::table.filter(datums::id.eq(&datum_id));
                 let now = Utc::now().naive_utc();
+                let lease_expires_at =
+                    now + chrono::Duration::seconds(DATUM_LEASE_SECONDS);
                 let datum: Datum = diesel::update(to_update)
                     .set((
                         datums::updated_at.eq(now),
                         datums::status.eq(&Status::Running),
                         datums::node_name.eq(&Some(node_name)),
                         datums::pod_name.eq(&Some(pod_name)),
+                        datums::lease_expires_at.eq(Some(lease_expires_at)),
                         datums::attempted_run_count
                             .eq(datums::attempted_run_count + 1),
                     ))
@@ -205,6 +239,45 @@ impl Job {
             .collect::<Result<_>>()
     }
 
+    /// Summarize the output files which this job has successfully uploaded.
+    #[tracing::instrument(skip(conn), level = "trace")]
+    pub fn output_file_summary(
+        &self,
+        conn: &mut PgConnection,
+    ) -> Result<OutputFileSummary> {
+        let (count, empty_count, unknown_size_count, size_bytes): (i64, i64, i64, i64) =
+            OutputFile::belonging_to(self)
+                .filter(output_files::status.eq(&Status::Done))
+                .select(dsl::sql::<(
+                    diesel::sql_types::BigInt,
+                    diesel::sql_types::BigInt,
+                    diesel::sql_types::BigInt,
+                    diesel::sql_types::BigInt,
+                )>(
+                    "count(*), count(*) filter (where size_bytes = 0), count(*) filter (where size_bytes is null), coalesce(sum(size_bytes), 0)::bigint",
+                ))
+                .first(conn)
+                .context("cannot summarize output files")?;
+        let staged_count: i64 = match self.staging_uri()? {
+            Some(staging_uri) => OutputFile::belonging_to(self)
+                .filter(output_files::status.eq(&Status::Done))
+                .filter(
+                    output_files::uri.like(format!("{}%", like_escape(&staging_uri))),
+                )
+                .count()
+                .get_result(conn)
+                .context("cannot count staged output files")?,
+            None => 0,
+        };
+        Ok(OutputFileSummary {
+            count: cast::u64(count)?,
+            empty_count: cast::u64(empty_count)?,
+            unknown_size_count: cast::u64(unknown_size_count)?,
+            size_bytes: cast::u64(size_bytes)?,
+            staged_count: cast::u64(staged_count)?,
+        })
+    }
+
     /// Get all our our currently running datums (the ones being processed by
     /// a worker somewhere).
     #[tracing::instrument(skip(conn), level = "trace")]
@@ -236,14 +309,14 @@ impl Job {
     #[tracing::instrument(skip(conn), level = "trace")]
     pub fn update_status_if_done(&mut self, conn: &mut PgConnection) -> Result<()> {
         trace!("querying for status of datums for job {}", self.id);
-        conn.transaction(|conn| {
+        let staged_uris = conn.transaction(|conn| -> Result<Vec<String>> {
             // Lock this job for update. This isn't necessary for this routine
             // by itself, but it should help avoid race conditions with job
             // retries and the babysitter.
             self.lock_for_update(conn)?;
             if self.status != Status::Running {
                 // Nothing to do, so return immediately.
-                return Ok(());
+                return Ok(vec![]);
             }
 
             // Count the datums with various statuses and divide them into
@@ -278,7 +351,7 @@ impl Job {
             }
 
             // Decide what to do, if anything.
-            let job_status = if unfinished > 0 || rerunable > 0 {
+            let mut job_status = if unfinished > 0 || rerunable > 0 {
                 trace!(
                     "{} datums remaining, {} rerunable, not updating job status",
                     unfinished,
@@ -295,6 +368,23 @@ impl Job {
                 );
                 Some(Status::Done)
             };
+            // If we're using atomic egress and we failed, we'll need to delete
+            // our staged output files. If we succeeded, we're not done until
+            // the babysitter has promoted them, outside of this transaction.
+            let mut staged_uris = vec![];
+            if let Some(staging_uri) = self.staging_uri()? {
+                match job_status {
+                    Some(Status::Done)
+                        if self.has_staged_output_files(&staging_uri, conn)? =>
+                    {
+                        debug!("waiting for staged output files to be promoted");
+                        job_status = None;
+                    }
+                    Some(Status::Error) => staged_uris = self.output_uris(conn)?,
+                    _ => {}
+                }
+            }
+
             if let Some(job_status) = job_status {
                 *self = diesel::update(jobs::table)
                     .filter(jobs::id.eq(&self.id))
@@ -306,8 +396,146 @@ impl Job {
                     .context("could not update job status")?;
             }
 
-            Ok(())
-        })
+            Ok(staged_uris)
+        })?;
+
+        // Now that we've updated our status, clean up any staged files.
+        self.delete_staged_output_files(&staged_uris);
+        Ok(())
+    }
+
+    /// If this job is using atomic egress, and all its datums have finished
+    /// successfully, copy any staged output files which haven't been promoted
+    /// yet to `egress_uri`, update our `OutputFile` records to point to the
+    /// copies, delete everything under our staging prefix, and mark the job as
+    /// done.
+    ///
+    /// This must not be called from within a transaction. We update each
+    /// `OutputFile` as soon as we've copied it, so if we fail part way
+    /// through, calling this again will pick up where we left off.
+    #[tracing::instrument(skip(conn), level = "trace")]
+    pub fn promote_staged_output_files(
+        &mut self,
+        conn: &mut PgConnection,
+    ) -> Result<()> {
+        let staging_uri = match self.staging_uri()? {
+            Some(staging_uri) if self.status == Status::Running => staging_uri,
+            _ => return Ok(()),
+        };
+        let status_counts = self.datum_status_counts(conn)?;
+        if status_counts.iter().any(|c| c.status != Status::Done) {
+            return Err(format_err!(
+                "cannot promote output files for job {} before all datums are done",
+                self.job_name,
+            ));
+        }
+        let output_files = OutputFile::belonging_to(&*self)
+            .filter(output_files::status.eq(&Status::Done))
+            .filter(output_files::uri.like(format!("{}%", like_escape(&staging_uri))))
+            .load::<OutputFile>(conn)
+            .context("could not load staged output files")?;
+        if !output_files.is_empty() {
+            debug!(
+                "promoting {} staged output files for job {}",
+                output_files.len(),
+                self.job_name
+            );
+            let storage = self.egress_storage()?;
+            for output_file in &output_files {
+                let final_uri = promote_staged_file(
+                    &*storage,
+                    &staging_uri,
+                    &self.egress_uri,
+                    &output_file.uri,
+                )?;
+                diesel::update(output_files::table.find(output_file.id))
+                    .set((
+                        output_files::updated_at.eq(Utc::now().naive_utc()),
+                        output_files::uri.eq(final_uri),
+                    ))
+                    .execute(conn)
+                    .context("could not update output file URI")?;
+            }
+
+            // Now that nothing refers to our staged files, delete them, along
+            // with any files left behind by failed uploads.
+            let staged_uris = storage
+                .list(&staging_uri)?
+                .into_iter()
+                .map(|obj| obj.uri)
+                .collect::<Vec<_>>();
+            self.delete_staged_output_files(&staged_uris);
+        }
+
+        // Now that we've promoted everything, we're done.
+        self.update_status_if_done(conn)
+    }
+
+    /// Does this job have any successfully uploaded output files which are
+    /// still under `staging_uri`?
+    #[tracing::instrument(skip(conn), level = "trace")]
+    fn has_staged_output_files(
+        &self,
+        staging_uri: &str,
+        conn: &mut PgConnection,
+    ) -> Result<bool> {
+        dsl::select(dsl::exists(
+            OutputFile::belonging_to(self)
+                .filter(output_files::status.eq(&Status::Done))
+                .filter(
+                    output_files::uri.like(format!("{}%", like_escape(staging_uri))),
+                ),
+        ))
+        .get_result(conn)
+        .context("could not check for staged output files")
+    }
+
+    /// Get the URIs of all our output files, regardless of whether they were
+    /// uploaded successfully. If we're using atomic egress and we failed, these
+    /// are either still staged, or were copied by a promotion which never
+    /// finished, so we need to delete all of them.
+    #[tracing::instrument(skip(conn), level = "trace")]
+    fn output_uris(&self, conn: &mut PgConnection) -> Result<Vec<String>> {
+        OutputFile::belonging_to(self)
+            .select(output_files::uri)
+            .load(conn)
+            .context("could not load output files")
+    }
+
+    /// Delete the staged output files at `staged_uris`. This is best-effort,
+    /// because nothing refers to them any more, so we only log errors.
+    fn delete_staged_output_files(&self, staged_uris: &[String]) {
+        if staged_uris.is_empty() {
+            return;
+        }
+        let result = self
+            .egress_storage()
+            .and_then(|storage| delete_staged_files(&*storage, staged_uris));
+        if let Err(err) = result {
+            warn!(
+                "could not delete staged output files for job {}: {}",
+                self.job_name,
+                err.display_causes_and_backtrace()
+            );
+        }
+    }
+
+    /// Record that we failed to promote our staged output files, and return the
+    /// total number of times we've failed.
+    #[tracing::instrument(skip(conn), level = "trace")]
+    pub fn record_promotion_failure(
+        &mut self,
+        conn: &mut PgConnection,
+    ) -> Result<i32> {
+        *self = diesel::update(jobs::table)
+            .filter(jobs::id.eq(&self.id))
+            .set((
+                jobs::updated_at.eq(Utc::now().naive_utc()),
+                jobs::promotion_failure_count.eq(jobs::promotion_failure_count + 1),
+            ))
+            .get_result(conn)
+            .context("could not record promotion failure")?;
+        Ok(self.promotion_failure_count)
     }
 
     /// Mark this job as having errored.
@@ -325,9 +553,123 @@ impl Job {
             ))
             .get_result(conn)
             .context("could not update job status")?;
+        if self.staging_uri()?.is_some() {
+            let output_uris = self.output_uris(conn)?;
+            self.delete_staged_output_files(&output_uris);
+        }
         Ok(())
     }
 
+    /// Our `egress` settings, if our `pipeline_spec` has any.
+    fn egress(&self) -> Result<Option<Egress>> {
+        match self.pipeline_spec.get("egress") {
+            Some(egress) => {
+                Ok(Some(serde_json::from_value(egress.clone()).with_context(
+                    || format!("could not parse egress for job {}", self.id),
+                )?))
+            }
+            // `Job::factory` doesn't include a copy of `egress`.
+            None => Ok(None),
+        }
+    }
+
+    /// The Kubernetes secrets needed to upload to `egress_uri`. If this is
+    /// empty, the worker should use the credentials in its environment.
+    pub fn egress_secrets(&self) -> Result<Vec<Secret>> {
+        Ok(self
+            .egress()?
+            .map(|egress| egress.secrets)
+            .unwrap_or_default())
+    }
+
+    /// What to do when two datums try to upload output files with the same
+    /// path.
+    pub fn collision_policy(&self) -> Result<CollisionPolicy> {
+        Ok(self
+            .egress()?
+            .map(|egress| egress.on_collision)
+            .unwrap_or_default())
+    }
+
+    /// How long a worker may spend running the command for a single datum, if
+    /// this is limited. We store this as a number of seconds.
+    pub fn datum_timeout(&self) -> Result<Option<Duration>> {
+        let secs = match self.pipeline_spec.get("datum_timeout") {
+            Some(secs) => serde_json::from_value::<Option<u64>>(secs.clone())
+                .with_context(|| {
+                    format!("could not parse datum_timeout for job {}", self.id)
+                })?,
+            None => None,
+        };
+        if secs == Some(0) {
+            return Err(format_err!("datum_timeout for job {} is zero", self.id));
+        }
+        Ok(secs.map(Duration::from_secs))
+    }
+
+    /// The options to use when uploading output files, such as encryption and
+    /// storage class.
+    pub fn upload_options(&self) -> Result<UploadOptions> {
+        match self.egress()? {
+            Some(egress) => egress.upload_options(),
+            None => Ok(UploadOptions::default()),
+        }
+    }
+
+    /// The format that workers should use to compress output files, if any.
+    pub fn output_compression(&self) -> Result<Option<Compression>> {
+        Ok(self.egress()?.and_then(|egress| egress.compress))
+    }
+
+    /// The prefix under which workers should upload the full output of datums
+    /// whose output is too long to store in the database, if any.
+    pub fn log_uri(&self) -> Result<Option<String>> {
+        Ok(self.egress()?.and_then(|egress| egress.log_uri))
+    }
+
+    /// If this job uses `egress.atomic`, the prefix under which workers should
+    /// stage their output files.
+    pub fn staging_uri(&self) -> Result<Option<String>> {
+        self.egress()?
+            .filter(|egress| egress.atomic)
+            .map(|egress| egress.job_staging_uri(self.id))
+            .transpose()
+    }
+
+    /// The prefix to which workers should upload their output files. This is
+    /// `egress_uri`, unless we're staging our output.
+    pub fn upload_uri(&self) -> Result<String> {
+        Ok(self
+            .staging_uri()?
+            .unwrap_or_else(|| self.egress_uri.clone()))
+    }
+
+    /// Get the storage backend for `egress_uri`, for use by `falconerid`. Like
+    /// our inputs, this uses `egress.secrets` if present, and
+    /// `transform.secrets` otherwise.
+    pub fn egress_storage(&self) -> Result<Box<dyn CloudStorage>> {
+        let mut secrets = self.egress_secrets()?;
+        if secrets.is_empty() {
+            secrets = self.transform_secrets()?;
+        }
+        let mut storage = <dyn CloudStorage>::for_uri(&self.egress_uri, &secrets)?;
+        storage.set_upload_options(self.upload_options()?)?;
+        Ok(storage)
+    }
+
+    /// The Kubernetes secrets specified by `transform.secrets`. These are used
+    /// to access any input or egress which doesn't specify its own secrets.
+    pub fn transform_secrets(&self) -> Result<Vec<Secret>> {
+        match self.pipeline_spec.pointer("/transform/secrets") {
+            Some(secrets) => {
+                serde_json::from_value(secrets.clone()).with_context(|| {
+                    format!("could not parse transform secrets for job {}", self.id)
+                })
+            }
+            None => Ok(vec![]),
+        }
+    }
+
     /// Generate a sample value for testing.
     pub fn factory() -> Self {
         let now = Utc::now().naive_utc();
@@ -340,10 +682,73 @@ impl Job {
             job_name: "my-job-123az".to_owned(), // TODO: Make unique.
             command: vec!["echo".to_owned(), "hi".to_owned()],
             egress_uri: "gs://example-bucket/output/".to_owned(),
+            promotion_failure_count: 0,
         }
     }
 }
 
+/// Escape `s` for use in an SQL `LIKE` pattern.
+fn like_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Copy the file at `staged_uri`, which must be located under `staging_uri`,
+/// to the corresponding location under `egress_uri`, and return the new URI.
+///
+/// If the staged file no longer exists, we assume that an earlier attempt
+/// already copied it, and only check that the copy exists.
+fn promote_staged_file(
+    storage: &dyn CloudStorage,
+    staging_uri: &str,
+    egress_uri: &str,
+    staged_uri: &str,
+) -> Result<String> {
+    let rel_path = staged_uri
+        .strip_prefix(staging_uri)
+        .ok_or_else(|| format_err!("{:?} is not in {:?}", staged_uri, staging_uri))?;
+    let final_uri = format!("{}/{}", egress_uri.trim_end_matches('/'), rel_path);
+    if storage.exists(staged_uri)? {
+        storage.copy(staged_uri, &final_uri)?;
+    } else if !storage.exists(&final_uri)? {
+        return Err(format_err!(
+            "cannot promote {}, because it no longer exists",
+            staged_uri,
+        ));
+    }
+    Ok(final_uri)
+}
+
+/// Delete each of the files at `staged_uris`.
+fn delete_staged_files(
+    storage: &dyn CloudStorage,
+    staged_uris: &[String],
+) -> Result<()> {
+    for staged_uri in staged_uris {
+        trace!("deleting staged file {}", staged_uri);
+        storage.delete(staged_uri)?;
+    }
+    Ok(())
+}
+
+/// A summary of the output files uploaded by a job.
+#[derive(Debug, Serialize)]
+pub struct OutputFileSummary {
+    /// The number of output files which were uploaded successfully.
+    pub count: u64,
+    /// The number of those files which were empty.
+    pub empty_count: u64,
+    /// The number of those files which were uploaded by older workers that
+    /// didn't record a size.
+    pub unknown_size_count: u64,
+    /// The total size of those files, in bytes.
+    pub size_bytes: u64,
+    /// The number of those files which are still staged, waiting to be
+    /// promoted into `egress_uri`.
+    pub staged_count: u64,
+}
+
 /// The number of datums with a specified status, plus how many are retryable.
 #[derive(Debug, Queryable, Serialize)]
 pub struct DatumStatusCount {
@@ -382,3 +787,94 @@ impl NewJob {
             .context("error inserting job")
     }
 }
+
+#[test]
+fn egress_uris() {
+    let mut job = Job::factory();
+    assert_eq!(job.staging_uri().unwrap(), None);
+    assert_eq!(job.upload_uri().unwrap(), job.egress_uri);
+
+    job.egress_uri = "mem://egress-uris/out/".to_owned();
+    job.pipeline_spec = serde_json::json!({
+        "egress": { "URI": &job.egress_uri, "atomic": true },
+    });
+    let staging_uri = format!("mem://egress-uris/_falconeri_staging/{}/", job.id);
+    assert_eq!(job.staging_uri().unwrap(), Some(staging_uri.clone()));
+    assert_eq!(job.upload_uri().unwrap(), staging_uri);
+}
+
+#[test]
+fn datum_timeout() {
+    let mut job = Job::factory();
+    assert_eq!(job.datum_timeout().unwrap(), None);
+    job.pipeline_spec = serde_json::json!({ "datum_timeout": null });
+    assert_eq!(job.datum_timeout().unwrap(), None);
+    job.pipeline_spec = serde_json::json!({ "datum_timeout": 90 });
+    assert_eq!(job.datum_timeout().unwrap(), Some(Duration::from_secs(90)));
+    job.pipeline_spec = serde_json::json!({ "datum_timeout": "90s" });
+    assert!(job.datum_timeout().is_err());
+    job.pipeline_spec = serde_json::json!({ "datum_timeout": 0 });
+    assert!(job.datum_timeout().is_err());
+}
+
+#[test]
+fn promote_and_delete_staged_files() {
+    use crate::storage::mem::MemoryStorage;
+
+    let staging_uri = "mem://promote_staged/_falconeri_staging/123/";
+    let staged_uris = vec![
+        format!("{}a.txt", staging_uri),
+        format!("{}nested/b.txt", staging_uri),
+    ];
+    MemoryStorage::put(&staged_uris[0], "a");
+    MemoryStorage::put(&staged_uris[1], "b");
+    MemoryStorage::put("mem://promote_staged/out/nested/b.txt", "old b");
+
+    let storage = <dyn CloudStorage>::for_uri(staging_uri, &[]).unwrap();
+    let promote = |staged_uri: &str| {
+        promote_staged_file(
+            &*storage,
+            staging_uri,
+            "mem://promote_staged/out",
+            staged_uri,
+        )
+    };
+    let final_uris = staged_uris
+        .iter()
+        .map(|uri| promote(uri))
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(
+        final_uris,
+        &[
+            "mem://promote_staged/out/a.txt",
+            "mem://promote_staged/out/nested/b.txt",
+        ],
+    );
+    assert_eq!(
+        MemoryStorage::get("mem://promote_staged/out/nested/b.txt").unwrap(),
+        b"b",
+    );
+
+    delete_staged_files(&*storage, &staged_uris).unwrap();
+    assert_eq!(
+        MemoryStorage::uris_with_prefix("mem://promote_staged/"),
+        final_uris,
+    );
+
+    // Promoting a file again after it has been deleted is harmless.
+    assert_eq!(promote(&staged_uris[0]).unwrap(), final_uris[0]);
+
+    // But we notice if a file is missing from both places.
+    assert!(promote(&format!("{}missing.txt", staging_uri)).is_err());
+
+    // We refuse to promote files from outside the staging prefix.
+    assert!(promote("mem://promote_staged/other.txt").is_err());
+
+    MemoryStorage::clear("mem://promote_staged/");
+}
+
+#[test]
+fn escapes_like_patterns() {
+    assert_eq!(like_escape(r"s3://a_b/100%\x/"), r"s3://a\_b/100\%\\x/");
+}
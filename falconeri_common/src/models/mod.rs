@@ -6,11 +6,13 @@ use diesel::{deserialize, pg::Pg, serialize};
 use crate::prelude::*;
 
 mod datum;
+mod datum_log_chunk;
 mod input_file;
 mod job;
 mod output_file;
 
 pub use self::datum::*;
+pub use self::datum_log_chunk::*;
 pub use self::input_file::*;
 pub use self::job::*;
 pub use self::output_file::*;
@@ -21,6 +23,11 @@ pub mod sql_types {
     #[derive(QueryId, SqlType)]
     #[diesel(postgres_type(name = "status"))]
     pub struct Status;
+
+    /// An output format enumeration type for use in Diesel's `table!` macro.
+    #[derive(QueryId, SqlType)]
+    #[diesel(postgres_type(name = "output_format"))]
+    pub struct OutputFormat;
 }
 
 /// Possible status values.
@@ -1,3 +1,4 @@
+use crate::pipeline::CollisionPolicy;
 use crate::prelude::*;
 use crate::schema::*;
 
@@ -12,8 +13,9 @@ pub struct OutputFile {
     pub created_at: NaiveDateTime,
     /// When we last updated this record.
     pub updated_at: NaiveDateTime,
-    /// The status of this record. This will be `running` while the file is
-    /// being uploaded.
+    /// The status of this record. Workers create this record with a status of
+    /// `running` _before_ uploading the file, so that we can clean up after
+    /// uploads which fail part way through.
     pub status: Status,
     /// The job which created this file.
     pub job_id: Uuid,
@@ -21,6 +23,12 @@ pub struct OutputFile {
     pub datum_id: Uuid,
     /// The URI to which we uploaded this file.
     pub uri: String,
+    /// The size of this file, in bytes. This will be `None` for files
+    /// uploaded by older workers.
+    pub size_bytes: Option<i64>,
+    /// The SHA-256 hash of this file, as lowercase hex. This will be `None` for
+    /// files uploaded by older workers.
+    pub sha256: Option<String>,
 }
 
 impl OutputFile {
@@ -33,8 +41,22 @@ impl OutputFile {
             .with_context(|| format!("could not load output file {}", id))
     }
 
-    /// Fetch all the input files corresponding to `datums`, returning grouped
-    /// in the same order.
+    /// Fetch all the output files belonging to `datum`.
+    #[tracing::instrument(skip(conn), level = "trace")]
+    pub fn for_datum(
+        datum: &Datum,
+        conn: &mut PgConnection,
+    ) -> Result<Vec<OutputFile>> {
+        OutputFile::belonging_to(datum)
+            .order_by(output_files::uri)
+            .load(conn)
+            .with_context(|| {
+                format!("could not load output files for datum {}", datum.id)
+            })
+    }
+
+    /// Delete all the output file records belonging to `datum`. This does not
+    /// delete the files themselves.
     #[tracing::instrument(skip(conn), level = "trace")]
     pub fn delete_for_datum(datum: &Datum, conn: &mut PgConnection) -> Result<()> {
         diesel::delete(OutputFile::belonging_to(datum))
@@ -43,6 +65,16 @@ impl OutputFile {
         Ok(())
     }
 
+    /// Delete the specified output file records. This does not delete the
+    /// files themselves.
+    #[tracing::instrument(skip(conn), level = "trace")]
+    pub fn delete_ids(ids: &[Uuid], conn: &mut PgConnection) -> Result<()> {
+        diesel::delete(output_files::table.filter(output_files::id.eq_any(ids)))
+            .execute(conn)
+            .context("could not delete output files")?;
+        Ok(())
+    }
+
     /// Mark the specified output files as having been successfully processed.
     #[tracing::instrument(skip(conn), level = "trace")]
     pub fn mark_ids_as_done(ids: &[Uuid], conn: &mut PgConnection) -> Result<()> {
@@ -102,10 +134,29 @@ impl OutputFile {
             .context("can't mark output file as error")?;
         Ok(())
     }
+
+    /// Generate a sample value for testing.
+    pub fn factory(datum: &Datum) -> Self {
+        let now = Utc::now().naive_utc();
+        OutputFile {
+            id: Uuid::new_v4(),
+            created_at: now,
+            updated_at: now,
+            status: Status::Done,
+            job_id: datum.job_id,
+            datum_id: datum.id,
+            uri: "gs://example-bucket/output/file.csv".to_owned(),
+            size_bytes: Some(3),
+            sha256: Some(
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+                    .to_owned(),
+            ),
+        }
+    }
 }
 
 /// Data required to create a new `OutputFile`.
-#[derive(Debug, Deserialize, Insertable, Serialize)]
+#[derive(Clone, Debug, Deserialize, Insertable, Serialize)]
 #[diesel(table_name = output_files)]
 pub struct NewOutputFile {
     /// The job which created this file.
@@ -114,19 +165,134 @@ pub struct NewOutputFile {
     pub datum_id: Uuid,
     /// The URI to which we uploaded this file.
     pub uri: String,
+    /// The size of this file, in bytes.
+    #[serde(default)]
+    pub size_bytes: Option<i64>,
+    /// The SHA-256 hash of this file, as lowercase hex.
+    #[serde(default)]
+    pub sha256: Option<String>,
 }
 
 impl NewOutputFile {
-    /// Insert new output files into the database.
+    /// Insert new output files into the database, returning them in the same
+    /// order.
+    ///
+    /// If another datum in the same job has already uploaded a file with the
+    /// same URI, we follow the job's `egress.on_collision` policy. This may
+    /// change the `uri` of the returned `OutputFile`, in which case the file
+    /// must be uploaded to the new URI.
     #[tracing::instrument(skip(conn), level = "trace")]
     pub fn insert_all(
         output_files: &[Self],
         conn: &mut PgConnection,
     ) -> Result<Vec<OutputFile>> {
-        let output_files = diesel::insert_into(output_files::table)
-            .values(output_files)
-            .get_results::<OutputFile>(conn)
-            .context("error inserting datums")?;
-        Ok(output_files)
+        conn.transaction(|conn| {
+            let mut policies = HashMap::new();
+            let mut inserted = Vec::with_capacity(output_files.len());
+            for output_file in output_files {
+                let policy = match policies.get(&output_file.job_id) {
+                    Some(&policy) => policy,
+                    None => {
+                        // Lock the job, so that two datums can't both decide
+                        // that a URI is available at the same time.
+                        let mut job = Job::find(output_file.job_id, conn)?;
+                        job.lock_for_update(conn)?;
+                        let policy = job.collision_policy()?;
+                        policies.insert(output_file.job_id, policy);
+                        policy
+                    }
+                };
+                inserted.push(output_file.insert_with_policy(policy, conn)?);
+            }
+            Ok(inserted)
+        })
+    }
+
+    /// Insert this output file, following `policy` if another datum has already
+    /// uploaded a file to the same URI.
+    fn insert_with_policy(
+        &self,
+        policy: CollisionPolicy,
+        conn: &mut PgConnection,
+    ) -> Result<OutputFile> {
+        let existing = output_files::table
+            .filter(output_files::job_id.eq(&self.job_id))
+            .filter(output_files::uri.eq(&self.uri))
+            .filter(output_files::datum_id.ne(&self.datum_id))
+            .first::<OutputFile>(conn)
+            .optional()
+            .context("error checking for output file collisions")?;
+        let mut output_file = self.clone();
+        if let Some(existing) = existing {
+            match policy {
+                CollisionPolicy::Error => {
+                    return Err(format_err!(
+                        "datum {} tried to upload {}, but datum {} already uploaded a file with that name (see egress.on_collision)",
+                        self.datum_id,
+                        self.uri,
+                        existing.datum_id,
+                    ));
+                }
+                CollisionPolicy::Overwrite => {
+                    warn!(
+                        "datum {} is overwriting {}, which was uploaded by datum {}",
+                        self.datum_id, self.uri, existing.datum_id,
+                    );
+                    diesel::delete(output_files::table.find(existing.id))
+                        .execute(conn)
+                        .context("error deleting overwritten output file")?;
+                }
+                CollisionPolicy::Suffix => {
+                    output_file.uri = suffixed_uri(&self.uri, self.datum_id);
+                    warn!(
+                        "datum {} is uploading {} as {}, because datum {} already uploaded a file with that name",
+                        self.datum_id, self.uri, output_file.uri, existing.datum_id,
+                    );
+                }
+            }
+        }
+        diesel::insert_into(output_files::table)
+            .values(&output_file)
+            .get_result::<OutputFile>(conn)
+            .with_context(|| {
+                format!("error inserting output file {}", output_file.uri)
+            })
     }
 }
+
+/// Insert `datum_id` into the file name of `uri`, just before the extension.
+fn suffixed_uri(uri: &str, datum_id: Uuid) -> String {
+    let name_start = uri.rfind('/').map(|i| i + 1).unwrap_or(0);
+    let name = &uri[name_start..];
+    // Don't treat the `.` in a hidden file like `.env` as an extension.
+    match name.rfind('.').filter(|&i| i > 0) {
+        Some(i) => format!(
+            "{}-{}{}",
+            &uri[..name_start + i],
+            datum_id,
+            &uri[name_start + i..]
+        ),
+        None => format!("{}-{}", uri, datum_id),
+    }
+}
+
+#[test]
+fn suffixes_uris() {
+    let id = Uuid::parse_str("8f1e3a44-9d4c-4d7e-9b39-2a0c1c6f0c55").unwrap();
+    assert_eq!(
+        suffixed_uri("gs://b/out/part.csv", id),
+        "gs://b/out/part-8f1e3a44-9d4c-4d7e-9b39-2a0c1c6f0c55.csv"
+    );
+    assert_eq!(
+        suffixed_uri("gs://b/out.d/part", id),
+        "gs://b/out.d/part-8f1e3a44-9d4c-4d7e-9b39-2a0c1c6f0c55"
+    );
+    assert_eq!(
+        suffixed_uri("gs://b/out/.env", id),
+        "gs://b/out/.env-8f1e3a44-9d4c-4d7e-9b39-2a0c1c6f0c55"
+    );
+    assert_eq!(
+        suffixed_uri("gs://b/out/data.tar.gz", id),
+        "gs://b/out/data.tar-8f1e3a44-9d4c-4d7e-9b39-2a0c1c6f0c55.gz"
+    );
+}
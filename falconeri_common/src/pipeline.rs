@@ -4,9 +4,19 @@
 //!
 //! [pipespec]: http://docs.pachyderm.io/en/latest/reference/pipeline_spec.html
 
-use std::time::Duration;
+use regex::Regex;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+    time::Duration,
+};
 
-use crate::{prelude::*, secret::Secret};
+use crate::{
+    compression::Compression,
+    prelude::*,
+    secret::Secret,
+    storage::{ServerSideEncryption, UploadOptions},
+};
 
 /// Represents a pipeline `*.json` file.
 ///
@@ -25,9 +35,21 @@ pub struct PipelineSpec {
     pub resource_requests: ResourceRequests,
     /// The maximum number of times to retry a single datum.
     pub datum_tries: Option<u32>,
+    /// How should we pack the matches from our `input` into datums?
+    #[serde(default)]
+    pub datum_set_spec: Option<DatumSetSpec>,
     /// Timeout a running job after this many seconds have elapsed.
     #[serde(default, with = "humantime_serde")]
     pub job_timeout: Option<Duration>,
+    /// EXTENSION: Kill the command processing a single datum if it runs for
+    /// longer than this, and mark the datum as having failed. This must be a
+    /// whole number of seconds.
+    #[serde(
+        default,
+        serialize_with = "humantime_serde::serialize",
+        deserialize_with = "deserialize_whole_seconds"
+    )]
+    pub datum_timeout: Option<Duration>,
     /// EXTENSION: Kubernetes node selectors describing the nodes where we can
     /// run this job.
     #[serde(default)]
@@ -38,6 +60,41 @@ pub struct PipelineSpec {
     pub egress: Egress,
 }
 
+impl PipelineSpec {
+    /// The names of all the Kubernetes secrets used by our `Input::Atom` and
+    /// `Egress` values, sorted and without duplicates.
+    pub fn storage_secret_names(&self) -> Vec<String> {
+        let mut names = BTreeSet::new();
+        self.input.for_each_atom_secret(&mut |secret| {
+            names.insert(secret.name().to_owned());
+        });
+        for secret in &self.egress.secrets {
+            names.insert(secret.name().to_owned());
+        }
+        names.into_iter().collect()
+    }
+}
+
+/// Deserialize an optional duration like `"90s"`, which must be a positive,
+/// whole number of seconds, because we only store the seconds.
+fn deserialize_whole_seconds<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let duration: Option<Duration> = humantime_serde::deserialize(deserializer)?;
+    match duration {
+        Some(d) if d.as_secs() == 0 || d.subsec_nanos() != 0 => {
+            Err(serde::de::Error::custom(format!(
+                "expected a positive, whole number of seconds, found {:?}",
+                d,
+            )))
+        }
+        _ => Ok(duration),
+    }
+}
+
 /// Metadata about this pipeline.
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
@@ -61,9 +118,8 @@ pub struct Transform {
     pub env: HashMap<String, String>,
     /// Kubernetes secrets to make available to our Docker containers.
     ///
-    /// TODO: We currently also use this for secrets needed to access buckets,
-    /// but that's not really a complete or well-thought-out solution, and we may
-    /// want to declare secrets as part of our `Input::Atom` values.
+    /// These are also used to access any `Input::Atom` or `Egress` which
+    /// doesn't specify its own `secrets`.
     #[serde(default)]
     pub secrets: Vec<Secret>,
     /// The Kubernetes service account to use for this job.
@@ -90,6 +146,22 @@ pub struct ResourceRequests {
     pub cpu: f32,
 }
 
+/// How should we pack the matches from our `input` into datums? By default,
+/// each match gets its own datum, but when there are many small files, it's
+/// much more efficient to process several of them at once.
+///
+/// If both `number` and `size_bytes` are specified, we start a new datum as
+/// soon as either limit would be exceeded. A single match which is larger than
+/// `size_bytes` still gets a datum of its own.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DatumSetSpec {
+    /// The maximum number of matches to put in a single datum.
+    pub number: Option<u32>,
+    /// The maximum total size of the input files in a single datum.
+    pub size_bytes: Option<u64>,
+}
+
 /// Specify our input data.
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
@@ -105,24 +177,208 @@ pub enum Input {
         repo: String,
         /// How to distribute the files in the repo over our workers.
         glob: Glob,
+        /// When used as part of an `Input::Join`, a template which builds a
+        /// join key from the capture groups in `glob`, such as `"$1"` or
+        /// `"${1}-${2}"`.
+        #[serde(default)]
+        join_on: Option<String>,
+        /// When used as part of an `Input::Group`, a template which builds a
+        /// group key from the capture groups in `glob`, like `join_on`.
+        #[serde(default)]
+        group_by: Option<String>,
+        /// EXTENSION: Kubernetes secrets used to access `uri`. If this is
+        /// empty, we use `transform.secrets` instead.
+        #[serde(default)]
+        secrets: Vec<Secret>,
+        /// EXTENSION: If true, decompress any `.gz` or `.zst` files as we
+        /// download them, and remove the extension from the local file name.
+        #[serde(default)]
+        decompress: bool,
     },
     /// Cross product of two other inputs, producing every possible combination.
     Cross(Vec<Input>),
     /// Union of two other inputs
     Union(Vec<Input>),
+    /// Inner join of two or more `Input::Atom` values, each of which must
+    /// specify `join_on`. Produces one datum for each join key which appears in
+    /// all the inputs, containing every matching file from each input.
+    Join(Vec<Input>),
+    /// Group together files from one or more `Input::Atom` values, each of
+    /// which must specify `group_by`. Produces one datum for each group key,
+    /// containing every matching file from every input.
+    Group(Vec<Input>),
+}
+
+impl Input {
+    /// Call `f` for each secret in each `Input::Atom`.
+    fn for_each_atom_secret(&self, f: &mut dyn FnMut(&Secret)) {
+        match self {
+            Input::Atom { secrets, .. } => secrets.iter().for_each(f),
+            Input::Cross(inputs)
+            | Input::Union(inputs)
+            | Input::Join(inputs)
+            | Input::Group(inputs) => {
+                for input in inputs {
+                    input.for_each_atom_secret(f);
+                }
+            }
+        }
+    }
 }
 
-/// How to distribute files from an input across workers. We only support two
-/// kinds of glob patterns for now.
-#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+/// How to distribute files from an input across workers.
+///
+/// This is written as a Pachyderm-style glob pattern, which is matched against
+/// the path of every file and directory in the repo, starting with `/`. Each
+/// match becomes a separate datum. We support:
+///
+/// - `*`: Any number of characters other than `/`.
+/// - `**`: Any number of characters, including `/`.
+/// - `?`: Any single character other than `/`.
+/// - `[abc]`, `[a-z]`, `[!abc]`: A single character in (or not in) a set.
+/// - `{a,b}`: Either of the comma-separated alternatives.
+/// - `(...)`: A capture group, which can be used by `join_on` or `group_by`.
+/// - `\*`: A literal `*`, or any other escaped character.
+///
+/// The two most common patterns, `"/"` and `"/*"`, have their own variants.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
 pub enum Glob {
     /// Put each top-level directory entry (file, subdir) its own datum.
-    #[serde(rename = "/*")]
     TopLevelDirectoryEntries,
 
     /// Put the entire repo in a single datum.
-    #[serde(rename = "/")]
     WholeRepo,
+
+    /// Put each file or directory matching the specified pattern in its own
+    /// datum.
+    Pattern(String),
+}
+
+impl Glob {
+    /// The glob pattern, as it would appear in a pipeline spec.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Glob::TopLevelDirectoryEntries => "/*",
+            Glob::WholeRepo => "/",
+            Glob::Pattern(pattern) => pattern,
+        }
+    }
+
+    /// Compile this glob to an anchored regular expression, which can be
+    /// matched against paths beginning with `/`.
+    pub fn to_regex(&self) -> Result<Regex> {
+        let pattern = self.as_str();
+        let re = glob_to_regex_str(pattern)?;
+        Regex::new(&re)
+            .with_context(|| format!("could not compile glob {:?}", pattern))
+    }
+}
+
+impl TryFrom<String> for Glob {
+    type Error = Error;
+
+    fn try_from(pattern: String) -> Result<Self> {
+        let glob = match &pattern[..] {
+            "/*" => Glob::TopLevelDirectoryEntries,
+            "/" => Glob::WholeRepo,
+            _ => Glob::Pattern(pattern),
+        };
+        // Make sure we can actually compile this pattern.
+        glob.to_regex()?;
+        Ok(glob)
+    }
+}
+
+impl From<Glob> for String {
+    fn from(glob: Glob) -> Self {
+        glob.as_str().to_owned()
+    }
+}
+
+/// Translate a glob pattern into the source code for an anchored regex.
+fn glob_to_regex_str(pattern: &str) -> Result<String> {
+    if !pattern.starts_with('/') {
+        return Err(format_err!("glob {:?} must start with '/'", pattern));
+    }
+
+    let mut re = String::with_capacity(2 * pattern.len() + 2);
+    re.push('^');
+    let mut chars = pattern.chars().peekable();
+    let mut in_braces = false;
+    let mut open_groups = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                re.push_str(".*");
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' => {
+                re.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    re.push('^');
+                }
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    match c {
+                        ']' => {
+                            closed = true;
+                            break;
+                        }
+                        // Escape anything with a special meaning inside a
+                        // `regex` character class.
+                        '\\' | '[' | '^' | '&' | '~' => {
+                            re.push('\\');
+                            re.push(c);
+                        }
+                        _ => re.push(c),
+                    }
+                }
+                if !closed {
+                    return Err(format_err!("unclosed '[' in glob {:?}", pattern));
+                }
+                re.push(']');
+            }
+            '{' if !in_braces => {
+                in_braces = true;
+                re.push_str("(?:");
+            }
+            ',' if in_braces => re.push('|'),
+            '}' if in_braces => {
+                in_braces = false;
+                re.push(')');
+            }
+            '(' => {
+                open_groups += 1;
+                re.push('(');
+            }
+            ')' if open_groups > 0 => {
+                open_groups -= 1;
+                re.push(')');
+            }
+            ')' => {
+                return Err(format_err!("unmatched ')' in glob {:?}", pattern));
+            }
+            '\\' => {
+                let escaped = chars.next().ok_or_else(|| {
+                    format_err!("trailing '\\' in glob {:?}", pattern)
+                })?;
+                re.push_str(&regex::escape(&escaped.to_string()));
+            }
+            _ => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    if in_braces {
+        return Err(format_err!("unclosed '{{' in glob {:?}", pattern));
+    }
+    if open_groups > 0 {
+        return Err(format_err!("unclosed '(' in glob {:?}", pattern));
+    }
+    re.push('$');
+    Ok(re)
 }
 
 /// Where to put the data when we're done with it.
@@ -132,6 +388,124 @@ pub struct Egress {
     /// A cloud bucket URI in which to place our output data.
     #[serde(rename = "URI")]
     pub uri: String,
+    /// EXTENSION: Kubernetes secrets used to access `uri`. If this is empty,
+    /// we use `transform.secrets` instead.
+    #[serde(default)]
+    pub secrets: Vec<Secret>,
+    /// EXTENSION: If true, workers upload to a staging prefix outside `uri`,
+    /// and we only move the files into `uri` once the job has finished
+    /// successfully. If the job fails, we delete the staged files.
+    #[serde(default)]
+    pub atomic: bool,
+    /// EXTENSION: If `atomic` is set, stage our output under this prefix,
+    /// using the same credentials as `uri`. This must not be inside `uri`.
+    /// Defaults to `_falconeri_staging/` next to `uri`.
+    #[serde(default)]
+    pub staging_uri: Option<String>,
+    /// EXTENSION: What to do when two datums try to upload output files with
+    /// the same path.
+    #[serde(default)]
+    pub on_collision: CollisionPolicy,
+    /// EXTENSION: If true, allow running a new job even if `uri` already
+    /// contains files. Our output files will replace any files with the same
+    /// names, and other files will be left alone.
+    #[serde(default)]
+    pub overwrite: bool,
+    /// EXTENSION: If true, delete any files in `uri` before running a new job.
+    #[serde(default)]
+    pub clear: bool,
+    /// EXTENSION: If present, compress each output file using this format,
+    /// and add the corresponding extension to its name. Files which already
+    /// have that extension are uploaded as-is.
+    #[serde(default)]
+    pub compress: Option<Compression>,
+    /// EXTENSION: How to encrypt our output files. This may be `"AES256"` or
+    /// `"aws:kms"`.
+    #[serde(default)]
+    pub sse: Option<ServerSideEncryption>,
+    /// EXTENSION: The KMS key used to encrypt our output files, if `sse` is
+    /// `"aws:kms"`. For Google Cloud Storage, this must be a full key name.
+    #[serde(default)]
+    pub kms_key_id: Option<String>,
+    /// EXTENSION: The storage class of our output files, such as
+    /// `"STANDARD_IA"` on S3 or `"NEARLINE"` on Google Cloud Storage.
+    #[serde(default)]
+    pub storage_class: Option<String>,
+    /// EXTENSION: Custom metadata to attach to each of our output files.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    /// EXTENSION: If a datum's output is too long to store in the database,
+    /// upload the full output under this prefix, using the same credentials
+    /// and upload options as `uri`. This must not be inside `uri`.
+    #[serde(default)]
+    pub log_uri: Option<String>,
+}
+
+/// What to do when two datums try to upload output files with the same path.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionPolicy {
+    /// Fail the second datum.
+    #[default]
+    Error,
+    /// Replace the first datum's file with the second datum's file.
+    Overwrite,
+    /// Upload the second datum's file under a new name, with the datum ID
+    /// inserted before the extension.
+    Suffix,
+}
+
+/// The directory next to `Egress::uri` in which atomic egress stages output,
+/// unless `Egress::staging_uri` is set. We use a leading `_`, like Hadoop's
+/// `_temporary` directory, so that most tools will ignore it.
+pub const EGRESS_STAGING_DIR: &str = "_falconeri_staging/";
+
+impl Egress {
+    /// The prefix under which an atomic egress stages the output of the job
+    /// `job_id`. This is never inside `uri`, so that nobody reading `uri` sees
+    /// our output before it has been promoted.
+    pub fn job_staging_uri(&self, job_id: Uuid) -> Result<String> {
+        let egress_dir = format!("{}/", self.uri.trim_end_matches('/'));
+        let staging_dir = match &self.staging_uri {
+            Some(staging_uri) => format!("{}/", staging_uri.trim_end_matches('/')),
+            None => {
+                // Use a sibling of `uri`, which must not be a bucket root.
+                let parent = egress_dir[..egress_dir.len() - 1]
+                    .rfind('/')
+                    .map(|i| &egress_dir[..=i])
+                    .filter(|parent| !parent.ends_with("://"))
+                    .ok_or_else(|| {
+                        format_err!(
+                            "cannot stage output next to egress URI {}, so please set egress.staging_uri",
+                            self.uri,
+                        )
+                    })?;
+                format!("{}{}", parent, EGRESS_STAGING_DIR)
+            }
+        };
+        if staging_dir.starts_with(&egress_dir) {
+            return Err(format_err!(
+                "egress staging URI {} must not be inside egress URI {}",
+                staging_dir,
+                egress_dir,
+            ));
+        }
+        Ok(format!("{}{}/", staging_dir, job_id))
+    }
+
+    /// The options to use when uploading our output files.
+    pub fn upload_options(&self) -> Result<UploadOptions> {
+        let options = UploadOptions {
+            sse: self.sse,
+            kms_key_id: self.kms_key_id.clone(),
+            storage_class: self.storage_class.clone(),
+            metadata: self.metadata.clone(),
+        };
+        options
+            .validate()
+            .with_context(|| format!("invalid egress options for {}", self.uri))?;
+        Ok(options)
+    }
 }
 
 #[test]
@@ -155,7 +529,13 @@ fn parse_nested_inputs() {
             "atom": {
                 "URI": "gs://example-bucket/more-books/",
                 "repo": "more-books",
-                "glob": "/*"
+                "glob": "/*",
+                "secrets": [{
+                    "name": "more-books",
+                    "key": "GOOGLE_SERVICE_ACCOUNT_KEY",
+                    "env_var": "GOOGLE_SERVICE_ACCOUNT_KEY"
+                }],
+                "decompress": true
             }
         }]
     }]
@@ -167,23 +547,111 @@ fn parse_nested_inputs() {
             uri: "gs://example-bucket/dewey-decimal-categories/".to_owned(),
             repo: "dewey-decimal-categories".to_owned(),
             glob: Glob::WholeRepo,
+            join_on: None,
+            group_by: None,
+            secrets: vec![],
+            decompress: false,
         },
         Input::Union(vec![
             Input::Atom {
                 uri: "gs://example-bucket/books/".to_owned(),
                 repo: "books".to_owned(),
                 glob: Glob::TopLevelDirectoryEntries,
+                join_on: None,
+                group_by: None,
+                secrets: vec![],
+                decompress: false,
             },
             Input::Atom {
                 uri: "gs://example-bucket/more-books/".to_owned(),
                 repo: "more-books".to_owned(),
                 glob: Glob::TopLevelDirectoryEntries,
+                join_on: None,
+                group_by: None,
+                secrets: vec![Secret::Env {
+                    name: "more-books".to_owned(),
+                    key: "GOOGLE_SERVICE_ACCOUNT_KEY".to_owned(),
+                    env_var: "GOOGLE_SERVICE_ACCOUNT_KEY".to_owned(),
+                }],
+                decompress: true,
             },
         ]),
     ]);
     assert_eq!(parsed, expected);
 }
 
+#[test]
+fn parse_join_input() {
+    let json = r#"
+{
+    "join": [{
+        "atom": {
+            "URI": "gs://example-bucket/books/",
+            "repo": "books",
+            "glob": "/(*).txt",
+            "join_on": "$1"
+        }
+    }, {
+        "atom": {
+            "URI": "gs://example-bucket/metadata/",
+            "repo": "metadata",
+            "glob": "/(*).json",
+            "join_on": "$1"
+        }
+    }]
+}
+"#;
+    let parsed: Input = serde_json::from_str(json).expect("parse error");
+    let expected = Input::Join(vec![
+        Input::Atom {
+            uri: "gs://example-bucket/books/".to_owned(),
+            repo: "books".to_owned(),
+            glob: Glob::Pattern("/(*).txt".to_owned()),
+            join_on: Some("$1".to_owned()),
+            group_by: None,
+            secrets: vec![],
+            decompress: false,
+        },
+        Input::Atom {
+            uri: "gs://example-bucket/metadata/".to_owned(),
+            repo: "metadata".to_owned(),
+            glob: Glob::Pattern("/(*).json".to_owned()),
+            join_on: Some("$1".to_owned()),
+            group_by: None,
+            secrets: vec![],
+            decompress: false,
+        },
+    ]);
+    assert_eq!(parsed, expected);
+}
+
+#[test]
+fn parse_group_input() {
+    let json = r#"
+{
+    "group": [{
+        "atom": {
+            "URI": "s3://example-bucket/hourly/",
+            "repo": "hourly",
+            "glob": "/(????-??-??)-??.csv",
+            "group_by": "$1"
+        }
+    }]
+}
+"#;
+    let parsed: Input = serde_json::from_str(json).expect("parse error");
+    let expected = Input::Group(vec![Input::Atom {
+        uri: "s3://example-bucket/hourly/".to_owned(),
+        repo: "hourly".to_owned(),
+        glob: Glob::Pattern("/(????-??-??)-??.csv".to_owned()),
+        join_on: None,
+        group_by: Some("$1".to_owned()),
+        secrets: vec![],
+        decompress: false,
+    }]);
+    assert_eq!(parsed, expected);
+}
+
 #[test]
 fn parse_pipeline_spec() {
     use serde_json;
@@ -217,7 +685,15 @@ fn parse_pipeline_spec() {
     assert_eq!(parsed.resource_requests.memory, "500Mi");
     assert!((parsed.resource_requests.cpu - 1.2).abs() < f32::EPSILON);
     assert_eq!(parsed.datum_tries, Some(3));
+    assert_eq!(
+        parsed.datum_set_spec,
+        Some(DatumSetSpec {
+            number: Some(100),
+            size_bytes: None,
+        }),
+    );
     assert_eq!(parsed.job_timeout, Some(Duration::from_secs(300)));
+    assert_eq!(parsed.datum_timeout, Some(Duration::from_secs(90)));
     assert_eq!(parsed.node_selector["node_type"], "falconeri_worker");
     assert_eq!(parsed.transform.image, "somerepo/my_python_nlp");
     assert_eq!(
@@ -226,7 +702,187 @@ fn parse_pipeline_spec() {
             uri: "gs://example-bucket/books/".to_owned(),
             repo: "books".to_owned(),
             glob: Glob::TopLevelDirectoryEntries,
+            join_on: None,
+            group_by: None,
+            secrets: vec![],
+            decompress: false,
         }
     );
     assert_eq!(parsed.egress.uri, "gs://example-bucket/words/");
+    assert_eq!(
+        parsed.egress.secrets,
+        vec![Secret::Env {
+            name: "gcs-writer".to_owned(),
+            key: "GOOGLE_SERVICE_ACCOUNT_KEY".to_owned(),
+            env_var: "GOOGLE_SERVICE_ACCOUNT_KEY".to_owned(),
+        }],
+    );
+    assert_eq!(parsed.storage_secret_names(), vec!["gcs-writer".to_owned()]);
+    assert!(!parsed.egress.atomic);
+    assert_eq!(parsed.egress.on_collision, CollisionPolicy::Error);
+    assert!(!parsed.egress.overwrite);
+    assert!(!parsed.egress.clear);
+    assert_eq!(parsed.egress.compress, None);
+    assert!(parsed.egress.upload_options().unwrap().is_empty());
+}
+
+#[test]
+fn datum_timeout_must_be_whole_seconds() {
+    let json = include_str!("example_pipeline_spec.json");
+    let parse = |timeout: &str| -> Result<Option<Duration>> {
+        let mut spec: serde_json::Value = serde_json::from_str(json)?;
+        spec["datum_timeout"] = serde_json::Value::String(timeout.to_owned());
+        Ok(serde_json::from_value::<PipelineSpec>(spec)?.datum_timeout)
+    };
+    assert_eq!(parse("2m").unwrap(), Some(Duration::from_secs(120)));
+    assert!(parse("0s").is_err());
+    assert!(parse("500ms").is_err());
+    assert!(parse("1s 500ms").is_err());
+}
+
+#[test]
+fn parse_egress_upload_options() {
+    let egress: Egress = serde_json::from_str(
+        r#"{
+            "URI": "s3://example-bucket/words/",
+            "sse": "aws:kms",
+            "kms_key_id": "alias/falconeri",
+            "storage_class": "STANDARD_IA",
+            "metadata": { "pipeline": "words" }
+        }"#,
+    )
+    .unwrap();
+    let options = egress.upload_options().unwrap();
+    assert_eq!(options.sse, Some(ServerSideEncryption::Kms));
+    assert_eq!(options.kms_key_id.as_deref(), Some("alias/falconeri"));
+    assert_eq!(options.storage_class.as_deref(), Some("STANDARD_IA"));
+    assert_eq!(options.metadata["pipeline"], "words");
+
+    let egress: Egress = serde_json::from_str(
+        r#"{ "URI": "s3://b/", "sse": "AES256", "kms_key_id": "alias/k" }"#,
+    )
+    .unwrap();
+    assert!(egress.upload_options().is_err());
+    assert!(
+        serde_json::from_str::<Egress>(r#"{ "URI": "s3://b/", "sse": "des" }"#)
+            .is_err()
+    );
+}
+
+#[test]
+fn parse_collision_policy() {
+    let parse = |policy: &str| -> Result<CollisionPolicy> {
+        let json = format!(r#"{{ "URI": "gs://b/", "on_collision": "{}" }}"#, policy);
+        Ok(serde_json::from_str::<Egress>(&json)?.on_collision)
+    };
+    assert_eq!(parse("error").unwrap(), CollisionPolicy::Error);
+    assert_eq!(parse("overwrite").unwrap(), CollisionPolicy::Overwrite);
+    assert_eq!(parse("suffix").unwrap(), CollisionPolicy::Suffix);
+    assert!(parse("ignore").is_err());
+}
+
+#[test]
+fn atomic_egress_staging_uri() {
+    let egress: Egress = serde_json::from_str(
+        r#"{ "URI": "s3://example-bucket/words", "atomic": true }"#,
+    )
+    .unwrap();
+    assert!(egress.atomic);
+    assert_eq!(egress.on_collision, CollisionPolicy::Error);
+    assert_eq!(
+        serde_json::from_str::<Egress>(r#"{ "URI": "gs://b/", "compress": "zstd" }"#)
+            .unwrap()
+            .compress,
+        Some(Compression::Zstd),
+    );
+    let job_id = Uuid::parse_str("8f1e3a44-9d4c-4d7e-9b39-2a0c1c6f0c55").unwrap();
+    assert_eq!(
+        egress.job_staging_uri(job_id).unwrap(),
+        "s3://example-bucket/_falconeri_staging/8f1e3a44-9d4c-4d7e-9b39-2a0c1c6f0c55/",
+    );
+
+    // We can't stage output next to a bucket root, or inside `uri`.
+    let staging_uri = |json: &str| -> Result<String> {
+        serde_json::from_str::<Egress>(json)?.job_staging_uri(job_id)
+    };
+    assert!(staging_uri(r#"{ "URI": "s3://example-bucket/" }"#).is_err());
+    assert_eq!(
+        staging_uri(
+            r#"{ "URI": "s3://example-bucket/", "staging_uri": "s3://tmp/x" }"#
+        )
+        .unwrap(),
+        "s3://tmp/x/8f1e3a44-9d4c-4d7e-9b39-2a0c1c6f0c55/",
+    );
+    assert!(staging_uri(
+        r#"{ "URI": "s3://example-bucket/", "staging_uri": "s3://example-bucket/x" }"#
+    )
+    .is_err());
+}
+
+#[test]
+fn parse_glob_patterns() {
+    let parse = |pattern: &str| -> Result<Glob> {
+        Ok(serde_json::from_value(serde_json::Value::String(
+            pattern.to_owned(),
+        ))?)
+    };
+    assert_eq!(parse("/").unwrap(), Glob::WholeRepo);
+    assert_eq!(parse("/*").unwrap(), Glob::TopLevelDirectoryEntries);
+    assert_eq!(parse("/*/*").unwrap(), Glob::Pattern("/*/*".to_owned()));
+    assert!(parse("*.csv").is_err());
+    assert!(parse("/[abc").is_err());
+    assert!(parse("/{a,b").is_err());
+    assert!(parse("/(*.csv").is_err());
+    assert!(parse("/*).csv").is_err());
+    assert_eq!(
+        serde_json::to_value(Glob::Pattern("/**.parquet".to_owned())).unwrap(),
+        serde_json::Value::String("/**.parquet".to_owned()),
+    );
+}
+
+#[test]
+fn glob_matching() {
+    #[allow(clippy::type_complexity)]
+    let examples: &[(&str, &[&str], &[&str])] = &[
+        ("/", &["/"], &["/a"]),
+        ("/*", &["/a", "/a.csv"], &["/a/b", "/a/"]),
+        ("/*/*", &["/a/b", "/2024-01/x.csv"], &["/a", "/a/b/c"]),
+        (
+            "/2024-*/*.csv",
+            &["/2024-01/x.csv"],
+            &["/2023-01/x.csv", "/2024-01/x.tsv", "/2024-01/a/x.csv"],
+        ),
+        (
+            "/**.parquet",
+            &["/a.parquet", "/a/b/c.parquet"],
+            &["/a/b.csv"],
+        ),
+        (
+            "/data?.csv",
+            &["/data1.csv"],
+            &["/data10.csv", "/data/.csv"],
+        ),
+        ("/[ab]*", &["/apple", "/b"], &["/cherry"]),
+        ("/[!ab]*", &["/cherry"], &["/apple"]),
+        ("/*.{csv,tsv}", &["/x.csv", "/x.tsv"], &["/x.json"]),
+        ("/a\\*b", &["/a*b"], &["/axb"]),
+        ("/a.b+c", &["/a.b+c"], &["/aXb+c", "/a.bbc"]),
+        ("/(*).csv", &["/x.csv"], &["/x.tsv"]),
+        ("/\\(*\\)", &["/(x)"], &["/x"]),
+    ];
+    for &(pattern, matches, non_matches) in examples {
+        let glob = Glob::try_from(pattern.to_owned()).unwrap();
+        let re = glob.to_regex().unwrap();
+        for path in matches {
+            assert!(re.is_match(path), "{:?} should match {:?}", pattern, path);
+        }
+        for path in non_matches {
+            assert!(
+                !re.is_match(path),
+                "{:?} should not match {:?}",
+                pattern,
+                path
+            );
+        }
+    }
 }
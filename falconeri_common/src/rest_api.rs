@@ -2,7 +2,6 @@
 
 use reqwest::blocking as reqwest;
 use serde::de::DeserializeOwned;
-use std::usize;
 use url::Url;
 
 use crate::db;
@@ -28,6 +27,25 @@ pub struct DatumReservationResponse {
     pub input_files: Vec<InputFile>,
 }
 
+/// Renew the lease on a running datum.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DatumHeartbeatRequest {
+    /// The Kubernetes pod name which is processing this datum.
+    pub pod_name: String,
+}
+
+/// Append a chunk of output to a running datum's log.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DatumLogChunkRequest {
+    /// The Kubernetes pod name which is processing this datum.
+    pub pod_name: String,
+    /// The position of this chunk in the output of the current attempt,
+    /// starting at 0.
+    pub seq: i32,
+    /// The output to append.
+    pub data: String,
+}
+
 /// Information about a datum that we can update.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct DatumPatch {
@@ -36,6 +54,13 @@ pub struct DatumPatch {
     pub status: Status,
     /// The output of procesisng the datum.
     pub output: String,
+    /// Where we uploaded the full output, if `output` only contains the
+    /// beginning and the end.
+    #[serde(default)]
+    pub output_log_uri: Option<String>,
+    /// How `output` is formatted.
+    #[serde(default)]
+    pub output_format: OutputFormat,
     /// If and only if `status` is `Status::Error`, this should be the error
     /// message.
     pub error_message: Option<String>,
@@ -208,12 +233,76 @@ impl Client {
         Ok(resv_resp.map(|r| (r.datum, r.input_files)))
     }
 
+    /// Tell `falconerid` that we're still working on `datum`, renewing our
+    /// lease. Returns false if we've lost our lease, in which case the datum
+    /// may have been given to another worker. This can only be called from
+    /// inside a pod.
+    ///
+    /// We don't retry failed heartbeats, because the caller should send
+    /// another one soon anyway.
+    ///
+    /// `POST /datums/<datum_id>/heartbeat`
+    #[tracing::instrument(level = "trace")]
+    pub fn send_datum_heartbeat(&self, datum: &Datum) -> Result<bool> {
+        let url = self.url.join(&format!("datums/{}/heartbeat", datum.id))?;
+        let pod_name = pod_name()?;
+        let resp = self
+            .client
+            .post(url.clone())
+            .basic_auth(&self.username, Some(&self.password))
+            .json(&DatumHeartbeatRequest {
+                pod_name: pod_name.clone(),
+            })
+            .send()
+            .with_context(|| format!("error posting {}", url))?;
+        let updated: Datum = self.handle_json_response(&url, resp)?;
+        Ok(updated.is_running_on(&pod_name))
+    }
+
+    /// Append a chunk of output to the log of `datum`, which we're currently
+    /// processing. This can only be called from inside a pod.
+    ///
+    /// Like heartbeats, we don't retry failed appends. Instead, the caller
+    /// should resend the same chunk later, with the same `seq`. `falconerid`
+    /// will ignore any duplicates.
+    ///
+    /// `POST /datums/<datum_id>/log_chunks`
+    #[tracing::instrument(skip(data), level = "trace")]
+    pub fn append_datum_log_chunk(
+        &self,
+        datum: &Datum,
+        seq: i32,
+        data: &str,
+    ) -> Result<()> {
+        let url = self.url.join(&format!("datums/{}/log_chunks", datum.id))?;
+        let resp = self
+            .client
+            .post(url.clone())
+            .basic_auth(&self.username, Some(&self.password))
+            .json(&DatumLogChunkRequest {
+                pod_name: pod_name()?,
+                seq,
+                data: data.to_owned(),
+            })
+            .send()
+            .with_context(|| format!("error posting {}", url))?;
+        self.handle_empty_response(&url, resp)
+    }
+
     /// Mark `datum` as done, and record the output of the commands we ran.
     #[tracing::instrument(level = "trace")]
-    pub fn mark_datum_as_done(&self, datum: &mut Datum, output: String) -> Result<()> {
+    pub fn mark_datum_as_done(
+        &self,
+        datum: &mut Datum,
+        output: String,
+        output_log_uri: Option<String>,
+        output_format: OutputFormat,
+    ) -> Result<()> {
         let patch = DatumPatch {
             status: Status::Done,
             output,
+            output_log_uri,
+            output_format,
             error_message: None,
             backtrace: None,
         };
@@ -227,12 +316,16 @@ impl Client {
         &self,
         datum: &mut Datum,
         output: String,
+        output_log_uri: Option<String>,
+        output_format: OutputFormat,
         error_message: String,
         backtrace: String,
     ) -> Result<()> {
         let patch = DatumPatch {
             status: Status::Error,
             output,
+            output_log_uri,
+            output_format,
             error_message: Some(error_message),
             backtrace: Some(backtrace),
         };
@@ -259,15 +352,19 @@ impl Client {
         Ok(())
     }
 
-    /// Create new output files.
+    /// Create new output files for datums that we're currently processing.
+    /// This can only be called from inside a pod.
     ///
-    /// `POST /output_files`
+    /// `POST /output_files?pod_name=<pod_name>`
     #[tracing::instrument(level = "trace")]
     pub fn create_output_files(
         &self,
         files: &[NewOutputFile],
     ) -> Result<Vec<OutputFile>> {
-        let url = self.url.join("output_files")?;
+        let mut url = self.url.join("output_files")?;
+        url.query_pairs_mut()
+            .append_pair("pod_name", &pod_name()?)
+            .finish();
         // TODO: We might want finer-grained retry here? This isn't remotely
         // idempotent. Though I suppose if we encounter a "double create", all
         // the retries should just fail until we give up, then we'll eventually
@@ -1,6 +1,19 @@
 table! {
     use diesel::sql_types::*;
-    use crate::models::sql_types::Status;
+
+    datum_log_chunks (id) {
+        id -> Uuid,
+        created_at -> Timestamp,
+        datum_id -> Uuid,
+        attempt -> Int4,
+        seq -> Int4,
+        data -> Text,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::sql_types::{OutputFormat, Status};
 
     datums (id) {
         id -> Uuid,
@@ -15,6 +28,10 @@ table! {
         output -> Nullable<Text>,
         attempted_run_count -> Int4,
         maximum_allowed_run_count -> Int4,
+        group_key -> Nullable<Text>,
+        lease_expires_at -> Nullable<Timestamp>,
+        output_log_uri -> Nullable<Text>,
+        output_format -> OutputFormat,
     }
 }
 
@@ -28,6 +45,8 @@ table! {
         uri -> Text,
         local_path -> Text,
         job_id -> Uuid,
+        secrets -> Jsonb,
+        decompress -> Bool,
     }
 }
 
@@ -44,6 +63,7 @@ table! {
         job_name -> Text,
         command -> Array<Text>,
         egress_uri -> Text,
+        promotion_failure_count -> Int4,
     }
 }
 
@@ -59,12 +79,21 @@ table! {
         job_id -> Uuid,
         datum_id -> Uuid,
         uri -> Text,
+        size_bytes -> Nullable<Int8>,
+        sha256 -> Nullable<Text>,
     }
 }
 
+joinable!(datum_log_chunks -> datums (datum_id));
 joinable!(datums -> jobs (job_id));
 joinable!(input_files -> datums (datum_id));
 joinable!(output_files -> datums (datum_id));
 joinable!(output_files -> jobs (job_id));
 
-allow_tables_to_appear_in_same_query!(datums, input_files, jobs, output_files,);
+allow_tables_to_appear_in_same_query!(
+    datum_log_chunks,
+    datums,
+    input_files,
+    jobs,
+    output_files,
+);
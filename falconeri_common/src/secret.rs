@@ -29,3 +29,12 @@ pub enum Secret {
         env_var: String,
     },
 }
+
+impl Secret {
+    /// The name of the Kubernetes secret to use.
+    pub fn name(&self) -> &str {
+        match self {
+            Secret::Mount { name, .. } | Secret::Env { name, .. } => name,
+        }
+    }
+}
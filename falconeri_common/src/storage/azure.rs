@@ -0,0 +1,866 @@
+//! Support for Azure Blob Storage.
+
+use attohttpc::{Method, RequestBuilder, Response, StatusCode};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use regex::Regex;
+use sha2::Sha256;
+use std::{collections::BTreeMap, env, fs, io::Read};
+use url::Url;
+
+use super::{
+    local_files_recursive, parse_timestamp, CloudStorage, ObjectMetadata,
+    ObjectWriter, SpooledObjectWriter,
+};
+use crate::kubernetes::{base64_encoded_optional_secret_string, storage_secret};
+use crate::prelude::*;
+use crate::secret::Secret;
+
+/// The version of the Azure Storage REST API that we use.
+const API_VERSION: &str = "2021-08-06";
+
+/// Files at least this large will be uploaded in blocks of this size, and
+/// smaller files will be uploaded using a single request.
+const BLOCK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// The names of the secret keys (and environment variables) that we use for
+/// authentication.
+const AZURE_ENV_VARS: &[&str] = &[
+    "AZURE_STORAGE_CONNECTION_STRING",
+    "AZURE_STORAGE_ACCOUNT",
+    "AZURE_STORAGE_KEY",
+];
+
+/// The well-known account name used by the Azurite storage emulator.
+const DEV_STORE_ACCOUNT: &str = "devstoreaccount1";
+
+/// The well-known account key used by the Azurite storage emulator.
+const DEV_STORE_KEY: &str = "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
+
+/// An Azure secret fetched from Kubernetes. This can be fetched using
+/// `kubernetes_secret`. Either `AZURE_STORAGE_CONNECTION_STRING` or both
+/// `AZURE_STORAGE_ACCOUNT` and `AZURE_STORAGE_KEY` must be present.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE", deny_unknown_fields)]
+struct AzureSecretData {
+    /// Our `AZURE_STORAGE_CONNECTION_STRING` value.
+    #[serde(default, with = "base64_encoded_optional_secret_string")]
+    azure_storage_connection_string: Option<String>,
+    /// Our `AZURE_STORAGE_ACCOUNT` value.
+    #[serde(default, with = "base64_encoded_optional_secret_string")]
+    azure_storage_account: Option<String>,
+    /// Our `AZURE_STORAGE_KEY` value.
+    #[serde(default, with = "base64_encoded_optional_secret_string")]
+    azure_storage_key: Option<String>,
+}
+
+impl AzureSecretData {
+    /// Read our secret data from environment variables with the same names as
+    /// our secret keys.
+    fn from_env() -> AzureSecretData {
+        AzureSecretData {
+            azure_storage_connection_string: env::var(AZURE_ENV_VARS[0]).ok(),
+            azure_storage_account: env::var(AZURE_ENV_VARS[1]).ok(),
+            azure_storage_key: env::var(AZURE_ENV_VARS[2]).ok(),
+        }
+    }
+}
+
+/// The information we need to talk to an Azure storage account.
+struct AzureAccount {
+    /// The name of our storage account.
+    name: String,
+    /// Our decoded account key.
+    key: Vec<u8>,
+    /// The URL of our blob service, such as
+    /// `https://example.blob.core.windows.net/`. For the Azurite emulator, this
+    /// will include the account name as a path component.
+    blob_endpoint: Url,
+}
+
+impl AzureAccount {
+    /// Get our account information from `secret_data`.
+    fn new(secret_data: &AzureSecretData) -> Result<AzureAccount> {
+        if let Some(conn_str) = &secret_data.azure_storage_connection_string {
+            AzureAccount::from_connection_string(conn_str)
+        } else if let (Some(name), Some(key)) = (
+            &secret_data.azure_storage_account,
+            &secret_data.azure_storage_key,
+        ) {
+            let endpoint = format!("https://{}.blob.core.windows.net/", name);
+            AzureAccount::from_parts(name, key, &endpoint)
+        } else {
+            Err(format_err!(
+                "Azure storage requires either AZURE_STORAGE_CONNECTION_STRING or \
+                 both AZURE_STORAGE_ACCOUNT and AZURE_STORAGE_KEY"
+            ))
+        }
+    }
+
+    /// Parse an Azure connection string, such as
+    /// `"DefaultEndpointsProtocol=https;AccountName=...;AccountKey=..."`.
+    fn from_connection_string(conn_str: &str) -> Result<AzureAccount> {
+        let mut fields = HashMap::new();
+        for field in conn_str.split(';').filter(|f| !f.trim().is_empty()) {
+            let (name, value) = field.split_once('=').ok_or_else(|| {
+                format_err!("malformed field in Azure connection string")
+            })?;
+            fields.insert(name.trim().to_owned(), value.trim().to_owned());
+        }
+        let field = |name: &str| fields.get(name).map(|v| &v[..]);
+
+        if field("UseDevelopmentStorage") == Some("true") {
+            let endpoint = format!("http://127.0.0.1:10000/{}/", DEV_STORE_ACCOUNT);
+            return AzureAccount::from_parts(
+                DEV_STORE_ACCOUNT,
+                DEV_STORE_KEY,
+                &endpoint,
+            );
+        }
+        let name = field("AccountName").ok_or_else(|| {
+            format_err!("Azure connection string does not contain AccountName")
+        })?;
+        let key = field("AccountKey").ok_or_else(|| {
+            format_err!("Azure connection string does not contain AccountKey")
+        })?;
+        let endpoint = match field("BlobEndpoint") {
+            Some(endpoint) => endpoint.to_owned(),
+            None => format!(
+                "{}://{}.blob.{}/",
+                field("DefaultEndpointsProtocol").unwrap_or("https"),
+                name,
+                field("EndpointSuffix").unwrap_or("core.windows.net"),
+            ),
+        };
+        AzureAccount::from_parts(name, key, &endpoint)
+    }
+
+    /// Construct an `AzureAccount` from its parts.
+    fn from_parts(name: &str, key: &str, blob_endpoint: &str) -> Result<AzureAccount> {
+        let key = BASE64_STANDARD
+            .decode(key)
+            .context("could not base64-decode Azure account key")?;
+        let mut blob_endpoint = blob_endpoint.to_owned();
+        if !blob_endpoint.ends_with('/') {
+            blob_endpoint.push('/');
+        }
+        let blob_endpoint = Url::parse(&blob_endpoint).with_context(|| {
+            format!("could not parse Azure blob endpoint {:?}", blob_endpoint)
+        })?;
+        Ok(AzureAccount {
+            name: name.to_owned(),
+            key,
+            blob_endpoint,
+        })
+    }
+}
+
+/// Backend for talking to Azure Blob Storage, using URIs of the form
+/// `az://container/path`. The storage account is specified by our secret.
+pub struct AzureStorage {
+    account: AzureAccount,
+}
+
+impl AzureStorage {
+    /// Create a new `AzureStorage` backend. If `secrets` contains a secret
+    /// which sets one of our environment variables, we read our credentials
+    /// from that secret. Otherwise, we read them from the environment.
+    #[allow(clippy::new_ret_no_self)]
+    #[tracing::instrument(level = "trace")]
+    pub fn new(secrets: &[Secret]) -> Result<Self> {
+        let secret = secrets.iter().find(|s| {
+            matches!(s, Secret::Env { env_var, .. } if AZURE_ENV_VARS.contains(&&env_var[..]))
+        });
+        let secret_data = if let Some(Secret::Env { name, .. }) = secret {
+            storage_secret(name)?
+        } else {
+            AzureSecretData::from_env()
+        };
+        Ok(AzureStorage {
+            account: AzureAccount::new(&secret_data)?,
+        })
+    }
+
+    /// Build the URL for `blob` in `container`, or for the container itself.
+    fn url(
+        &self,
+        container: &str,
+        blob: Option<&str>,
+        query: &[(&str, &str)],
+    ) -> Result<Url> {
+        let mut url = self.account.blob_endpoint.clone();
+        {
+            let mut segments = url.path_segments_mut().map_err(|_| {
+                format_err!(
+                    "invalid Azure blob endpoint {}",
+                    self.account.blob_endpoint
+                )
+            })?;
+            segments.pop_if_empty().push(container);
+            if let Some(blob) = blob {
+                segments.extend(blob.split('/'));
+            }
+        }
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+        Ok(url)
+    }
+
+    /// Sign and send a request. Does not check the response status.
+    fn send_unchecked(
+        &self,
+        method: Method,
+        url: Url,
+        ms_headers: &[(&'static str, &str)],
+        body: Option<Vec<u8>>,
+    ) -> Result<Response> {
+        trace!("{} {}", method, url);
+        let date = Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        let mut all_ms_headers =
+            vec![("x-ms-date", &date[..]), ("x-ms-version", API_VERSION)];
+        all_ms_headers.extend_from_slice(ms_headers);
+
+        let content_length = body.as_ref().map(|b| b.len()).unwrap_or(0);
+        let content_type = if body.is_some() {
+            "application/octet-stream"
+        } else {
+            ""
+        };
+        let to_sign = string_to_sign(
+            method.as_str(),
+            content_length,
+            content_type,
+            &all_ms_headers,
+            &self.account.name,
+            &url,
+        );
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.account.key)
+            .expect("HMAC should accept any key length");
+        mac.update(to_sign.as_bytes());
+        let signature = BASE64_STANDARD.encode(mac.finalize().into_bytes());
+
+        let mut builder = RequestBuilder::try_new(method, url.clone())?.header(
+            "Authorization",
+            format!("SharedKey {}:{}", self.account.name, signature),
+        );
+        for (name, value) in all_ms_headers {
+            builder = builder.header(name, value);
+        }
+        if let Some(body) = body {
+            builder
+                .header("Content-Type", content_type)
+                .bytes(body)
+                .send()
+        } else {
+            builder.send()
+        }
+        .with_context(|| format!("error talking to Azure at {}", url))
+    }
+
+    /// Sign and send a request. Returns an error if the request fails.
+    fn send(
+        &self,
+        method: Method,
+        url: Url,
+        ms_headers: &[(&'static str, &str)],
+        body: Option<Vec<u8>>,
+    ) -> Result<Response> {
+        let response = self.send_unchecked(method, url.clone(), ms_headers, body)?;
+        if response.is_success() {
+            Ok(response)
+        } else {
+            let status = response.status();
+            let body = response.text().unwrap_or_default();
+            Err(format_err!(
+                "Azure returned {} for {}: {}",
+                status,
+                url,
+                body
+            ))
+        }
+    }
+
+    /// Download `blob` in `container` to `local_path`.
+    fn download_file(
+        &self,
+        container: &str,
+        blob: &str,
+        local_path: &Path,
+    ) -> Result<()> {
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent)
+                .context("cannot create local download directory")?;
+        }
+        let file = fs::File::create(local_path)
+            .with_context(|| format!("cannot create {}", local_path.display()))?;
+        let url = self.url(container, Some(blob), &[])?;
+        self.send(Method::GET, url, &[], None)?
+            .write_to(file)
+            .with_context(|| {
+                format!("could not download az://{}/{}", container, blob)
+            })?;
+        Ok(())
+    }
+
+    /// Upload `local_path` to `blob` in `container`.
+    fn upload_file(
+        &self,
+        local_path: &Path,
+        container: &str,
+        blob: &str,
+    ) -> Result<()> {
+        let mut file = fs::File::open(local_path)
+            .with_context(|| format!("cannot open {}", local_path.display()))?;
+        let size = file
+            .metadata()
+            .with_context(|| format!("cannot stat {}", local_path.display()))?
+            .len();
+
+        if size < BLOCK_SIZE {
+            // Small files can be sent using a single request.
+            let mut content = Vec::with_capacity(cast::usize(size));
+            file.read_to_end(&mut content)
+                .with_context(|| format!("cannot read {}", local_path.display()))?;
+            let url = self.url(container, Some(blob), &[])?;
+            self.send(
+                Method::PUT,
+                url,
+                &[("x-ms-blob-type", "BlockBlob")],
+                Some(content),
+            )?;
+            return Ok(());
+        }
+
+        // Upload large files in blocks, and then commit the list of blocks.
+        let mut block_ids = vec![];
+        loop {
+            let mut chunk = Vec::with_capacity(cast::usize(BLOCK_SIZE));
+            (&mut file)
+                .take(BLOCK_SIZE)
+                .read_to_end(&mut chunk)
+                .with_context(|| format!("cannot read {}", local_path.display()))?;
+            if chunk.is_empty() {
+                break;
+            }
+            let block_id = BASE64_STANDARD.encode(format!("{:08}", block_ids.len()));
+            let url = self.url(
+                container,
+                Some(blob),
+                &[("comp", "block"), ("blockid", &block_id)],
+            )?;
+            self.send(Method::PUT, url, &[], Some(chunk))?;
+            block_ids.push(block_id);
+        }
+        let mut block_list = String::from(r#"<?xml version="1.0" encoding="utf-8"?>"#);
+        block_list.push_str("<BlockList>");
+        for block_id in &block_ids {
+            block_list.push_str(&format!("<Latest>{}</Latest>", block_id));
+        }
+        block_list.push_str("</BlockList>");
+        let url = self.url(container, Some(blob), &[("comp", "blocklist")])?;
+        self.send(Method::PUT, url, &[], Some(block_list.into_bytes()))?;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for AzureStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't include secrets in the debug output, for trace mode.
+        f.debug_struct("AzureStorage")
+            .field("account", &self.account.name)
+            .finish()
+    }
+}
+
+impl CloudStorage for AzureStorage {
+    #[tracing::instrument(level = "trace")]
+    fn list(&self, uri: &str) -> Result<Vec<ObjectMetadata>> {
+        trace!("listing {}", uri);
+        let (container, path) = parse_az_uri(uri)?;
+        let mut prefix = path.to_owned();
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+
+        // Fetch as many pages of results as we need.
+        let mut objects = vec![];
+        let mut marker: Option<String> = None;
+        loop {
+            let mut query = vec![
+                ("restype", "container"),
+                ("comp", "list"),
+                ("prefix", &prefix[..]),
+            ];
+            if let Some(marker) = &marker {
+                query.push(("marker", &marker[..]));
+            }
+            let url = self.url(container, None, &query)?;
+            let body = self
+                .send(Method::GET, url, &[], None)?
+                .text()
+                .with_context(|| format!("could not list {:?}", uri))?;
+            let results = parse_list_blobs_response(&body)?;
+            objects.extend(
+                results
+                    .blobs
+                    .blobs
+                    .into_iter()
+                    // Remove the directory itself.
+                    .filter(|blob| blob.name != prefix)
+                    .map(|blob| ObjectMetadata {
+                        uri: format!("az://{}/{}", container, blob.name),
+                        size: blob.properties.content_length,
+                        etag: blob.properties.etag,
+                        updated_at: blob
+                            .properties
+                            .last_modified
+                            .as_deref()
+                            .and_then(parse_timestamp),
+                    }),
+            );
+            match results.next_marker {
+                Some(next_marker) if !next_marker.is_empty() => {
+                    marker = Some(next_marker);
+                }
+                _ => break,
+            }
+        }
+        Ok(objects)
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn sync_down(&self, uri: &str, local_path: &Path) -> Result<()> {
+        trace!("downloading {} to {}", uri, local_path.display());
+        let (container, path) = parse_az_uri(uri)?;
+        if uri.ends_with('/') {
+            fs::create_dir_all(local_path)
+                .context("cannot create local download directory")?;
+            for obj in self.list(uri)? {
+                let rel_path = &obj.uri[uri.len()..];
+                if rel_path.ends_with('/') {
+                    // Skip placeholder blobs for directories.
+                    continue;
+                }
+                let blob = format!("{}{}", path, rel_path);
+                self.download_file(container, &blob, &local_path.join(rel_path))?;
+            }
+            Ok(())
+        } else {
+            self.download_file(container, path, local_path)
+        }
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn sync_up(&self, local_path: &Path, uri: &str) -> Result<()> {
+        trace!("uploading {} to {}", local_path.display(), uri);
+        let (container, path) = parse_az_uri(uri)?;
+        let mut prefix = path.to_owned();
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        for rel_path in local_files_recursive(local_path)? {
+            let blob = format!("{}{}", prefix, rel_path);
+            self.upload_file(&local_path.join(&rel_path), container, &blob)?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn stat(&self, uri: &str) -> Result<Option<ObjectMetadata>> {
+        let (container, path) = parse_az_uri(uri)?;
+        let url = self.url(container, Some(path), &[])?;
+        let response = self.send_unchecked(Method::HEAD, url, &[], None)?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        } else if !response.is_success() {
+            return Err(format_err!(
+                "Azure returned {} for {}",
+                response.status(),
+                uri
+            ));
+        }
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        Ok(Some(ObjectMetadata {
+            uri: uri.to_owned(),
+            size: header("Content-Length")
+                .and_then(|len| len.parse::<u64>().ok())
+                .unwrap_or(0),
+            etag: header("ETag").map(|etag| etag.to_owned()),
+            updated_at: header("Last-Modified").and_then(parse_timestamp),
+        }))
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn delete(&self, uri: &str) -> Result<()> {
+        let (container, path) = parse_az_uri(uri)?;
+        let url = self.url(container, Some(path), &[])?;
+        let response = self.send_unchecked(Method::DELETE, url, &[], None)?;
+        if response.is_success() || response.status() == StatusCode::NOT_FOUND {
+            Ok(())
+        } else {
+            Err(format_err!(
+                "Azure returned {} when deleting {}",
+                response.status(),
+                uri
+            ))
+        }
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn open_read(&self, uri: &str) -> Result<Box<dyn Read + '_>> {
+        let (container, path) = parse_az_uri(uri)?;
+        let url = self.url(container, Some(path), &[])?;
+        let (_status, _headers, reader) =
+            self.send(Method::GET, url, &[], None)?.split();
+        Ok(Box::new(reader))
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn open_write(&self, uri: &str) -> Result<Box<dyn ObjectWriter + '_>> {
+        let (container, path) = parse_az_uri(uri)?;
+        let (container, path) = (container.to_owned(), path.to_owned());
+        Ok(Box::new(SpooledObjectWriter::new(move |local_path| {
+            self.upload_file(local_path, &container, &path)
+        })?))
+    }
+}
+
+/// Build the string which we sign using our account key, as described in
+/// [Authorize with Shared Key][shared-key].
+///
+/// [shared-key]:
+/// https://learn.microsoft.com/en-us/rest/api/storageservices/authorize-with-shared-key
+fn string_to_sign(
+    method: &str,
+    content_length: usize,
+    content_type: &str,
+    ms_headers: &[(&str, &str)],
+    account_name: &str,
+    url: &Url,
+) -> String {
+    // Standard headers, most of which we never send.
+    let content_length = if content_length == 0 {
+        String::new()
+    } else {
+        content_length.to_string()
+    };
+    let mut to_sign = format!(
+        "{}\n\n\n{}\n\n{}\n\n\n\n\n\n\n",
+        method, content_length, content_type,
+    );
+
+    // Canonicalized `x-ms-` headers.
+    let ms_headers = ms_headers
+        .iter()
+        .map(|(name, value)| (name.to_ascii_lowercase(), value.trim()))
+        .collect::<BTreeMap<_, _>>();
+    for (name, value) in ms_headers {
+        to_sign.push_str(&format!("{}:{}\n", name, value));
+    }
+
+    // Canonicalized resource, including any query parameters.
+    to_sign.push_str(&format!("/{}{}", account_name, url.path()));
+    let mut params = BTreeMap::<String, Vec<String>>::new();
+    for (name, value) in url.query_pairs() {
+        params
+            .entry(name.to_ascii_lowercase())
+            .or_default()
+            .push(value.into_owned());
+    }
+    for (name, mut values) in params {
+        values.sort();
+        to_sign.push_str(&format!("\n{}:{}", name, values.join(",")));
+    }
+    to_sign
+}
+
+/// Parse an Azure URI of the form `az://container/path`.
+#[tracing::instrument(level = "trace")]
+fn parse_az_uri(uri: &str) -> Result<(&str, &str)> {
+    // lazy_static allows us to compile this regex only once.
+    lazy_static! {
+        static ref RE: Regex =
+            Regex::new("^az://(?P<container>[^/]+)(?:/(?P<path>.*))?$")
+                .expect("couldn't parse built-in regex");
+    }
+
+    let caps = RE
+        .captures(uri)
+        .ok_or_else(|| format_err!("the URI {:?} could not be parsed", uri))?;
+    let container = caps
+        .name("container")
+        .expect("missing hard-coded capture???")
+        .as_str();
+    let path = caps.name("path").map(|m| m.as_str()).unwrap_or("");
+
+    Ok((container, path))
+}
+
+/// Parse the XML returned by the [List Blobs][list] API.
+///
+/// [list]: https://learn.microsoft.com/en-us/rest/api/storageservices/list-blobs
+fn parse_list_blobs_response(xml: &str) -> Result<EnumerationResults> {
+    quick_xml::de::from_str(xml).context("could not parse Azure blob listing")
+}
+
+/// The response to a List Blobs request.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct EnumerationResults {
+    blobs: Blobs,
+    next_marker: Option<String>,
+}
+
+/// A list of blobs.
+#[derive(Debug, Deserialize)]
+struct Blobs {
+    #[serde(default, rename = "Blob")]
+    blobs: Vec<Blob>,
+}
+
+/// Information about a blob.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Blob {
+    name: String,
+    properties: BlobProperties,
+}
+
+/// Properties of a blob.
+#[derive(Debug, Deserialize)]
+struct BlobProperties {
+    #[serde(rename = "Content-Length")]
+    content_length: u64,
+    #[serde(default, rename = "Etag")]
+    etag: Option<String>,
+    #[serde(default, rename = "Last-Modified")]
+    last_modified: Option<String>,
+}
+
+#[test]
+fn uri_parsing() {
+    assert_eq!(parse_az_uri("az://container").unwrap(), ("container", ""));
+    assert_eq!(
+        parse_az_uri("az://container/path/").unwrap(),
+        ("container", "path/")
+    );
+    assert!(parse_az_uri("s3://bucket/").is_err());
+}
+
+#[test]
+fn connection_string_parsing() {
+    let account = AzureAccount::from_connection_string(
+        "DefaultEndpointsProtocol=https;AccountName=example;AccountKey=a2V5;EndpointSuffix=core.windows.net",
+    )
+    .unwrap();
+    assert_eq!(account.name, "example");
+    assert_eq!(account.key, b"key");
+    assert_eq!(
+        account.blob_endpoint.as_str(),
+        "https://example.blob.core.windows.net/"
+    );
+
+    let account = AzureAccount::from_connection_string(
+        "AccountName=devstoreaccount1;AccountKey=a2V5;BlobEndpoint=http://azurite:10000/devstoreaccount1;",
+    )
+    .unwrap();
+    assert_eq!(
+        account.blob_endpoint.as_str(),
+        "http://azurite:10000/devstoreaccount1/"
+    );
+
+    let account =
+        AzureAccount::from_connection_string("UseDevelopmentStorage=true").unwrap();
+    assert_eq!(account.name, DEV_STORE_ACCOUNT);
+    assert!(AzureAccount::from_connection_string("AccountName=x").is_err());
+    assert!(AzureAccount::new(&AzureSecretData::default()).is_err());
+}
+
+#[test]
+fn shared_key_string_to_sign() {
+    let storage = AzureStorage {
+        account: AzureAccount::from_connection_string("UseDevelopmentStorage=true")
+            .unwrap(),
+    };
+    let url = storage
+        .url(
+            "container",
+            None,
+            &[
+                ("restype", "container"),
+                ("comp", "list"),
+                ("prefix", "a b/"),
+            ],
+        )
+        .unwrap();
+    assert_eq!(
+        url.as_str(),
+        "http://127.0.0.1:10000/devstoreaccount1/container?restype=container&comp=list&prefix=a+b%2F",
+    );
+    let to_sign = string_to_sign(
+        "GET",
+        0,
+        "",
+        &[
+            ("x-ms-version", "2021-08-06"),
+            ("x-ms-date", "Fri, 26 Jun 2015 23:39:12 GMT"),
+        ],
+        DEV_STORE_ACCOUNT,
+        &url,
+    );
+    assert_eq!(
+        to_sign,
+        "GET\n\n\n\n\n\n\n\n\n\n\n\n\
+         x-ms-date:Fri, 26 Jun 2015 23:39:12 GMT\n\
+         x-ms-version:2021-08-06\n\
+         /devstoreaccount1/devstoreaccount1/container\n\
+         comp:list\n\
+         prefix:a b/\n\
+         restype:container",
+    );
+
+    let url = storage
+        .url("container", Some("dir/a file.csv"), &[])
+        .unwrap();
+    assert_eq!(
+        url.as_str(),
+        "http://127.0.0.1:10000/devstoreaccount1/container/dir/a%20file.csv"
+    );
+    let to_sign = string_to_sign(
+        "PUT",
+        5,
+        "application/octet-stream",
+        &[("x-ms-blob-type", "BlockBlob")],
+        DEV_STORE_ACCOUNT,
+        &url,
+    );
+    assert_eq!(
+        to_sign,
+        "PUT\n\n\n5\n\napplication/octet-stream\n\n\n\n\n\n\n\
+         x-ms-blob-type:BlockBlob\n\
+         /devstoreaccount1/devstoreaccount1/container/dir/a%20file.csv",
+    );
+}
+
+#[test]
+fn list_blobs_parsing() {
+    let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<EnumerationResults ServiceEndpoint="http://127.0.0.1:10000/devstoreaccount1" ContainerName="container">
+  <Prefix>books/</Prefix>
+  <Blobs>
+    <Blob>
+      <Name>books/a.txt</Name>
+      <Properties>
+        <Creation-Time>Fri, 26 Jun 2015 23:39:12 GMT</Creation-Time>
+        <Last-Modified>Sat, 27 Jun 2015 23:39:12 GMT</Last-Modified>
+        <Etag>0x8CBFF45D8A29A19</Etag>
+        <Content-Length>1234</Content-Length>
+        <Content-Type>text/plain</Content-Type>
+      </Properties>
+    </Blob>
+    <Blob>
+      <Name>books/b.txt</Name>
+      <Properties>
+        <Content-Length>0</Content-Length>
+      </Properties>
+    </Blob>
+  </Blobs>
+  <NextMarker>abc</NextMarker>
+</EnumerationResults>"#;
+    let results = parse_list_blobs_response(xml).unwrap();
+    assert_eq!(results.blobs.blobs.len(), 2);
+    assert_eq!(results.blobs.blobs[0].name, "books/a.txt");
+    assert_eq!(results.blobs.blobs[0].properties.content_length, 1234);
+    assert_eq!(
+        results.blobs.blobs[0].properties.etag.as_deref(),
+        Some("0x8CBFF45D8A29A19"),
+    );
+    assert_eq!(
+        results.blobs.blobs[0].properties.last_modified.as_deref(),
+        Some("Sat, 27 Jun 2015 23:39:12 GMT"),
+    );
+    assert_eq!(results.blobs.blobs[1].properties.etag, None);
+    assert_eq!(results.next_marker.as_deref(), Some("abc"));
+
+    let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<EnumerationResults><Blobs /><NextMarker /></EnumerationResults>"#;
+    let results = parse_list_blobs_response(xml).unwrap();
+    assert!(results.blobs.blobs.is_empty());
+    assert!(results.next_marker.unwrap_or_default().is_empty());
+}
+
+/// Upload, list and download files using a real Azure storage account. To run
+/// this against the Azurite emulator, use something like:
+///
+/// ```sh
+/// AZURE_STORAGE_CONNECTION_STRING=UseDevelopmentStorage=true \
+///     FALCONERI_TEST_AZURE_URI=az://test-container/ \
+///     cargo test -p falconeri_common -- --ignored azure_round_trip
+/// ```
+#[test]
+#[ignore]
+fn azure_round_trip() {
+    let base_uri = env::var("FALCONERI_TEST_AZURE_URI")
+        .expect("FALCONERI_TEST_AZURE_URI should be set");
+    let uri = format!("{}{}/", base_uri, Uuid::new_v4());
+    let storage = AzureStorage::new(&[]).unwrap();
+
+    // Build a local directory with a large file (to test block uploads).
+    let local_dir = env::temp_dir().join(format!("falconeri-{}", Uuid::new_v4()));
+    let big = (0..BLOCK_SIZE + 12345)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    fs::create_dir_all(local_dir.join("small")).unwrap();
+    fs::write(local_dir.join("big.bin"), &big).unwrap();
+    fs::write(local_dir.join("small/a file.txt"), "hello").unwrap();
+    fs::write(local_dir.join("small/empty.txt"), "").unwrap();
+    storage.sync_up(&local_dir, &uri).unwrap();
+
+    let objects = storage.list(&uri).unwrap();
+    assert_eq!(objects.len(), 3);
+    let big_uri = format!("{}big.bin", uri);
+    let big_obj = objects.iter().find(|obj| obj.uri == big_uri).unwrap();
+    assert_eq!(big_obj.size, big.len() as u64);
+    let stat = storage.stat(&big_uri).unwrap().unwrap();
+    assert_eq!(stat.size, big_obj.size);
+    assert_eq!(stat.etag, big_obj.etag);
+
+    let download_dir = env::temp_dir().join(format!("falconeri-{}/", Uuid::new_v4()));
+    storage.sync_down(&uri, &download_dir).unwrap();
+    assert_eq!(fs::read(download_dir.join("big.bin")).unwrap(), big);
+    assert_eq!(
+        fs::read_to_string(download_dir.join("small/a file.txt")).unwrap(),
+        "hello",
+    );
+    assert_eq!(
+        fs::read_to_string(download_dir.join("small/empty.txt")).unwrap(),
+        "",
+    );
+
+    // Stream objects in and out, and delete them.
+    let new_uri = format!("{}new.bin", uri);
+    let mut writer = storage.open_write(&new_uri).unwrap();
+    writer.write_all(&big).unwrap();
+    writer.finish().unwrap();
+    let mut contents = vec![];
+    storage
+        .open_read(&new_uri)
+        .unwrap()
+        .read_to_end(&mut contents)
+        .unwrap();
+    assert_eq!(contents, big);
+    storage.delete(&new_uri).unwrap();
+    storage.delete(&new_uri).unwrap();
+    assert!(!storage.exists(&new_uri).unwrap());
+    assert!(storage.open_read(&new_uri).is_err());
+
+    fs::remove_dir_all(local_dir).unwrap();
+    fs::remove_dir_all(download_dir).unwrap();
+}
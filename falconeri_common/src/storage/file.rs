@@ -0,0 +1,276 @@
+//! Support for local filesystems, such as NFS shares or Kubernetes persistent
+//! volumes which are mounted on every worker.
+
+use std::{
+    fs,
+    io::{self, Read},
+};
+use tempfile::NamedTempFile;
+
+use super::{local_files_recursive, CloudStorage, ObjectMetadata, ObjectWriter};
+use crate::prelude::*;
+use crate::secret::Secret;
+
+/// Backend for reading and writing files on a local filesystem, using URIs
+/// like `file:///mnt/data/`.
+#[derive(Debug)]
+pub struct FileStorage {}
+
+impl FileStorage {
+    /// Create a new `FileStorage` backend.
+    #[allow(clippy::new_ret_no_self)]
+    #[tracing::instrument(level = "trace")]
+    pub fn new(_secrets: &[Secret]) -> Result<Self> {
+        // Local files are protected by the usual filesystem permissions.
+        Ok(FileStorage {})
+    }
+}
+
+impl CloudStorage for FileStorage {
+    #[tracing::instrument(level = "trace")]
+    fn list(&self, uri: &str) -> Result<Vec<ObjectMetadata>> {
+        trace!("listing {}", uri);
+        let mut base = uri.to_owned();
+        if !base.ends_with('/') {
+            base.push('/');
+        }
+        let dir = file_uri_to_path(&base)?;
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut objects = vec![];
+        for rel_path in local_files_recursive(&dir)? {
+            let path = dir.join(&rel_path);
+            let metadata = fs::metadata(&path)
+                .with_context(|| format!("could not stat {}", path.display()))?;
+            objects.push(object_metadata(format!("{}{}", base, rel_path), &metadata));
+        }
+        Ok(objects)
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn sync_down(&self, uri: &str, local_path: &Path) -> Result<()> {
+        trace!("copying {} to {}", uri, local_path.display());
+        let path = file_uri_to_path(uri)?;
+        if uri.ends_with('/') {
+            copy_dir(&path, local_path)
+        } else {
+            copy_file(&path, local_path)
+        }
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn sync_up(&self, local_path: &Path, uri: &str) -> Result<()> {
+        trace!("copying {} to {}", local_path.display(), uri);
+        copy_dir(local_path, &file_uri_to_path(uri)?)
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn stat(&self, uri: &str) -> Result<Option<ObjectMetadata>> {
+        let path = file_uri_to_path(uri)?;
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => {
+                Err(format_err!("expected a file, found directory {}", uri))
+            }
+            Ok(metadata) => Ok(Some(object_metadata(uri.to_owned(), &metadata))),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => {
+                Err(err).with_context(|| format!("could not stat {}", path.display()))
+            }
+        }
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn delete(&self, uri: &str) -> Result<()> {
+        let path = file_uri_to_path(uri)?;
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err)
+                .with_context(|| format!("could not delete {}", path.display())),
+        }
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn open_read(&self, uri: &str) -> Result<Box<dyn Read + '_>> {
+        let path = file_uri_to_path(uri)?;
+        let file = fs::File::open(&path)
+            .with_context(|| format!("cannot open {}", path.display()))?;
+        Ok(Box::new(file))
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn open_write(&self, uri: &str) -> Result<Box<dyn ObjectWriter + '_>> {
+        let path = file_uri_to_path(uri)?;
+        let parent = path
+            .parent()
+            .ok_or_else(|| format_err!("cannot write to {}", uri))?;
+        fs::create_dir_all(parent)
+            .with_context(|| format!("cannot create {}", parent.display()))?;
+        // Write to a temporary file in the same directory, so that we can
+        // rename it into place when we're done.
+        let file = NamedTempFile::new_in(parent).with_context(|| {
+            format!("cannot create temporary file in {}", parent.display())
+        })?;
+        Ok(Box::new(FileObjectWriter { file, path }))
+    }
+}
+
+/// An `ObjectWriter` which writes to a temporary file, and then renames it into
+/// place, so readers never see a partial file.
+struct FileObjectWriter {
+    /// Our temporary file.
+    file: NamedTempFile,
+    /// The final path of our file.
+    path: PathBuf,
+}
+
+impl Write for FileObjectWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl ObjectWriter for FileObjectWriter {
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.file
+            .flush()
+            .with_context(|| format!("cannot write {}", self.path.display()))?;
+        let FileObjectWriter { file, path } = *self;
+        file.persist(&path)
+            .with_context(|| format!("cannot write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Build the `ObjectMetadata` for `uri` using the local file's `metadata`.
+fn object_metadata(uri: String, metadata: &fs::Metadata) -> ObjectMetadata {
+    ObjectMetadata {
+        uri,
+        size: metadata.len(),
+        etag: None,
+        updated_at: metadata
+            .modified()
+            .ok()
+            .map(|modified| chrono::DateTime::<Utc>::from(modified).naive_utc()),
+    }
+}
+
+/// Convert a `file://` URI to a local path. We only support absolute paths on
+/// the local machine, and we don't decode `%`-escapes.
+fn file_uri_to_path(uri: &str) -> Result<PathBuf> {
+    let path = uri
+        .strip_prefix("file://")
+        .ok_or_else(|| format_err!("expected a file:// URI, found {:?}", uri))?;
+    if !path.starts_with('/') {
+        return Err(format_err!(
+            "expected {:?} to have an absolute path, as in file:///path/",
+            uri,
+        ));
+    }
+    Ok(PathBuf::from(path))
+}
+
+/// Copy `src` to `dest`, creating any parent directories.
+fn copy_file(src: &Path, dest: &Path) -> Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("cannot create {}", parent.display()))?;
+    }
+    fs::copy(src, dest).with_context(|| {
+        format!("cannot copy {} to {}", src.display(), dest.display())
+    })?;
+    Ok(())
+}
+
+/// Recursively copy the contents of `src` into `dest`.
+fn copy_dir(src: &Path, dest: &Path) -> Result<()> {
+    fs::create_dir_all(dest)
+        .with_context(|| format!("cannot create {}", dest.display()))?;
+    for rel_path in local_files_recursive(src)? {
+        copy_file(&src.join(&rel_path), &dest.join(&rel_path))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn file_uri_parsing() {
+    assert_eq!(
+        file_uri_to_path("file:///mnt/data/").unwrap(),
+        Path::new("/mnt/data/"),
+    );
+    assert!(file_uri_to_path("file://host/data/").is_err());
+    assert!(file_uri_to_path("gs://bucket/").is_err());
+}
+
+#[test]
+fn file_round_trip() {
+    let root = std::env::temp_dir().join(format!("falconeri-{}", Uuid::new_v4()));
+    let local = root.join("local");
+    fs::create_dir_all(local.join("nested")).unwrap();
+    fs::write(local.join("a.txt"), "hello").unwrap();
+    fs::write(local.join("nested/b.txt"), "hi").unwrap();
+
+    let storage = FileStorage::new(&[]).unwrap();
+    let uri = format!("file://{}/bucket/", root.display());
+    assert!(storage.list(&uri).unwrap().is_empty());
+    storage.sync_up(&local, &uri).unwrap();
+    let objects = storage.list(&uri).unwrap();
+    assert_eq!(
+        objects
+            .iter()
+            .map(|obj| (&obj.uri[..], obj.size))
+            .collect::<Vec<_>>(),
+        vec![
+            (&format!("{}a.txt", uri)[..], 5),
+            (&format!("{}nested/b.txt", uri)[..], 2),
+        ],
+    );
+    assert!(objects.iter().all(|obj| obj.updated_at.is_some()));
+
+    let download = root.join("download/");
+    storage
+        .sync_down(&format!("{}nested/", uri), &download)
+        .unwrap();
+    assert_eq!(fs::read_to_string(download.join("b.txt")).unwrap(), "hi");
+    storage
+        .sync_down(&format!("{}a.txt", uri), &download.join("copy.txt"))
+        .unwrap();
+    assert_eq!(
+        fs::read_to_string(download.join("copy.txt")).unwrap(),
+        "hello",
+    );
+
+    let new_uri = format!("{}new/c.txt", uri);
+    let mut writer = storage.open_write(&new_uri).unwrap();
+    writer.write_all(b"new").unwrap();
+    assert!(!storage.exists(&new_uri).unwrap());
+    writer.finish().unwrap();
+    assert_eq!(storage.stat(&new_uri).unwrap().unwrap().size, 3);
+    let mut contents = String::new();
+    storage
+        .open_read(&new_uri)
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents, "new");
+    assert_eq!(
+        storage
+            .list(&format!("{}new/", uri))
+            .unwrap()
+            .into_iter()
+            .map(|obj| obj.uri)
+            .collect::<Vec<_>>(),
+        vec![new_uri.clone()],
+    );
+    storage.delete(&new_uri).unwrap();
+    storage.delete(&new_uri).unwrap();
+    assert_eq!(storage.stat(&new_uri).unwrap(), None);
+    assert!(storage.stat(&format!("{}nested", uri)).is_err());
+
+    fs::remove_dir_all(root).unwrap();
+}
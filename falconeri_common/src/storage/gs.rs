@@ -1,47 +1,197 @@
 //! Support for Google Cloud Storage.
 
-use std::{collections::HashSet, fs, io::BufRead, process};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::{self, BufRead, Read},
+    process,
+};
+use tempfile::NamedTempFile;
 
-use super::CloudStorage;
+use super::{
+    local_files_recursive, parse_timestamp, CloudStorage, ObjectMetadata,
+    ObjectWriter, UploadOptions,
+};
+use crate::kubernetes::{base64_encoded_secret_string, storage_secret};
 use crate::prelude::*;
 use crate::secret::Secret;
 
+/// A Google Cloud secret fetched from Kubernetes. This can be fetched using
+/// `kubernetes_secret`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE", deny_unknown_fields)]
+struct GoogleSecretData {
+    /// Our `GOOGLE_SERVICE_ACCOUNT_KEY` value, containing the JSON key for a
+    /// service account.
+    #[serde(with = "base64_encoded_secret_string")]
+    google_service_account_key: String,
+}
+
+/// The parts of a service account key that we check before using it.
+#[derive(Debug, Deserialize)]
+struct ServiceAccountKey {
+    /// The type of the key, which should be `"service_account"`.
+    #[serde(rename = "type")]
+    key_type: String,
+    /// The email address of the service account.
+    client_email: String,
+}
+
 /// Backend for talking to Google Cloud Storage, currently based on `gsutil`.
 #[derive(Debug)]
-pub struct GoogleCloudStorage {}
+pub struct GoogleCloudStorage {
+    /// A temporary file containing our service account key, if we have one.
+    /// This will be deleted when we're dropped.
+    key_file: Option<NamedTempFile>,
+    /// Options to use when creating objects.
+    upload_options: UploadOptions,
+}
 
 impl GoogleCloudStorage {
-    /// Create a new `GoogleCloudStorage` backend.
+    /// Create a new `GoogleCloudStorage` backend. If `secrets` contains a
+    /// secret which sets `GOOGLE_SERVICE_ACCOUNT_KEY`, we authenticate using
+    /// the service account key in that secret. Otherwise, we use the
+    /// `GOOGLE_SERVICE_ACCOUNT_KEY` environment variable, if present, and
+    /// `gsutil`'s ambient credentials if not.
     #[allow(clippy::new_ret_no_self)]
     #[tracing::instrument(level = "trace")]
-    pub fn new(_secrets: &[Secret]) -> Result<Self> {
-        // We don't yet know how to authenticate using secrets.
-        Ok(GoogleCloudStorage {})
+    pub fn new(secrets: &[Secret]) -> Result<Self> {
+        let secret = secrets.iter().find(|s| {
+            matches!(s, Secret::Env { env_var, .. } if env_var == "GOOGLE_SERVICE_ACCOUNT_KEY")
+        });
+        let key = if let Some(Secret::Env { name, .. }) = secret {
+            let secret_data: GoogleSecretData = storage_secret(name)?;
+            Some(secret_data.google_service_account_key)
+        } else {
+            env::var("GOOGLE_SERVICE_ACCOUNT_KEY").ok()
+        };
+        GoogleCloudStorage::from_key(key.as_deref())
+    }
+
+    /// Create a new `GoogleCloudStorage` backend using the JSON service account
+    /// `key`, if present.
+    fn from_key(key: Option<&str>) -> Result<Self> {
+        let key_file = key.map(write_key_file).transpose()?;
+        Ok(GoogleCloudStorage {
+            key_file,
+            upload_options: UploadOptions::default(),
+        })
+    }
+
+    /// Build a `gsutil` command which uses our credentials.
+    fn gsutil(&self) -> process::Command {
+        let mut cmd = process::Command::new("gsutil");
+        if let Some(key_file) = &self.key_file {
+            // This is respected by the `gsutil` wrapper in the Cloud SDK, and
+            // it takes priority over any credentials from `gcloud auth`.
+            cmd.env("CLOUDSDK_AUTH_CREDENTIAL_FILE_OVERRIDE", key_file.path());
+        }
+        cmd
+    }
+
+    /// Build a `gsutil` command which uses our credentials and applies our
+    /// upload options. The caller should pass `-s` to any `cp` subcommand
+    /// using `storage_class_args`.
+    fn gsutil_upload(&self) -> process::Command {
+        let mut cmd = self.gsutil();
+        cmd.args(gsutil_upload_args(&self.upload_options));
+        cmd
+    }
+
+    /// Extra arguments for `gsutil cp` which set our storage class, if any.
+    fn storage_class_args(&self) -> Vec<&str> {
+        match &self.upload_options.storage_class {
+            Some(storage_class) => vec!["-s", storage_class],
+            None => vec![],
+        }
+    }
+}
+
+/// Global `gsutil` arguments which apply `options` to any objects we create.
+///
+/// Google Cloud Storage always encrypts objects, so `sse` has no effect by
+/// itself. But if we have a `kms_key_id`, we use it as a customer-managed
+/// encryption key.
+fn gsutil_upload_args(options: &UploadOptions) -> Vec<String> {
+    let mut args = vec![];
+    if let Some(kms_key_id) = &options.kms_key_id {
+        args.push("-o".to_owned());
+        args.push(format!("GSUtil:encryption_key={}", kms_key_id));
     }
+    for (key, value) in &options.metadata {
+        args.push("-h".to_owned());
+        args.push(format!("x-goog-meta-{}:{}", key, value));
+    }
+    args
+}
+
+/// Does `stderr` from `gsutil` say that the object doesn't exist?
+fn is_not_found(stderr: &[u8]) -> bool {
+    String::from_utf8_lossy(stderr).contains("No URLs matched")
+}
+
+/// Check that `key` looks like a service account key, and write it to a
+/// temporary file which only we can read.
+fn write_key_file(key: &str) -> Result<NamedTempFile> {
+    let parsed = serde_json::from_str::<ServiceAccountKey>(key)
+        .context("could not parse Google service account key")?;
+    if parsed.key_type != "service_account" {
+        return Err(format_err!(
+            "expected Google service account key for {}, found {:?} key",
+            parsed.client_email,
+            parsed.key_type,
+        ));
+    }
+    trace!("using service account {}", parsed.client_email);
+    let mut key_file = NamedTempFile::new()
+        .context("could not create temporary file for service account key")?;
+    key_file
+        .write_all(key.as_bytes())
+        .and_then(|()| key_file.flush())
+        .context("could not write service account key")?;
+    Ok(key_file)
 }
 
 impl CloudStorage for GoogleCloudStorage {
+    fn set_upload_options(&mut self, options: UploadOptions) -> Result<()> {
+        self.upload_options = options;
+        Ok(())
+    }
+
     #[tracing::instrument(level = "trace")]
-    fn list(&self, uri: &str) -> Result<Vec<String>> {
+    fn list(&self, uri: &str) -> Result<Vec<ObjectMetadata>> {
         trace!("listing {}", uri);
-        // Shell out to gsutil to list the files we want to process.
-        let output = process::Command::new("gsutil")
-            .arg("ls")
-            .arg(uri)
-            .stderr(process::Stdio::inherit())
+
+        // Use a `**` wildcard to list all files recursively, like `S3Storage`.
+        let mut pattern = uri.to_owned();
+        if !pattern.ends_with('/') {
+            pattern.push('/');
+        }
+        pattern.push_str("**");
+
+        // Shell out to gsutil to list the files we want to process, including
+        // their sizes.
+        let output = self
+            .gsutil()
+            .args(["ls", "-l"])
+            .arg(&pattern)
             .output()
             .context("error running gsutil")?;
         if !output.status.success() {
-            return Err(format_err!("could not list {:?}: {}", uri, output.status));
-        }
-        // `gsutil ls` is "eventually consistent", and seems to occasionally retun
-        // duplicate entries.
-        let mut paths = HashSet::new();
-        for line in output.stdout.lines() {
-            let line = line?;
-            paths.insert(line.trim_end().to_owned());
+            // `gsutil` fails if the wildcard doesn't match anything.
+            if String::from_utf8_lossy(&output.stderr).contains("matched no objects") {
+                return Ok(vec![]);
+            }
+            return Err(format_err!(
+                "could not list {:?}: {}: {}",
+                uri,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim(),
+            ));
         }
-        Ok(paths.into_iter().collect())
+        parse_gsutil_ls_long(&output.stdout)
     }
 
     #[tracing::instrument(level = "trace")]
@@ -56,7 +206,8 @@ impl CloudStorage for GoogleCloudStorage {
             trace!("syncing {} to {}", uri, local_path.display());
             fs::create_dir_all(local_path)
                 .context("cannot create local download directory")?;
-            let status = process::Command::new("gsutil")
+            let status = self
+                .gsutil()
                 .args(["-m", "rsync"])
                 .arg(uri)
                 .arg(local_path)
@@ -72,7 +223,8 @@ impl CloudStorage for GoogleCloudStorage {
                 fs::create_dir_all(parent)
                     .context("cannot create local download directory")?;
             }
-            let status = process::Command::new("gsutil")
+            let status = self
+                .gsutil()
                 .args(["-m", "cp", "-r"])
                 .arg(uri)
                 .arg(local_path)
@@ -88,12 +240,31 @@ impl CloudStorage for GoogleCloudStorage {
     #[tracing::instrument(level = "trace")]
     fn sync_up(&self, local_path: &Path, uri: &str) -> Result<()> {
         trace!("uploading {} to {}", local_path.display(), uri);
-        let status = process::Command::new("gsutil")
-            .args(["-m", "rsync", "-r"])
-            .arg(local_path)
-            .arg(uri)
-            .status()
-            .context("could not run gsutil")?;
+        let mut cmd = self.gsutil_upload();
+        if self.upload_options.storage_class.is_some() {
+            // `gsutil rsync` can't set the storage class, so copy each of the
+            // top-level entries in `local_path` into `uri` instead.
+            if local_files_recursive(local_path)?.is_empty() {
+                return Ok(());
+            }
+            let mut dir_uri = uri.to_owned();
+            if !dir_uri.ends_with('/') {
+                dir_uri.push('/');
+            }
+            cmd.args(["-m", "cp", "-r"]).args(self.storage_class_args());
+            let entries = fs::read_dir(local_path)
+                .with_context(|| format!("could not list {}", local_path.display()))?;
+            for entry in entries {
+                let entry = entry.with_context(|| {
+                    format!("could not list {}", local_path.display())
+                })?;
+                cmd.arg(entry.path());
+            }
+            cmd.arg(dir_uri);
+        } else {
+            cmd.args(["-m", "rsync", "-r"]).arg(local_path).arg(uri);
+        }
+        let status = cmd.status().context("could not run gsutil")?;
         if !status.success() {
             return Err(format_err!(
                 "could not upload {}: {}",
@@ -103,4 +274,337 @@ impl CloudStorage for GoogleCloudStorage {
         }
         Ok(())
     }
+
+    #[tracing::instrument(level = "trace")]
+    fn stat(&self, uri: &str) -> Result<Option<ObjectMetadata>> {
+        let output = self
+            .gsutil()
+            .arg("stat")
+            .arg(uri)
+            .output()
+            .context("error running gsutil")?;
+        if output.status.success() {
+            parse_gsutil_stat(uri, &output.stdout).map(Some)
+        } else if is_not_found(&output.stderr) {
+            Ok(None)
+        } else {
+            Err(format_err!(
+                "could not stat {:?}: {}: {}",
+                uri,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim(),
+            ))
+        }
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn delete(&self, uri: &str) -> Result<()> {
+        trace!("deleting {}", uri);
+        let output = self
+            .gsutil()
+            .arg("rm")
+            .arg(uri)
+            .output()
+            .context("error running gsutil")?;
+        if output.status.success() || is_not_found(&output.stderr) {
+            Ok(())
+        } else {
+            Err(format_err!(
+                "could not delete {:?}: {}: {}",
+                uri,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim(),
+            ))
+        }
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn copy(&self, from: &str, to: &str) -> Result<()> {
+        // `gsutil` can copy objects without downloading them.
+        trace!("copying {} to {}", from, to);
+        let status = self
+            .gsutil_upload()
+            .arg("cp")
+            .args(self.storage_class_args())
+            .arg(from)
+            .arg(to)
+            .status()
+            .context("could not run gsutil cp")?;
+        if !status.success() {
+            return Err(format_err!("could not copy {:?}: {}", from, status));
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn open_read(&self, uri: &str) -> Result<Box<dyn Read + '_>> {
+        let mut child = self
+            .gsutil()
+            .arg("cat")
+            .arg(uri)
+            .stdout(process::Stdio::piped())
+            .spawn()
+            .context("could not run gsutil cat")?;
+        let stdout = child.stdout.take().expect("stdout should be piped");
+        Ok(Box::new(GsutilReader {
+            uri: uri.to_owned(),
+            child: Some(child),
+            stdout,
+        }))
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn open_write(&self, uri: &str) -> Result<Box<dyn ObjectWriter + '_>> {
+        let mut child = self
+            .gsutil_upload()
+            .arg("cp")
+            .args(self.storage_class_args())
+            .arg("-")
+            .arg(uri)
+            .stdin(process::Stdio::piped())
+            .spawn()
+            .context("could not run gsutil cp")?;
+        let stdin = child.stdin.take().expect("stdin should be piped");
+        Ok(Box::new(GsutilWriter {
+            uri: uri.to_owned(),
+            child,
+            stdin,
+        }))
+    }
+}
+
+/// Streams an object from `gsutil cat`.
+struct GsutilReader {
+    /// The URI we're reading.
+    uri: String,
+    /// Our `gsutil` process, until we've reached the end of the output.
+    child: Option<process::Child>,
+    /// The standard output of `child`.
+    stdout: process::ChildStdout,
+}
+
+impl Read for GsutilReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.stdout.read(buf)?;
+        if count == 0 && !buf.is_empty() {
+            // Make sure `gsutil` didn't fail part way through.
+            if let Some(mut child) = self.child.take() {
+                let status = child.wait()?;
+                if !status.success() {
+                    return Err(io::Error::other(format!(
+                        "could not read {:?}: {}",
+                        self.uri, status
+                    )));
+                }
+            }
+        }
+        Ok(count)
+    }
+}
+
+impl Drop for GsutilReader {
+    fn drop(&mut self) {
+        // If we stopped reading early, don't leave `gsutil` running.
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Streams an object to `gsutil cp -`.
+struct GsutilWriter {
+    /// The URI we're writing.
+    uri: String,
+    /// Our `gsutil` process.
+    child: process::Child,
+    /// The standard input of `child`.
+    stdin: process::ChildStdin,
+}
+
+impl Write for GsutilWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin.flush()
+    }
+}
+
+impl ObjectWriter for GsutilWriter {
+    fn finish(self: Box<Self>) -> Result<()> {
+        let GsutilWriter {
+            uri,
+            mut child,
+            mut stdin,
+        } = *self;
+        stdin
+            .flush()
+            .with_context(|| format!("could not write {:?}", uri))?;
+        // Close stdin so that `gsutil` knows we're done.
+        drop(stdin);
+        let status = child.wait().context("error running gsutil")?;
+        if !status.success() {
+            return Err(format_err!("could not write {:?}: {}", uri, status));
+        }
+        Ok(())
+    }
+}
+
+/// Parse the output of `gsutil ls -l`.
+fn parse_gsutil_ls_long(stdout: &[u8]) -> Result<Vec<ObjectMetadata>> {
+    // lazy_static allows us to compile this regex only once.
+    lazy_static! {
+        static ref RE: Regex =
+            Regex::new(r"^\s*(?P<size>[0-9]+)\s+(?P<updated>\S+)\s+(?P<uri>gs://.*)$")
+                .expect("couldn't parse built-in regex");
+    }
+
+    // `gsutil ls` is "eventually consistent", and seems to occasionally retun
+    // duplicate entries.
+    let mut objects = BTreeMap::new();
+    for line in stdout.lines() {
+        let line = line?;
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with("TOTAL:") {
+            continue;
+        }
+        let caps = RE
+            .captures(line)
+            .ok_or_else(|| format_err!("cannot parse gsutil output {:?}", line))?;
+        let size = caps["size"]
+            .parse::<u64>()
+            .with_context(|| format!("cannot parse gsutil output {:?}", line))?;
+        let mut obj = ObjectMetadata::new(&caps["uri"], size);
+        obj.updated_at = parse_timestamp(&caps["updated"]);
+        objects.insert(obj.uri.clone(), obj);
+    }
+    Ok(objects.into_values().collect())
+}
+
+/// Parse the output of `gsutil stat` for `uri`.
+fn parse_gsutil_stat(uri: &str, stdout: &[u8]) -> Result<ObjectMetadata> {
+    let mut fields = HashMap::new();
+    for line in stdout.lines() {
+        let line = line?;
+        if let Some((key, value)) = line.trim().split_once(':') {
+            fields.insert(key.to_owned(), value.trim().to_owned());
+        }
+    }
+    let size = fields
+        .get("Content-Length")
+        .ok_or_else(|| format_err!("no Content-Length in gsutil stat output"))?
+        .parse::<u64>()
+        .context("cannot parse Content-Length in gsutil stat output")?;
+    let mut obj = ObjectMetadata::new(uri, size);
+    obj.etag = fields
+        .get("ETag")
+        .or_else(|| fields.get("Hash (md5)"))
+        .cloned();
+    obj.updated_at = fields
+        .get("Update time")
+        .and_then(|updated| parse_timestamp(updated));
+    Ok(obj)
+}
+
+#[test]
+fn parses_gsutil_ls_long() {
+    let stdout = b"\
+         0  2024-01-01T00:00:00Z  gs://bucket/dir/
+      1234  2024-01-02T03:04:05Z  gs://bucket/dir/a file.csv
+      1234  2024-01-02T03:04:05Z  gs://bucket/dir/a file.csv
+TOTAL: 3 objects, 2468 bytes (2.41 KiB)
+";
+    assert_eq!(
+        parse_gsutil_ls_long(stdout).unwrap(),
+        vec![
+            ObjectMetadata {
+                uri: "gs://bucket/dir/".to_owned(),
+                size: 0,
+                etag: None,
+                updated_at: parse_timestamp("2024-01-01T00:00:00Z"),
+            },
+            ObjectMetadata {
+                uri: "gs://bucket/dir/a file.csv".to_owned(),
+                size: 1234,
+                etag: None,
+                updated_at: parse_timestamp("2024-01-02T03:04:05Z"),
+            },
+        ]
+    );
+    assert!(parse_gsutil_ls_long(b"garbage\n").is_err());
+}
+
+#[test]
+fn parses_gsutil_stat() {
+    let stdout = b"\
+gs://bucket/dir/a.csv:
+    Creation time:          Tue, 02 Jan 2024 03:04:05 GMT
+    Update time:            Wed, 03 Jan 2024 03:04:05 GMT
+    Storage class:          STANDARD
+    Content-Length:         1234
+    Content-Type:           text/csv
+    Hash (crc32c):          AAAAAA==
+    Hash (md5):             1B2M2Y8AsgTpgAmY7PhCfg==
+    ETag:                   CJ+Xq8v2/YIDEAE=
+    Generation:             1704164645000000
+    Metageneration:         1
+";
+    let obj = parse_gsutil_stat("gs://bucket/dir/a.csv", stdout).unwrap();
+    assert_eq!(obj.uri, "gs://bucket/dir/a.csv");
+    assert_eq!(obj.size, 1234);
+    assert_eq!(obj.etag.as_deref(), Some("CJ+Xq8v2/YIDEAE="));
+    assert_eq!(obj.updated_at, parse_timestamp("2024-01-03T03:04:05Z"),);
+    assert!(obj.updated_at.is_some());
+    assert!(parse_gsutil_stat("gs://bucket/a", b"gs://bucket/a:\n").is_err());
+}
+
+#[test]
+fn builds_gsutil_upload_args() {
+    assert!(gsutil_upload_args(&UploadOptions::default()).is_empty());
+    let mut options = UploadOptions {
+        kms_key_id: Some("projects/p/locations/l/keyRings/r/cryptoKeys/k".to_owned()),
+        storage_class: Some("NEARLINE".to_owned()),
+        ..UploadOptions::default()
+    };
+    options
+        .metadata
+        .insert("pipeline".to_owned(), "words".to_owned());
+    assert_eq!(
+        gsutil_upload_args(&options),
+        &[
+            "-o",
+            "GSUtil:encryption_key=projects/p/locations/l/keyRings/r/cryptoKeys/k",
+            "-h",
+            "x-goog-meta-pipeline:words",
+        ],
+    );
+}
+
+#[test]
+fn gsutil_uses_service_account_key() {
+    let ambient = GoogleCloudStorage::from_key(None).unwrap();
+    assert_eq!(ambient.gsutil().get_envs().count(), 0);
+
+    let key = r#"{"type": "service_account", "client_email": "a@b.iam.gserviceaccount.com", "private_key": "..."}"#;
+    let storage = GoogleCloudStorage::from_key(Some(key)).unwrap();
+    let key_path = storage.key_file.as_ref().unwrap().path().to_owned();
+    let cmd = storage.gsutil();
+    let envs = cmd.get_envs().collect::<Vec<_>>();
+    assert_eq!(
+        envs,
+        vec![(
+            "CLOUDSDK_AUTH_CREDENTIAL_FILE_OVERRIDE".as_ref(),
+            Some(key_path.as_os_str()),
+        )],
+    );
+    assert_eq!(fs::read_to_string(&key_path).unwrap(), key);
+    drop(cmd);
+    drop(storage);
+    assert!(!key_path.exists());
+
+    assert!(GoogleCloudStorage::from_key(Some("not json")).is_err());
+    let user_key = r#"{"type": "authorized_user", "client_email": "a@b.com"}"#;
+    assert!(GoogleCloudStorage::from_key(Some(user_key)).is_err());
 }
@@ -0,0 +1,404 @@
+//! Read-only support for files served over HTTP(S).
+//!
+//! Since HTTP has no standard way to list a directory, a directory URI (ending
+//! in `/`) must serve a "manifest": a text file listing the URLs of the files
+//! in that directory, one per line. Each line may optionally be followed by
+//! whitespace and the size of the file in bytes, which saves us from needing
+//! to make a `HEAD` request for every file. Blank lines and lines starting with
+//! `#` are ignored. Relative URLs are resolved relative to the directory URI,
+//! and all URLs must point inside the directory. A URI without a trailing `/`
+//! names a single file.
+
+use attohttpc::{Method, RequestBuilder, Response, StatusCode};
+use std::{env, fs, io::Read};
+use url::Url;
+
+use super::{parse_timestamp, CloudStorage, ObjectMetadata, ObjectWriter};
+use crate::kubernetes::{base64_encoded_secret_string, storage_secret};
+use crate::prelude::*;
+use crate::secret::Secret;
+
+/// An HTTP secret fetched from Kubernetes. This can be fetched using
+/// `kubernetes_secret`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE", deny_unknown_fields)]
+struct HttpSecretData {
+    /// Our `HTTP_AUTHORIZATION` value, which will be sent as an
+    /// `Authorization` header, as in `"Bearer ..."`.
+    #[serde(with = "base64_encoded_secret_string")]
+    http_authorization: String,
+}
+
+/// Backend for reading files from HTTP(S) servers.
+pub struct HttpStorage {
+    /// The value of our `Authorization` header, if any.
+    authorization: Option<String>,
+}
+
+impl HttpStorage {
+    /// Create a new `HttpStorage` backend. If `secrets` contains a secret which
+    /// sets `HTTP_AUTHORIZATION`, we read our `Authorization` header from that
+    /// secret. Otherwise, we use the `HTTP_AUTHORIZATION` environment variable,
+    /// if present.
+    #[allow(clippy::new_ret_no_self)]
+    #[tracing::instrument(level = "trace")]
+    pub fn new(secrets: &[Secret]) -> Result<Self> {
+        let secret = secrets.iter().find(|s| {
+            matches!(s, Secret::Env { env_var, .. } if env_var == "HTTP_AUTHORIZATION")
+        });
+        let authorization = if let Some(Secret::Env { name, .. }) = secret {
+            let secret_data: HttpSecretData = storage_secret(name)?;
+            Some(secret_data.http_authorization)
+        } else {
+            env::var("HTTP_AUTHORIZATION").ok()
+        };
+        Ok(HttpStorage { authorization })
+    }
+
+    /// Send a request to `uri`, including any authorization we happen to have.
+    /// Does not check the response status.
+    fn send_unchecked(&self, method: Method, uri: &str) -> Result<Response> {
+        trace!("{} {}", method, uri);
+        let mut builder = RequestBuilder::try_new(method, uri)?;
+        if let Some(authorization) = &self.authorization {
+            builder = builder.header("Authorization", authorization);
+        }
+        builder
+            .send()
+            .with_context(|| format!("could not fetch {}", uri))
+    }
+
+    /// Send a request to `uri`, including any authorization we happen to have.
+    /// Returns an error if the request fails.
+    fn send(&self, method: Method, uri: &str) -> Result<Response> {
+        let response = self.send_unchecked(method, uri)?;
+        if response.is_success() {
+            Ok(response)
+        } else {
+            Err(format_err!(
+                "could not fetch {}: {}",
+                uri,
+                response.status()
+            ))
+        }
+    }
+
+    /// Download `uri` to `local_path`.
+    fn download_file(&self, uri: &str, local_path: &Path) -> Result<()> {
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent)
+                .context("cannot create local download directory")?;
+        }
+        let file = fs::File::create(local_path)
+            .with_context(|| format!("cannot create {}", local_path.display()))?;
+        self.send(Method::GET, uri)?
+            .write_to(file)
+            .with_context(|| format!("could not download {}", uri))?;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for HttpStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't include secrets in the debug output, for trace mode.
+        f.debug_struct("HttpStorage").finish()
+    }
+}
+
+impl CloudStorage for HttpStorage {
+    #[tracing::instrument(level = "trace")]
+    fn list(&self, uri: &str) -> Result<Vec<ObjectMetadata>> {
+        trace!("listing {}", uri);
+        if !uri.ends_with('/') {
+            // A single file, which we can't list, so just ask the server for
+            // its metadata.
+            let response = self.send(Method::HEAD, uri)?;
+            return Ok(vec![response_metadata(uri.to_owned(), &response)]);
+        }
+        let manifest = self
+            .send(Method::GET, uri)?
+            .text()
+            .with_context(|| format!("could not read manifest {}", uri))?;
+
+        let mut objects = vec![];
+        for (file_uri, size) in parse_manifest(uri, &manifest)? {
+            let obj = match size {
+                Some(size) => ObjectMetadata::new(file_uri, size),
+                None => {
+                    // Ask the server for the size.
+                    let response = self.send(Method::HEAD, &file_uri)?;
+                    response_metadata(file_uri, &response)
+                }
+            };
+            objects.push(obj);
+        }
+        Ok(objects)
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn sync_down(&self, uri: &str, local_path: &Path) -> Result<()> {
+        trace!("downloading {} to {}", uri, local_path.display());
+        if uri.ends_with('/') {
+            fs::create_dir_all(local_path)
+                .context("cannot create local download directory")?;
+            for obj in self.list(uri)? {
+                let rel_path = &obj.uri[uri.len()..];
+                self.download_file(&obj.uri, &local_path.join(rel_path))?;
+            }
+            Ok(())
+        } else {
+            self.download_file(uri, local_path)
+        }
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn sync_up(&self, local_path: &Path, uri: &str) -> Result<()> {
+        Err(format_err!(
+            "cannot upload {} to {}: HTTP storage is read-only",
+            local_path.display(),
+            uri,
+        ))
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn stat(&self, uri: &str) -> Result<Option<ObjectMetadata>> {
+        let response = self.send_unchecked(Method::HEAD, uri)?;
+        if response.status() == StatusCode::NOT_FOUND {
+            Ok(None)
+        } else if response.is_success() {
+            Ok(Some(response_metadata(uri.to_owned(), &response)))
+        } else {
+            Err(format_err!(
+                "could not fetch {}: {}",
+                uri,
+                response.status()
+            ))
+        }
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn delete(&self, uri: &str) -> Result<()> {
+        Err(format_err!(
+            "cannot delete {}: HTTP storage is read-only",
+            uri
+        ))
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn open_read(&self, uri: &str) -> Result<Box<dyn Read + '_>> {
+        let (_status, _headers, reader) = self.send(Method::GET, uri)?.split();
+        Ok(Box::new(reader))
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn open_write(&self, uri: &str) -> Result<Box<dyn ObjectWriter + '_>> {
+        Err(format_err!(
+            "cannot write {}: HTTP storage is read-only",
+            uri
+        ))
+    }
+}
+
+/// Build the `ObjectMetadata` for `uri` using the headers in `response`. Some
+/// servers don't report a `Content-Length`, in which case we assume 0.
+fn response_metadata(uri: String, response: &Response) -> ObjectMetadata {
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    ObjectMetadata {
+        uri,
+        size: header("Content-Length")
+            .and_then(|len| len.parse::<u64>().ok())
+            .unwrap_or(0),
+        etag: header("ETag").map(|etag| etag.to_owned()),
+        updated_at: header("Last-Modified").and_then(parse_timestamp),
+    }
+}
+
+/// Parse a manifest listing the files in `base_uri`. Returns the absolute
+/// URI of each file, and its size, if known.
+fn parse_manifest(
+    base_uri: &str,
+    manifest: &str,
+) -> Result<Vec<(String, Option<u64>)>> {
+    let base = Url::parse(base_uri)
+        .with_context(|| format!("could not parse URL {:?}", base_uri))?;
+    let mut files = vec![];
+    for line in manifest.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let rel_uri = fields.next().expect("non-empty line should have a field");
+        let size = fields
+            .next()
+            .map(|size| {
+                size.parse::<u64>().with_context(|| {
+                    format!("invalid size in manifest {}: {:?}", base_uri, line)
+                })
+            })
+            .transpose()?;
+        if fields.next().is_some() {
+            return Err(format_err!(
+                "too many fields in manifest {}: {:?}",
+                base_uri,
+                line,
+            ));
+        }
+
+        let file_uri = base
+            .join(rel_uri)
+            .with_context(|| {
+                format!("invalid URL in manifest {}: {:?}", base_uri, line)
+            })?
+            .to_string();
+        if !file_uri.starts_with(base_uri) || file_uri.len() == base_uri.len() {
+            return Err(format_err!(
+                "manifest {} contains {}, which is not inside {}",
+                base_uri,
+                file_uri,
+                base_uri,
+            ));
+        }
+        files.push((file_uri, size));
+    }
+    Ok(files)
+}
+
+#[test]
+fn manifest_parsing() {
+    let base = "https://example.com/data/";
+    let manifest = "\
+# Our reference data.
+a.csv 10
+https://example.com/data/nested/b.csv
+
+  c%20d.csv\t3
+";
+    assert_eq!(
+        parse_manifest(base, manifest).unwrap(),
+        vec![
+            ("https://example.com/data/a.csv".to_owned(), Some(10)),
+            ("https://example.com/data/nested/b.csv".to_owned(), None),
+            ("https://example.com/data/c%20d.csv".to_owned(), Some(3)),
+        ],
+    );
+    assert!(parse_manifest(base, "../secret.csv").is_err());
+    assert!(parse_manifest(base, "https://example.org/data/a.csv").is_err());
+    assert!(parse_manifest(base, "a.csv big").is_err());
+    assert!(parse_manifest(base, "a.csv 1 2").is_err());
+}
+
+#[test]
+fn http_round_trip() {
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+        thread,
+    };
+
+    // Run a tiny HTTP server which requires an `Authorization` header.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut authorized = false;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                authorized |= header.trim() == "authorization: Bearer secret";
+            }
+            let mut parts = request_line.split_whitespace();
+            let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+            let body = match (authorized, path) {
+                (false, _) => None,
+                (true, "/data/") => Some("a.txt 5\nnested/b.txt\n"),
+                (true, "/data/a.txt") => Some("hello"),
+                (true, "/data/nested/b.txt") => Some("hi"),
+                _ => None,
+            };
+            let response = match body {
+                Some(body) => format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nETag: \"{}\"\r\n\
+                     Last-Modified: Tue, 02 Jan 2024 03:04:05 GMT\r\n\
+                     Connection: close\r\n\r\n{}",
+                    body.len(),
+                    body,
+                    if method == "HEAD" { "" } else { body },
+                ),
+                None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_owned(),
+            };
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+
+    let uri = format!("http://{}/data/", addr);
+    let storage = HttpStorage {
+        authorization: Some("Bearer secret".to_owned()),
+    };
+    assert_eq!(
+        storage.list(&uri).unwrap(),
+        vec![
+            ObjectMetadata::new(format!("{}a.txt", uri), 5),
+            ObjectMetadata {
+                uri: format!("{}nested/b.txt", uri),
+                size: 2,
+                etag: Some("\"hi\"".to_owned()),
+                updated_at: parse_timestamp("Tue, 02 Jan 2024 03:04:05 GMT"),
+            },
+        ],
+    );
+    assert_eq!(
+        storage.list(&format!("{}a.txt", uri)).unwrap(),
+        vec![ObjectMetadata {
+            uri: format!("{}a.txt", uri),
+            size: 5,
+            etag: Some("\"hello\"".to_owned()),
+            updated_at: parse_timestamp("Tue, 02 Jan 2024 03:04:05 GMT"),
+        }],
+    );
+    assert!(storage.list(&format!("{}missing.txt", uri)).is_err());
+    assert!(storage.exists(&format!("{}a.txt", uri)).unwrap());
+    assert_eq!(storage.stat(&format!("{}missing.txt", uri)).unwrap(), None);
+    let mut contents = String::new();
+    storage
+        .open_read(&format!("{}a.txt", uri))
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents, "hello");
+    assert!(storage.delete(&format!("{}a.txt", uri)).is_err());
+    assert!(storage.open_write(&format!("{}a.txt", uri)).is_err());
+
+    let root = env::temp_dir().join(format!("falconeri-{}", Uuid::new_v4()));
+    let download = root.join("download/");
+    storage.sync_down(&uri, &download).unwrap();
+    assert_eq!(
+        fs::read_to_string(download.join("nested/b.txt")).unwrap(),
+        "hi"
+    );
+    storage
+        .sync_down(&format!("{}a.txt", uri), &root.join("a.txt"))
+        .unwrap();
+    assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "hello");
+    assert!(storage.sync_up(&root, &uri).is_err());
+
+    let anonymous = HttpStorage {
+        authorization: None,
+    };
+    assert!(anonymous.list(&uri).is_err());
+
+    fs::remove_dir_all(root).unwrap();
+}
@@ -0,0 +1,267 @@
+//! An in-memory storage backend, for use in tests.
+//!
+//! All `mem://` URIs share a single, process-wide store, so tests can seed it
+//! with input data before calling code that uses `CloudStorage`, and inspect it
+//! afterwards. Since tests run in parallel, each test should use its own
+//! prefix, such as `mem://test-name/`.
+//!
+//! This is only available in our own tests, or with the `test-storage` feature,
+//! which other crates should enable in their `dev-dependencies`.
+
+use lazy_static::lazy_static;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Read},
+    sync::Mutex,
+};
+
+use super::{local_files_recursive, CloudStorage, ObjectMetadata, ObjectWriter};
+use crate::prelude::*;
+use crate::secret::Secret;
+
+lazy_static! {
+    /// The contents of every `mem://` object, indexed by URI.
+    static ref OBJECTS: Mutex<BTreeMap<String, Vec<u8>>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Backend which stores objects in memory.
+#[derive(Debug)]
+pub struct MemoryStorage {}
+
+impl MemoryStorage {
+    /// Create a new `MemoryStorage` backend.
+    #[allow(clippy::new_ret_no_self)]
+    #[tracing::instrument(level = "trace")]
+    pub fn new(_secrets: &[Secret]) -> Result<Self> {
+        Ok(MemoryStorage {})
+    }
+
+    /// Store `data` at `uri`, replacing any existing object.
+    pub fn put<D: Into<Vec<u8>>>(uri: &str, data: D) {
+        assert!(uri.starts_with("mem://"), "expected mem:// URI: {:?}", uri);
+        objects().insert(uri.to_owned(), data.into());
+    }
+
+    /// Get the contents of the object at `uri`, if it exists.
+    pub fn get(uri: &str) -> Option<Vec<u8>> {
+        objects().get(uri).cloned()
+    }
+
+    /// Get the URIs of all the objects starting with `prefix`, in sorted order.
+    pub fn uris_with_prefix(prefix: &str) -> Vec<String> {
+        objects()
+            .keys()
+            .filter(|uri| uri.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    /// Delete all the objects starting with `prefix`.
+    pub fn clear(prefix: &str) {
+        objects().retain(|uri, _| !uri.starts_with(prefix));
+    }
+}
+
+/// Lock our global object store.
+fn objects() -> std::sync::MutexGuard<'static, BTreeMap<String, Vec<u8>>> {
+    // If another test panicked while holding the lock, our data is still fine.
+    OBJECTS.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Make sure `uri` is a `mem://` URI.
+fn check_uri(uri: &str) -> Result<()> {
+    if uri.starts_with("mem://") {
+        Ok(())
+    } else {
+        Err(format_err!("expected a mem:// URI, found {:?}", uri))
+    }
+}
+
+/// Write `data` to `local_path`, creating any parent directories.
+fn write_local_file(local_path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(parent) = local_path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("cannot create {}", parent.display()))?;
+    }
+    fs::write(local_path, data)
+        .with_context(|| format!("cannot write {}", local_path.display()))
+}
+
+impl CloudStorage for MemoryStorage {
+    #[tracing::instrument(level = "trace")]
+    fn list(&self, uri: &str) -> Result<Vec<ObjectMetadata>> {
+        check_uri(uri)?;
+        let mut prefix = uri.to_owned();
+        if !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        Ok(objects()
+            .iter()
+            .filter(|(obj_uri, _)| obj_uri.starts_with(&prefix))
+            .map(|(obj_uri, data)| ObjectMetadata::new(obj_uri, data.len() as u64))
+            .collect())
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn sync_down(&self, uri: &str, local_path: &Path) -> Result<()> {
+        check_uri(uri)?;
+        if uri.ends_with('/') {
+            fs::create_dir_all(local_path)
+                .with_context(|| format!("cannot create {}", local_path.display()))?;
+            // Copy our data out so we don't hold the lock during I/O.
+            let matches = objects()
+                .iter()
+                .filter(|(obj_uri, _)| obj_uri.starts_with(uri))
+                .map(|(obj_uri, data)| (obj_uri[uri.len()..].to_owned(), data.clone()))
+                .collect::<Vec<_>>();
+            for (rel_path, data) in matches {
+                write_local_file(&local_path.join(rel_path), &data)?;
+            }
+            Ok(())
+        } else {
+            let data = MemoryStorage::get(uri)
+                .ok_or_else(|| format_err!("could not find {:?}", uri))?;
+            write_local_file(local_path, &data)
+        }
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn sync_up(&self, local_path: &Path, uri: &str) -> Result<()> {
+        check_uri(uri)?;
+        let mut prefix = uri.to_owned();
+        if !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        for rel_path in local_files_recursive(local_path)? {
+            let path = local_path.join(&rel_path);
+            let data = fs::read(&path)
+                .with_context(|| format!("cannot read {}", path.display()))?;
+            MemoryStorage::put(&format!("{}{}", prefix, rel_path), data);
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn stat(&self, uri: &str) -> Result<Option<ObjectMetadata>> {
+        check_uri(uri)?;
+        Ok(objects()
+            .get(uri)
+            .map(|data| ObjectMetadata::new(uri, data.len() as u64)))
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn delete(&self, uri: &str) -> Result<()> {
+        check_uri(uri)?;
+        objects().remove(uri);
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn open_read(&self, uri: &str) -> Result<Box<dyn Read + '_>> {
+        check_uri(uri)?;
+        let data = MemoryStorage::get(uri)
+            .ok_or_else(|| format_err!("could not find {:?}", uri))?;
+        Ok(Box::new(io::Cursor::new(data)))
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn open_write(&self, uri: &str) -> Result<Box<dyn ObjectWriter + '_>> {
+        check_uri(uri)?;
+        Ok(Box::new(MemoryObjectWriter {
+            uri: uri.to_owned(),
+            data: vec![],
+        }))
+    }
+}
+
+/// An `ObjectWriter` which stores an object in memory when finished.
+struct MemoryObjectWriter {
+    /// The URI of the object to write.
+    uri: String,
+    /// The data written so far.
+    data: Vec<u8>,
+}
+
+impl Write for MemoryObjectWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ObjectWriter for MemoryObjectWriter {
+    fn finish(self: Box<Self>) -> Result<()> {
+        MemoryStorage::put(&self.uri, self.data);
+        Ok(())
+    }
+}
+
+#[test]
+fn mem_round_trip() {
+    let root = std::env::temp_dir().join(format!("falconeri-{}", Uuid::new_v4()));
+    let uri = "mem://mem_round_trip/";
+    MemoryStorage::put("mem://mem_round_trip/a.txt", "hello");
+    MemoryStorage::put("mem://mem_round_trip/nested/b.txt", "hi");
+    let storage = MemoryStorage::new(&[]).unwrap();
+    assert_eq!(
+        storage.list(uri).unwrap(),
+        vec![
+            ObjectMetadata::new("mem://mem_round_trip/a.txt", 5),
+            ObjectMetadata::new("mem://mem_round_trip/nested/b.txt", 2),
+        ],
+    );
+
+    let download = root.join("download/");
+    storage.sync_down(uri, &download).unwrap();
+    assert_eq!(
+        fs::read_to_string(download.join("nested/b.txt")).unwrap(),
+        "hi"
+    );
+    assert!(storage
+        .sync_down(
+            "mem://mem_round_trip/missing.txt",
+            &root.join("missing.txt")
+        )
+        .is_err());
+
+    storage
+        .sync_up(&download, "mem://mem_round_trip/copy/")
+        .unwrap();
+    assert_eq!(
+        MemoryStorage::uris_with_prefix("mem://mem_round_trip/copy/"),
+        &[
+            "mem://mem_round_trip/copy/a.txt",
+            "mem://mem_round_trip/copy/nested/b.txt",
+        ],
+    );
+
+    let mut writer = storage.open_write("mem://mem_round_trip/new.txt").unwrap();
+    writer.write_all(b"new").unwrap();
+    assert!(!storage.exists("mem://mem_round_trip/new.txt").unwrap());
+    writer.finish().unwrap();
+    assert_eq!(
+        storage.stat("mem://mem_round_trip/new.txt").unwrap(),
+        Some(ObjectMetadata::new("mem://mem_round_trip/new.txt", 3)),
+    );
+    let mut contents = String::new();
+    storage
+        .open_read("mem://mem_round_trip/new.txt")
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents, "new");
+    storage.delete("mem://mem_round_trip/new.txt").unwrap();
+    storage.delete("mem://mem_round_trip/new.txt").unwrap();
+    assert!(!storage.exists("mem://mem_round_trip/new.txt").unwrap());
+    assert!(storage.open_read("mem://mem_round_trip/new.txt").is_err());
+
+    MemoryStorage::clear(uri);
+    assert!(MemoryStorage::get("mem://mem_round_trip/a.txt").is_none());
+
+    fs::remove_dir_all(root).unwrap();
+}
@@ -1,16 +1,144 @@
 //! Cloud storage backends.
 
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Read},
+};
+use tempfile::NamedTempFile;
+
 use crate::prelude::*;
 use crate::secret::Secret;
 
+pub mod azure;
+pub mod file;
 pub mod gs;
+pub mod http;
+#[cfg(any(test, feature = "test-storage"))]
+pub mod mem;
 pub mod s3;
 
+/// Information about an object stored in a cloud storage bucket.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObjectMetadata {
+    /// The full URI of the object.
+    pub uri: String,
+    /// The size of the object, in bytes.
+    pub size: u64,
+    /// An opaque identifier for the current contents of the object, such as an
+    /// S3 ETag or a GCS MD5 hash, if the backend provides one. The format
+    /// depends on the backend, so this should only be compared with other
+    /// values from the same backend.
+    pub etag: Option<String>,
+    /// When the object was last modified (in UTC), if known.
+    pub updated_at: Option<NaiveDateTime>,
+}
+
+impl ObjectMetadata {
+    /// Create metadata for an object, without any of the optional fields.
+    pub fn new<S: Into<String>>(uri: S, size: u64) -> ObjectMetadata {
+        ObjectMetadata {
+            uri: uri.into(),
+            size,
+            etag: None,
+            updated_at: None,
+        }
+    }
+}
+
+/// How cloud storage should encrypt the objects we upload.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum ServerSideEncryption {
+    /// Encrypt objects using keys managed by the storage provider.
+    #[serde(rename = "AES256")]
+    Aes256,
+    /// Encrypt objects using a key stored in the provider's key management
+    /// service.
+    #[serde(rename = "aws:kms")]
+    Kms,
+}
+
+/// Options which control how we store the objects we upload. Not every backend
+/// supports every option.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UploadOptions {
+    /// How to encrypt new objects.
+    pub sse: Option<ServerSideEncryption>,
+    /// The KMS key used to encrypt new objects. Requires `sse` to be `Kms`.
+    pub kms_key_id: Option<String>,
+    /// The storage class of new objects, such as `STANDARD_IA` or `NEARLINE`.
+    pub storage_class: Option<String>,
+    /// Custom metadata to attach to new objects.
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl UploadOptions {
+    /// Are all our options set to their defaults?
+    pub fn is_empty(&self) -> bool {
+        self == &UploadOptions::default()
+    }
+
+    /// Make sure our options are consistent, and that they can be safely sent
+    /// as HTTP headers.
+    pub fn validate(&self) -> Result<()> {
+        if self.kms_key_id.is_some() && self.sse != Some(ServerSideEncryption::Kms) {
+            return Err(format_err!("kms_key_id requires sse to be \"aws:kms\""));
+        }
+        let is_header_safe =
+            |value: &str| value.chars().all(|c| (' '..='~').contains(&c));
+        for value in self.kms_key_id.iter().chain(&self.storage_class) {
+            if value.is_empty() || !is_header_safe(value) {
+                return Err(format_err!("invalid upload option {:?}", value));
+            }
+        }
+        for (key, value) in &self.metadata {
+            let valid_key = !key.is_empty()
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid_key {
+                return Err(format_err!("invalid metadata key {:?}", key));
+            }
+            if !is_header_safe(value) {
+                return Err(format_err!(
+                    "metadata value for {:?} must be printable ASCII",
+                    key
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A stream which writes a new object to cloud storage.
+///
+/// Many backends don't actually store the object until `finish` is called,
+/// and there's no way to report errors from `drop`. So callers must always
+/// call `finish`, or the object may be discarded or left incomplete.
+pub trait ObjectWriter: Write {
+    /// Finish writing the object, and wait until it has been stored.
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
 /// Abstract interface to different kinds of cloud storage backends.
 pub trait CloudStorage {
-    /// List all the files and subdirectories immediately present in `uri` if
-    /// `uri` is a directory, or just return `uri` if it points to a file.
-    fn list(&self, uri: &str) -> Result<Vec<String>>;
+    /// List all the files present in `uri`, recursively. Here, `uri` should be
+    /// a directory, and it should end with `/`. Returns the URI and size of
+    /// each file. If `uri` doesn't exist, this returns an empty list.
+    fn list(&self, uri: &str) -> Result<Vec<ObjectMetadata>>;
+
+    /// Use `options` for all objects we upload from now on. Backends which
+    /// don't support upload options return an error unless `options` is empty.
+    fn set_upload_options(&mut self, options: UploadOptions) -> Result<()> {
+        if options.is_empty() {
+            Ok(())
+        } else {
+            Err(format_err!(
+                "this storage backend does not support sse, kms_key_id, \
+                 storage_class or metadata"
+            ))
+        }
+    }
 
     /// Synchronize `uri` down to `local_path` recursively. Does not delete any
     /// existing destination files. The contents of `uri` should be exactly
@@ -28,6 +156,39 @@ pub trait CloudStorage {
     /// exactly represented in `uri`, without the trailing subdirectory name
     /// being inserted—this is a straight directory-to-directory sync.
     fn sync_up(&self, local_path: &Path, uri: &str) -> Result<()>;
+
+    /// Get metadata for the object at `uri`, or `None` if it doesn't exist.
+    /// Here, `uri` must be a file, not a directory.
+    fn stat(&self, uri: &str) -> Result<Option<ObjectMetadata>>;
+
+    /// Does the object at `uri` exist?
+    fn exists(&self, uri: &str) -> Result<bool> {
+        Ok(self.stat(uri)?.is_some())
+    }
+
+    /// Delete the object at `uri`. It is not an error if the object doesn't
+    /// exist. Here, `uri` must be a file, not a directory.
+    fn delete(&self, uri: &str) -> Result<()>;
+
+    /// Open the object at `uri` for reading.
+    fn open_read(&self, uri: &str) -> Result<Box<dyn Read + '_>>;
+
+    /// Create (or replace) the object at `uri`, returning a stream which can be
+    /// used to write its contents. You must call `ObjectWriter::finish` when
+    /// you're done writing.
+    fn open_write(&self, uri: &str) -> Result<Box<dyn ObjectWriter + '_>>;
+
+    /// Copy the object at `from` to `to`, replacing any existing object. Both
+    /// URIs must be files handled by this backend. By default, this streams the
+    /// data through this process.
+    fn copy(&self, from: &str, to: &str) -> Result<()> {
+        trace!("copying {} to {}", from, to);
+        let mut reader = self.open_read(from)?;
+        let mut writer = self.open_write(to)?;
+        io::copy(&mut reader, &mut writer)
+            .with_context(|| format!("could not copy {} to {}", from, to))?;
+        writer.finish()
+    }
 }
 
 impl dyn CloudStorage {
@@ -35,8 +196,20 @@ impl dyn CloudStorage {
     /// secrets, we can pass them as the `secrets` array, and the storage driver
     /// can check to see if there are any secrets it can use to authenticate.
     pub fn for_uri(uri: &str, secrets: &[Secret]) -> Result<Box<dyn CloudStorage>> {
-        if uri.starts_with("gs://") {
+        // `mem://` is only for tests, so it's never available in production.
+        #[cfg(any(test, feature = "test-storage"))]
+        if uri.starts_with("mem://") {
+            return Ok(Box::new(mem::MemoryStorage::new(secrets)?));
+        }
+
+        if uri.starts_with("az://") {
+            Ok(Box::new(azure::AzureStorage::new(secrets)?))
+        } else if uri.starts_with("file://") {
+            Ok(Box::new(file::FileStorage::new(secrets)?))
+        } else if uri.starts_with("gs://") {
             Ok(Box::new(gs::GoogleCloudStorage::new(secrets)?))
+        } else if uri.starts_with("http://") || uri.starts_with("https://") {
+            Ok(Box::new(http::HttpStorage::new(secrets)?))
         } else if uri.starts_with("s3://") {
             Ok(Box::new(s3::S3Storage::new(secrets)?))
         } else {
@@ -44,3 +217,137 @@ impl dyn CloudStorage {
         }
     }
 }
+
+/// List all the files under `dir`, recursively, as `/`-separated paths
+/// relative to `dir`. The paths are returned in sorted order.
+pub(crate) fn local_files_recursive(dir: &Path) -> Result<Vec<String>> {
+    let mut files = vec![];
+    let mut pending = vec![(dir.to_owned(), String::new())];
+    while let Some((path, rel_path)) = pending.pop() {
+        let entries = fs::read_dir(&path)
+            .with_context(|| format!("could not list {}", path.display()))?;
+        for entry in entries {
+            let entry =
+                entry.with_context(|| format!("could not list {}", path.display()))?;
+            let name = entry.file_name().into_string().map_err(|name| {
+                format_err!("non-UTF-8 filename {:?} in {}", name, path.display())
+            })?;
+            let entry_rel_path = format!("{}{}", rel_path, name);
+            // Use `fs::metadata` so that we follow symlinks.
+            let metadata = fs::metadata(entry.path())
+                .with_context(|| format!("could not stat {:?}", entry.path()))?;
+            if metadata.is_dir() {
+                pending.push((entry.path(), format!("{}/", entry_rel_path)));
+            } else {
+                files.push(entry_rel_path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// A function which uploads a local file.
+type UploadFn<'a> = Box<dyn FnOnce(&Path) -> Result<()> + 'a>;
+
+/// An `ObjectWriter` which writes to a temporary local file, and uploads it
+/// when we're finished. This is useful for backends which need to know the
+/// size of an object before they can upload it.
+pub(crate) struct SpooledObjectWriter<'a> {
+    /// Our temporary file.
+    file: NamedTempFile,
+    /// A function which uploads our temporary file.
+    upload: UploadFn<'a>,
+}
+
+impl<'a> SpooledObjectWriter<'a> {
+    /// Create a new `SpooledObjectWriter`, which will call `upload` with the
+    /// path to a local file containing everything we've written.
+    pub(crate) fn new<F>(upload: F) -> Result<SpooledObjectWriter<'a>>
+    where
+        F: FnOnce(&Path) -> Result<()> + 'a,
+    {
+        let file = NamedTempFile::new().context("could not create temporary file")?;
+        Ok(SpooledObjectWriter {
+            file,
+            upload: Box::new(upload),
+        })
+    }
+}
+
+impl<'a> Write for SpooledObjectWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl<'a> ObjectWriter for SpooledObjectWriter<'a> {
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.file
+            .flush()
+            .context("could not write temporary file")?;
+        let SpooledObjectWriter { file, upload } = *self;
+        upload(file.path())
+    }
+}
+
+/// Parse a timestamp returned by a storage backend, which may be either an
+/// HTTP-style date (`"Tue, 02 Jan 2024 03:04:05 GMT"`) or an RFC 3339 date
+/// (`"2024-01-02T03:04:05.000Z"`). Returns `None` if we can't parse it, since
+/// these timestamps are purely informational.
+pub(crate) fn parse_timestamp(timestamp: &str) -> Option<NaiveDateTime> {
+    let timestamp = timestamp.trim();
+    chrono::DateTime::parse_from_rfc2822(timestamp)
+        .or_else(|_| chrono::DateTime::parse_from_rfc3339(timestamp))
+        .ok()
+        .map(|dt| dt.naive_utc())
+}
+
+#[test]
+fn parses_timestamps() {
+    let expected = chrono::NaiveDate::from_ymd_opt(2024, 1, 2)
+        .unwrap()
+        .and_hms_opt(3, 4, 5)
+        .unwrap();
+    assert_eq!(
+        parse_timestamp("Tue, 02 Jan 2024 03:04:05 GMT"),
+        Some(expected)
+    );
+    assert_eq!(parse_timestamp("2024-01-02T03:04:05.000Z"), Some(expected));
+    assert_eq!(parse_timestamp("2024-01-02T04:04:05+01:00"), Some(expected));
+    assert_eq!(parse_timestamp("yesterday"), None);
+}
+
+#[test]
+fn validates_upload_options() {
+    assert!(UploadOptions::default().is_empty());
+    assert!(UploadOptions::default().validate().is_ok());
+
+    let mut options = UploadOptions {
+        sse: Some(ServerSideEncryption::Kms),
+        kms_key_id: Some("alias/falconeri".to_owned()),
+        storage_class: Some("STANDARD_IA".to_owned()),
+        ..UploadOptions::default()
+    };
+    options
+        .metadata
+        .insert("pipeline-name".to_owned(), "books".to_owned());
+    assert!(!options.is_empty());
+    assert!(options.validate().is_ok());
+
+    let mut bad = options.clone();
+    bad.sse = Some(ServerSideEncryption::Aes256);
+    assert!(bad.validate().is_err());
+    let mut bad = options.clone();
+    bad.metadata
+        .insert("bad key".to_owned(), "value".to_owned());
+    assert!(bad.validate().is_err());
+    let mut bad = options;
+    bad.metadata
+        .insert("key".to_owned(), "line\nbreak".to_owned());
+    assert!(bad.validate().is_err());
+}
@@ -2,14 +2,28 @@
 
 use lazy_static::lazy_static;
 use regex::Regex;
-use serde_json;
-use std::{fs, process};
+use s3::{creds::Credentials, error::S3Error, Bucket, Region};
+use std::{collections::HashMap, env, fs, io::Read, str::FromStr, sync::Mutex};
 
-use super::CloudStorage;
-use crate::kubernetes::{base64_encoded_secret_string, kubectl_secret};
+use super::{
+    local_files_recursive, parse_timestamp, CloudStorage, ObjectMetadata,
+    ObjectWriter, ServerSideEncryption, SpooledObjectWriter, UploadOptions,
+};
+use crate::kubernetes::{
+    base64_encoded_optional_secret_string, base64_encoded_secret_string,
+    storage_secret,
+};
 use crate::prelude::*;
 use crate::secret::Secret;
 
+/// Files at least this large will be uploaded using a multipart upload. This
+/// matches the part size used by `rust-s3`.
+const MULTIPART_THRESHOLD: u64 = 8 * 1024 * 1024;
+
+/// How long the presigned URLs used by `open_read` should remain valid. This
+/// only needs to be long enough to start the download.
+const PRESIGNED_URL_EXPIRY_SECS: u32 = 15 * 60;
+
 /// An S3 secret fetched from Kubernetes. This can be fetched using
 /// `kubernetes_secret`.
 #[derive(Debug, Deserialize)]
@@ -21,11 +35,112 @@ struct S3SecretData {
     /// Our `AWS_SECRET_ACCESS_KEY` value.
     #[serde(with = "base64_encoded_secret_string")]
     aws_secret_access_key: String,
+    /// Our `AWS_ENDPOINT_URL` value, for S3-compatible servers like MinIO.
+    #[serde(default, with = "base64_encoded_optional_secret_string")]
+    aws_endpoint_url: Option<String>,
+    /// Our `AWS_REGION` value.
+    #[serde(default, with = "base64_encoded_optional_secret_string")]
+    aws_region: Option<String>,
+    /// Our `AWS_S3_ADDRESSING_STYLE` value.
+    #[serde(default, with = "base64_encoded_optional_secret_string")]
+    aws_s3_addressing_style: Option<String>,
+}
+
+/// How should we build the URLs for our S3 requests?
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AddressingStyle {
+    /// Use path-style URLs for custom endpoints, and virtual-hosted-style URLs
+    /// for AWS.
+    Auto,
+    /// Use URLs of the form `https://endpoint/bucket/key`.
+    Path,
+    /// Use URLs of the form `https://bucket.endpoint/key`.
+    Virtual,
+}
+
+impl FromStr for AddressingStyle {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(AddressingStyle::Auto),
+            "path" => Ok(AddressingStyle::Path),
+            "virtual" => Ok(AddressingStyle::Virtual),
+            _ => Err(format_err!(
+                "unknown S3 addressing style {:?} (expected auto, path or virtual)",
+                s,
+            )),
+        }
+    }
+}
+
+/// Where and how we should connect to S3.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct S3Config {
+    /// A custom endpoint URL to use instead of AWS.
+    endpoint_url: Option<String>,
+    /// The region to use, if known.
+    region: Option<String>,
+    /// How to build our request URLs.
+    addressing_style: AddressingStyle,
 }
 
-/// Backend for talking to AWS S3, currently based on `awscli`.
+impl S3Config {
+    /// Build our configuration from `secret_data` (if present), falling back to
+    /// the environment. Values set in the secret take priority.
+    ///
+    /// The environment variables have the same names as the secret keys. This
+    /// allows workers to use the same configuration as `falconerid`, as long as
+    /// the keys are also listed in `transform.secrets`.
+    fn new(secret_data: Option<&S3SecretData>) -> Result<S3Config> {
+        S3Config::from_lookup(secret_data, |var| env::var(var).ok())
+    }
+
+    /// Implementation of `new`, with an abstract `env_var` lookup for testing.
+    fn from_lookup(
+        secret_data: Option<&S3SecretData>,
+        env_var: impl Fn(&str) -> Option<String>,
+    ) -> Result<S3Config> {
+        let from_secret = |f: fn(&S3SecretData) -> &Option<String>| {
+            secret_data.and_then(|data| f(data).to_owned())
+        };
+        let endpoint_url = from_secret(|data| &data.aws_endpoint_url)
+            .or_else(|| env_var("AWS_ENDPOINT_URL"));
+        let region = from_secret(|data| &data.aws_region)
+            .or_else(|| env_var("AWS_REGION"))
+            .or_else(|| env_var("AWS_DEFAULT_REGION"));
+        let addressing_style = from_secret(|data| &data.aws_s3_addressing_style)
+            .or_else(|| env_var("AWS_S3_ADDRESSING_STYLE"))
+            .map(|style| style.parse())
+            .transpose()?
+            .unwrap_or(AddressingStyle::Auto);
+        Ok(S3Config {
+            endpoint_url,
+            region,
+            addressing_style,
+        })
+    }
+
+    /// Should we use path-style URLs?
+    fn use_path_style(&self) -> bool {
+        match self.addressing_style {
+            AddressingStyle::Auto => self.endpoint_url.is_some(),
+            AddressingStyle::Path => true,
+            AddressingStyle::Virtual => false,
+        }
+    }
+}
+
+/// Backend for talking to AWS S3, using a native client.
 pub struct S3Storage {
     secret_data: Option<S3SecretData>,
+    /// Where and how we should connect to S3.
+    config: S3Config,
+    /// The regions of the buckets we've seen, so we only need to look them up
+    /// once.
+    bucket_regions: Mutex<HashMap<String, Region>>,
+    /// Options to use when creating objects.
+    upload_options: UploadOptions,
 }
 
 impl S3Storage {
@@ -37,121 +152,269 @@ impl S3Storage {
             matches!(s, Secret::Env { env_var, .. } if env_var == "AWS_ACCESS_KEY_ID")
         });
         let secret_data = if let Some(Secret::Env { name, .. }) = secret {
-            Some(kubectl_secret(name)?)
+            Some(storage_secret(name)?)
         } else {
             None
         };
-        Ok(S3Storage { secret_data })
+        let config = S3Config::new(secret_data.as_ref())?;
+        Ok(S3Storage {
+            secret_data,
+            config,
+            bucket_regions: Mutex::new(HashMap::new()),
+            upload_options: UploadOptions::default(),
+        })
     }
 
     /// Construct a new `S3Storage` backend, using an AWS access key from
     /// the Kubernetes secret `secret_name`.
     #[tracing::instrument(level = "trace")]
     pub fn new_with_secret(secret_name: &str) -> Result<Self> {
+        let secret_data = storage_secret(secret_name)?;
+        let config = S3Config::new(Some(&secret_data))?;
         Ok(S3Storage {
-            secret_data: kubectl_secret(secret_name)?,
+            secret_data: Some(secret_data),
+            config,
+            bucket_regions: Mutex::new(HashMap::new()),
+            upload_options: UploadOptions::default(),
         })
     }
 
-    /// Build a `Command` object which calls the `aws` CLI tool, including any
-    /// authentication that we happen to have.
+    /// Get our AWS credentials. If we don't have a secret, we fall back to the
+    /// standard environment variables, `~/.aws/credentials`, and the instance
+    /// metadata service, just like the `aws` CLI tool.
+    fn credentials(&self) -> Result<Credentials> {
+        let credentials = if let Some(secret_data) = &self.secret_data {
+            Credentials::new(
+                Some(&secret_data.aws_access_key_id),
+                Some(&secret_data.aws_secret_access_key),
+                None,
+                None,
+                None,
+            )
+        } else {
+            Credentials::default()
+        };
+        credentials.context("could not find AWS credentials")
+    }
+
+    /// Figure out which region `bucket_name` lives in.
+    ///
+    /// If we have a custom endpoint URL, we talk to that server instead of AWS
+    /// (which is useful for MinIO and other S3-compatible servers). Otherwise
+    /// we use our configured region if present, and we ask S3 as a last resort.
+    fn region(&self, bucket_name: &str, credentials: &Credentials) -> Result<Region> {
+        let region_name = self.config.region.clone();
+        if let Some(endpoint) = &self.config.endpoint_url {
+            return Ok(Region::Custom {
+                region: region_name.unwrap_or_else(|| "us-east-1".to_owned()),
+                endpoint: endpoint.to_owned(),
+            });
+        } else if let Some(region_name) = region_name {
+            return region_name
+                .parse()
+                .with_context(|| format!("unknown AWS region {:?}", region_name));
+        }
+
+        let mut bucket_regions =
+            self.bucket_regions.lock().expect("lock poisoned by panic");
+        if let Some(region) = bucket_regions.get(bucket_name) {
+            return Ok(region.to_owned());
+        }
+        trace!("looking up region for S3 bucket {}", bucket_name);
+        let (region, _) =
+            Bucket::new(bucket_name, Region::UsEast1, credentials.to_owned())?
+                .location()
+                .with_context(|| {
+                    format!("could not find region for S3 bucket {}", bucket_name)
+                })?;
+        bucket_regions.insert(bucket_name.to_owned(), region.clone());
+        Ok(region)
+    }
+
+    /// Build a `Bucket` object which we can use to talk to `bucket_name`,
+    /// including any authentication that we happen to have.
     #[tracing::instrument(level = "trace")]
-    fn aws_command(&self) -> process::Command {
-        let mut command = process::Command::new("aws");
-        if let Some(secret_data) = &self.secret_data {
-            command.env("AWS_ACCESS_KEY_ID", &secret_data.aws_access_key_id);
-            command.env("AWS_SECRET_ACCESS_KEY", &secret_data.aws_secret_access_key);
+    fn bucket(&self, bucket_name: &str) -> Result<Box<Bucket>> {
+        let credentials = self.credentials()?;
+        let region = self.region(bucket_name, &credentials)?;
+        let mut bucket = Bucket::new(bucket_name, region, credentials)
+            .with_context(|| format!("could not access S3 bucket {}", bucket_name))?;
+        if self.config.use_path_style() {
+            bucket = bucket.with_path_style();
+        }
+        Ok(bucket)
+    }
+
+    /// Download the object `key` in `bucket` to `local_path`.
+    fn download_file(
+        &self,
+        bucket: &Bucket,
+        key: &str,
+        local_path: &Path,
+    ) -> Result<()> {
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent)
+                .context("cannot create local download directory")?;
+        }
+        let mut file = fs::File::create(local_path)
+            .with_context(|| format!("cannot create {}", local_path.display()))?;
+        bucket
+            .get_object_to_writer(key, &mut file)
+            .with_context(|| {
+                format!("could not download s3://{}/{}", bucket.name(), key)
+            })?;
+        Ok(())
+    }
+
+    /// Upload the file `local_path` to the object `key` in `bucket`.
+    fn upload_file(
+        &self,
+        bucket: &Bucket,
+        local_path: &Path,
+        key: &str,
+    ) -> Result<()> {
+        let mkerr = || {
+            format!(
+                "could not upload {} to s3://{}/{}",
+                local_path.display(),
+                bucket.name(),
+                key,
+            )
+        };
+        let mut file = fs::File::open(local_path)
+            .with_context(|| format!("cannot open {}", local_path.display()))?;
+        let size = file.metadata().with_context(mkerr)?.len();
+        let mut upload_bucket = bucket.clone();
+        for (name, value) in upload_headers(&self.upload_options) {
+            upload_bucket.add_header(&name, &value);
+        }
+        if size < MULTIPART_THRESHOLD {
+            // Small files can be sent using a single request.
+            let mut content = Vec::with_capacity(cast::usize(size));
+            file.read_to_end(&mut content).with_context(mkerr)?;
+            upload_bucket
+                .put_object(key, &content)
+                .with_context(mkerr)?;
+        } else {
+            self.upload_multipart(bucket, &upload_bucket, &mut file, key)
+                .with_context(mkerr)?;
+        }
+        Ok(())
+    }
+
+    /// Upload `file` to the object `key` using a multipart upload. We only send
+    /// the headers in `upload_bucket` when creating the upload, and not with
+    /// each part, because S3 rejects encryption headers on part uploads. This
+    /// is why we can't use `Bucket::put_object_stream`.
+    fn upload_multipart(
+        &self,
+        bucket: &Bucket,
+        upload_bucket: &Bucket,
+        file: &mut fs::File,
+        key: &str,
+    ) -> Result<()> {
+        let content_type = "application/octet-stream";
+        let upload = upload_bucket.initiate_multipart_upload(key, content_type)?;
+        let result = (|| -> Result<()> {
+            let mut parts = vec![];
+            let mut chunk = Vec::with_capacity(cast::usize(MULTIPART_THRESHOLD));
+            loop {
+                chunk.clear();
+                (&mut *file)
+                    .take(MULTIPART_THRESHOLD)
+                    .read_to_end(&mut chunk)?;
+                if chunk.is_empty() {
+                    break;
+                }
+                let part_number = cast::u32(parts.len() + 1)?;
+                parts.push(bucket.put_multipart_chunk(
+                    &chunk,
+                    &upload.key,
+                    part_number,
+                    &upload.upload_id,
+                    content_type,
+                )?);
+            }
+            bucket.complete_multipart_upload(&upload.key, &upload.upload_id, parts)?;
+            Ok(())
+        })();
+        if result.is_err() {
+            // Clean up as best we can, and report the original error.
+            if let Err(err) = bucket.abort_upload(&upload.key, &upload.upload_id) {
+                warn!("could not abort multipart upload of {}: {}", key, err);
+            }
         }
-        command
+        result
     }
 }
 
 impl fmt::Debug for S3Storage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // Don't include secrets in the debug output, for trace mode.
-        f.debug_struct("S3Storage").finish()
+        f.debug_struct("S3Storage")
+            .field("config", &self.config)
+            .field("upload_options", &self.upload_options)
+            .finish()
     }
 }
 
 impl CloudStorage for S3Storage {
+    fn set_upload_options(&mut self, options: UploadOptions) -> Result<()> {
+        self.upload_options = options;
+        Ok(())
+    }
+
     #[tracing::instrument(level = "trace")]
-    fn list(&self, uri: &str) -> Result<Vec<String>> {
+    fn list(&self, uri: &str) -> Result<Vec<ObjectMetadata>> {
         trace!("listing {}", uri);
 
-        let (bucket, key) = parse_s3_url(uri)?;
+        let (bucket_name, key) = parse_s3_url(uri)?;
         let mut prefix = key.to_owned();
         if !key.is_empty() && !key.ends_with('/') {
             prefix.push('/');
         }
 
-        // Use `aws` to list our bucket, and parse the results.parse_s3_url(
-        let output = self
-            .aws_command()
-            .args(["s3api", "list-objects-v2"])
-            .arg("--bucket")
-            .arg(bucket)
-            .arg("--prefix")
-            .arg(prefix.clone())
-            .stderr(process::Stdio::inherit())
-            .output()
-            .context("could not run gsutil")?;
-        if !output.status.success() {
-            return Err(format_err!("could not list {:?}: {}", uri, output.status));
-        }
-        let s3_output: ListObjectsV2Output = serde_json::from_slice(&output.stdout)
-            .context("error parsing list-objects-v2 output")?;
+        // List our bucket. This will automatically fetch as many pages of
+        // results as necessary.
+        let pages = self
+            .bucket(bucket_name)?
+            .list(prefix.clone(), None)
+            .with_context(|| format!("could not list {:?}", uri))?;
 
-        // Fail if the bucket has too many entries to get in one call.
-        //
-        // TODO: Chain together multiple calls to `list-objects-v2`.
-        if s3_output.is_truncated.unwrap_or(false) {
-            return Err(format_err!(
-                "S3 prefix {:?} contains too many objects for this version",
-                uri,
-            ));
-        }
-
-        Ok(s3_output
-            .contents
+        Ok(pages
             .into_iter()
+            .flat_map(|page| page.contents)
             // Remove the directory itself.
             .filter(|obj| obj.key != prefix)
             // Convert to URLs.
-            .map(|obj| format!("s3://{}/{}", bucket, obj.key))
+            .map(|obj| ObjectMetadata {
+                uri: format!("s3://{}/{}", bucket_name, obj.key),
+                size: obj.size,
+                etag: obj.e_tag,
+                updated_at: parse_timestamp(&obj.last_modified),
+            })
             .collect::<Vec<_>>())
     }
 
     #[tracing::instrument(level = "trace")]
     fn sync_down(&self, uri: &str, local_path: &Path) -> Result<()> {
         trace!("downloading {} to {}", uri, local_path.display());
+        let (bucket_name, key) = parse_s3_url(uri)?;
+        let bucket = self.bucket(bucket_name)?;
         if uri.ends_with('/') {
             fs::create_dir_all(local_path)
                 .context("cannot create local download directory")?;
-            let status = self
-                .aws_command()
-                .args(["s3", "sync", "--quiet"])
-                .arg(uri)
-                .arg(local_path)
-                .status()
-                .context("could not run aws s3")?;
-            if !status.success() {
-                return Err(format_err!("could not download {:?}: {}", uri, status));
+            for obj in self.list(uri)? {
+                let rel_path = obj.uri[uri.len()..].to_owned();
+                if rel_path.ends_with('/') {
+                    // Skip placeholder objects for directories.
+                    continue;
+                }
+                let obj_key = format!("{}{}", key, rel_path);
+                self.download_file(&bucket, &obj_key, &local_path.join(&rel_path))?;
             }
         } else {
-            if let Some(parent) = local_path.parent() {
-                fs::create_dir_all(parent)
-                    .context("cannot create local download directory")?;
-            }
-            let status = self
-                .aws_command()
-                .args(["s3", "cp"])
-                .arg(uri)
-                .arg(local_path)
-                .status()
-                .context("could not run aws s3")?;
-            if !status.success() {
-                return Err(format_err!("could not download {:?}: {}", uri, status));
-            }
+            self.download_file(&bucket, key, local_path)?;
         }
         Ok(())
     }
@@ -161,24 +424,107 @@ impl CloudStorage for S3Storage {
         trace!("uploading {} to {}", local_path.display(), uri);
 
         // We assume that we only need to support directories, namely /pfs/out.
-        let status = self
-            .aws_command()
-            .args(["s3", "sync", "--quiet"])
-            .arg(local_path)
-            .arg(uri)
-            .status()
-            .context("could not run gsutil")?;
-        if !status.success() {
+        let (bucket_name, key) = parse_s3_url(uri)?;
+        let mut prefix = key.to_owned();
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        let bucket = self.bucket(bucket_name)?;
+        for rel_path in local_files_recursive(local_path)? {
+            let obj_key = format!("{}{}", prefix, rel_path);
+            self.upload_file(&bucket, &local_path.join(&rel_path), &obj_key)?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn stat(&self, uri: &str) -> Result<Option<ObjectMetadata>> {
+        let (bucket_name, key) = parse_s3_url(uri)?;
+        match self.bucket(bucket_name)?.head_object(key) {
+            Ok((head, _)) => Ok(Some(ObjectMetadata {
+                uri: uri.to_owned(),
+                size: head.content_length.map(cast::u64).transpose()?.unwrap_or(0),
+                etag: head.e_tag,
+                updated_at: head.last_modified.as_deref().and_then(parse_timestamp),
+            })),
+            Err(S3Error::HttpFailWithBody(404, _)) => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("could not stat {}", uri)),
+        }
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn delete(&self, uri: &str) -> Result<()> {
+        // S3 doesn't complain if the object doesn't exist.
+        let (bucket_name, key) = parse_s3_url(uri)?;
+        self.bucket(bucket_name)?
+            .delete_object(key)
+            .with_context(|| format!("could not delete {}", uri))?;
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn open_read(&self, uri: &str) -> Result<Box<dyn Read + '_>> {
+        // `rust-s3` can only stream downloads in async mode, so we download
+        // from a presigned URL instead.
+        let (bucket_name, key) = parse_s3_url(uri)?;
+        let url = self
+            .bucket(bucket_name)?
+            .presign_get(key, PRESIGNED_URL_EXPIRY_SECS, None)
+            .with_context(|| format!("could not presign {}", uri))?;
+        let response = attohttpc::get(&url)
+            .send()
+            .with_context(|| format!("could not download {}", uri))?;
+        if !response.is_success() {
             return Err(format_err!(
-                "could not upload {:?}: {}",
-                local_path.display(),
-                status,
+                "could not download {}: {}",
+                uri,
+                response.status()
             ));
         }
-        Ok(())
+        let (_status, _headers, reader) = response.split();
+        Ok(Box::new(reader))
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn open_write(&self, uri: &str) -> Result<Box<dyn ObjectWriter + '_>> {
+        let (bucket_name, key) = parse_s3_url(uri)?;
+        let bucket = self.bucket(bucket_name)?;
+        let key = key.to_owned();
+        Ok(Box::new(SpooledObjectWriter::new(move |path| {
+            self.upload_file(&bucket, path, &key)
+        })?))
     }
 }
 
+/// The extra HTTP headers we need to send when creating an object using
+/// `options`.
+fn upload_headers(options: &UploadOptions) -> Vec<(String, String)> {
+    let mut headers = vec![];
+    if let Some(sse) = options.sse {
+        let sse = match sse {
+            ServerSideEncryption::Aes256 => "AES256",
+            ServerSideEncryption::Kms => "aws:kms",
+        };
+        headers.push(("x-amz-server-side-encryption".to_owned(), sse.to_owned()));
+    }
+    if let Some(kms_key_id) = &options.kms_key_id {
+        headers.push((
+            "x-amz-server-side-encryption-aws-kms-key-id".to_owned(),
+            kms_key_id.to_owned(),
+        ));
+    }
+    if let Some(storage_class) = &options.storage_class {
+        headers.push(("x-amz-storage-class".to_owned(), storage_class.to_owned()));
+    }
+    for (key, value) in &options.metadata {
+        headers.push((
+            format!("x-amz-meta-{}", key.to_ascii_lowercase()),
+            value.to_owned(),
+        ));
+    }
+    headers
+}
+
 /// Parse an S3 URL.
 #[tracing::instrument(level = "trace")]
 fn parse_s3_url(url: &str) -> Result<(&str, &str)> {
@@ -215,24 +561,196 @@ fn url_parsing() {
     assert!(parse_s3_url("gs://foo/").is_err());
 }
 
-/// Local, `serde`-compatible reimplementation of
-/// [`rusoto_s3::ListObjectsV2Output`][rusoto].
-///
-/// [rusoto]:
-/// https://rusoto.github.io/rusoto/rusoto_s3/struct.ListObjectsV2Output.html
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "PascalCase")]
-struct ListObjectsV2Output {
-    #[serde(default)]
-    contents: Vec<Object>,
-    is_truncated: Option<bool>,
+#[test]
+fn builds_upload_headers() {
+    assert!(upload_headers(&UploadOptions::default()).is_empty());
+    let mut options = UploadOptions {
+        sse: Some(ServerSideEncryption::Kms),
+        kms_key_id: Some("alias/falconeri".to_owned()),
+        storage_class: Some("STANDARD_IA".to_owned()),
+        ..UploadOptions::default()
+    };
+    options
+        .metadata
+        .insert("Pipeline".to_owned(), "words".to_owned());
+    let headers = upload_headers(&options);
+    let headers = headers
+        .iter()
+        .map(|(name, value)| (&name[..], &value[..]))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        headers,
+        &[
+            ("x-amz-server-side-encryption", "aws:kms"),
+            (
+                "x-amz-server-side-encryption-aws-kms-key-id",
+                "alias/falconeri"
+            ),
+            ("x-amz-storage-class", "STANDARD_IA"),
+            ("x-amz-meta-pipeline", "words"),
+        ]
+    );
 }
 
-/// Local, `serde`-compatible reimplementation of [`rusoto_s3::Output`][rusoto].
+#[test]
+fn s3_config_lookup() {
+    use base64::{prelude::BASE64_STANDARD, Engine};
+
+    let no_env = |_: &str| None;
+    let config = S3Config::from_lookup(None, no_env).unwrap();
+    assert_eq!(config.endpoint_url, None);
+    assert_eq!(config.region, None);
+    assert!(!config.use_path_style());
+
+    // Secrets contain Base64-encoded values, and override the environment.
+    let secret_json = serde_json::json!({
+        "AWS_ACCESS_KEY_ID": BASE64_STANDARD.encode("key"),
+        "AWS_SECRET_ACCESS_KEY": BASE64_STANDARD.encode("secret"),
+        "AWS_ENDPOINT_URL": BASE64_STANDARD.encode("http://minio:9000"),
+    });
+    let secret_data: S3SecretData = serde_json::from_value(secret_json).unwrap();
+    let env = |var: &str| match var {
+        "AWS_ENDPOINT_URL" => Some("http://other:9000".to_owned()),
+        "AWS_DEFAULT_REGION" => Some("eu-west-1".to_owned()),
+        _ => None,
+    };
+    let config = S3Config::from_lookup(Some(&secret_data), env).unwrap();
+    assert_eq!(config.endpoint_url.as_deref(), Some("http://minio:9000"));
+    assert_eq!(config.region.as_deref(), Some("eu-west-1"));
+    assert!(config.use_path_style());
+
+    let env = |var: &str| match var {
+        "AWS_S3_ADDRESSING_STYLE" => Some("virtual".to_owned()),
+        _ => None,
+    };
+    let config = S3Config::from_lookup(Some(&secret_data), env).unwrap();
+    assert!(!config.use_path_style());
+
+    let env = |var: &str| match var {
+        "AWS_S3_ADDRESSING_STYLE" => Some("sideways".to_owned()),
+        _ => None,
+    };
+    assert!(S3Config::from_lookup(None, env).is_err());
+}
+
+/// Upload, list and download files using a real S3-compatible server. To run
+/// this against a local server like MinIO, use something like:
 ///
-/// [rusoto]: https://rusoto.github.io/rusoto/rusoto_s3/struct.Object.html
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "PascalCase")]
-struct Object {
-    key: String,
+/// ```sh
+/// AWS_ENDPOINT_URL=http://localhost:9000 AWS_ACCESS_KEY_ID=... \
+///     AWS_SECRET_ACCESS_KEY=... FALCONERI_TEST_S3_URI=s3://test-bucket/ \
+///     cargo test -p falconeri_common -- --ignored s3_round_trip
+/// ```
+#[test]
+#[ignore]
+fn s3_round_trip() {
+    let base_uri = env::var("FALCONERI_TEST_S3_URI")
+        .expect("FALCONERI_TEST_S3_URI should be set");
+    let uri = format!("{}{}/", base_uri, Uuid::new_v4());
+    let storage = S3Storage::new(&[]).unwrap();
+
+    // Build a local directory with a large file (to test multipart uploads) and
+    // more than 1,000 small files (to test paginated listing).
+    let local_dir = env::temp_dir().join(format!("falconeri-{}", Uuid::new_v4()));
+    let big = (0..MULTIPART_THRESHOLD + 12345)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    fs::create_dir_all(local_dir.join("small")).unwrap();
+    fs::write(local_dir.join("big.bin"), &big).unwrap();
+    for i in 0..1005 {
+        fs::write(local_dir.join(format!("small/{:04}.txt", i)), "hello").unwrap();
+    }
+    storage.sync_up(&local_dir, &uri).unwrap();
+
+    let objects = storage.list(&uri).unwrap();
+    assert_eq!(objects.len(), 1006);
+    let big_uri = format!("{}big.bin", uri);
+    let big_obj = objects.iter().find(|obj| obj.uri == big_uri).unwrap();
+    assert_eq!(big_obj.size, big.len() as u64);
+    assert!(big_obj.etag.is_some());
+    assert!(big_obj.updated_at.is_some());
+    let stat = storage.stat(&big_uri).unwrap().unwrap();
+    assert_eq!(stat.size, big_obj.size);
+    assert_eq!(stat.etag, big_obj.etag);
+
+    let download_dir = env::temp_dir().join(format!("falconeri-{}/", Uuid::new_v4()));
+    storage.sync_down(&uri, &download_dir).unwrap();
+    assert_eq!(fs::read(download_dir.join("big.bin")).unwrap(), big);
+    assert_eq!(
+        fs::read_to_string(download_dir.join("small/1004.txt")).unwrap(),
+        "hello",
+    );
+    let download_file = download_dir.join("copy.txt");
+    storage
+        .sync_down(&format!("{}small/0000.txt", uri), &download_file)
+        .unwrap();
+    assert_eq!(fs::read_to_string(download_file).unwrap(), "hello");
+
+    // Stream objects in and out, and delete them.
+    let new_uri = format!("{}new.bin", uri);
+    let mut writer = storage.open_write(&new_uri).unwrap();
+    writer.write_all(&big).unwrap();
+    writer.finish().unwrap();
+    let mut contents = vec![];
+    storage
+        .open_read(&new_uri)
+        .unwrap()
+        .read_to_end(&mut contents)
+        .unwrap();
+    assert_eq!(contents, big);
+    storage.delete(&new_uri).unwrap();
+    storage.delete(&new_uri).unwrap();
+    assert!(!storage.exists(&new_uri).unwrap());
+    assert!(storage.open_read(&new_uri).is_err());
+
+    fs::remove_dir_all(local_dir).unwrap();
+    fs::remove_dir_all(download_dir).unwrap();
+}
+
+/// Upload files with `UploadOptions` using a real S3-compatible server, and
+/// make sure the options were applied. This takes the same environment
+/// variables as `s3_round_trip`.
+#[test]
+#[ignore]
+fn s3_upload_options() {
+    let base_uri = env::var("FALCONERI_TEST_S3_URI")
+        .expect("FALCONERI_TEST_S3_URI should be set");
+    let uri = format!("{}{}/", base_uri, Uuid::new_v4());
+    let mut storage = S3Storage::new(&[]).unwrap();
+    let mut options = UploadOptions {
+        sse: Some(ServerSideEncryption::Aes256),
+        storage_class: Some("STANDARD_IA".to_owned()),
+        ..UploadOptions::default()
+    };
+    options
+        .metadata
+        .insert("pipeline".to_owned(), "words".to_owned());
+    storage.set_upload_options(options).unwrap();
+
+    // Upload both a small file and a multipart file.
+    let local_dir = env::temp_dir().join(format!("falconeri-{}", Uuid::new_v4()));
+    fs::create_dir_all(&local_dir).unwrap();
+    fs::write(local_dir.join("small.txt"), "hello").unwrap();
+    let big = vec![7; cast::usize(2 * MULTIPART_THRESHOLD + 1)];
+    fs::write(local_dir.join("big.bin"), &big).unwrap();
+    storage.sync_up(&local_dir, &uri).unwrap();
+
+    let (bucket_name, key) = parse_s3_url(&uri).unwrap();
+    let bucket = storage.bucket(bucket_name).unwrap();
+    for name in &["small.txt", "big.bin"] {
+        let (head, _) = bucket.head_object(format!("{}{}", key, name)).unwrap();
+        assert_eq!(head.server_side_encryption.as_deref(), Some("AES256"));
+        assert_eq!(head.storage_class.as_deref(), Some("STANDARD_IA"));
+        assert_eq!(head.metadata.unwrap()["pipeline"], "words");
+    }
+    let big_uri = format!("{}big.bin", uri);
+    assert_eq!(
+        storage.stat(&big_uri).unwrap().unwrap().size,
+        big.len() as u64
+    );
+
+    for obj in storage.list(&uri).unwrap() {
+        storage.delete(&obj.uri).unwrap();
+    }
+    fs::remove_dir_all(local_dir).unwrap();
 }
@@ -11,9 +11,14 @@
 use std::{panic::catch_unwind, process, thread, time::Duration};
 
 use falconeri_common::{
-    chrono, db, kubernetes::get_all_job_names, prelude::*, tracing,
+    chrono, db, kubernetes::get_all_job_names, prelude::*, storage::CloudStorage,
+    tracing,
 };
 
+/// How many times we try to promote a job's staged output files before we give
+/// up and mark the job as failed.
+const MAX_PROMOTION_FAILURES: i32 = 5;
+
 /// Spawn a thread and run the babysitter in it. This should run indefinitely.
 #[tracing::instrument(level = "trace")]
 pub fn start_babysitter() -> Result<thread::JoinHandle<()>> {
@@ -75,12 +80,16 @@ fn run_babysitter() {
 #[tracing::instrument(level = "debug")]
 fn check_running_jobs() -> Result<()> {
     let mut conn = db::connect(ConnectVia::Cluster)?;
+    // Atomic egress jobs stay running until we've promoted their output, so do
+    // this first, before we check whether they've vanished.
+    check_for_staged_output_files(&mut conn)?;
     check_for_finished_and_vanished_jobs(&mut conn)?;
     check_for_zombie_datums(&mut conn)?;
     // Note that any datums marked as `Status::Error` by
     // `check_for_zombie_datums` above may then be retried normally by
     // `check_for_datums_which_can_be_rerun` (if they're eligible).
-    check_for_datums_which_can_be_rerun(&mut conn)
+    check_for_datums_which_can_be_rerun(&mut conn)?;
+    check_for_expired_log_chunks(&mut conn)
 }
 
 /// Check for jobs which should already be marked as finished, or which have
@@ -121,29 +130,92 @@ fn check_for_finished_and_vanished_jobs(conn: &mut PgConnection) -> Result<()> {
     Ok(())
 }
 
-/// Check for datums which claim to be running in a pod that no longer exists.
+/// Check for jobs using atomic egress whose datums have all finished, but whose
+/// staged output files haven't been promoted yet, and mark them as done once
+/// we've promoted them. We do this outside of any transaction, because copying
+/// the files may take a while, and we can safely resume if we fail part way
+/// through.
+#[tracing::instrument(skip(conn), level = "debug")]
+fn check_for_staged_output_files(conn: &mut PgConnection) -> Result<()> {
+    let jobs = Job::find_ready_to_promote(conn)?;
+    for mut job in jobs {
+        // Don't let one job's problems keep us from promoting other jobs'
+        // output.
+        if let Err(err) = job.promote_staged_output_files(conn) {
+            let failures = job.record_promotion_failure(conn)?;
+            if failures >= MAX_PROMOTION_FAILURES {
+                error!(
+                    "could not promote staged output files for job {} after {} tries, marking job as failed: {}",
+                    job.job_name,
+                    failures,
+                    err.display_causes_and_backtrace()
+                );
+                // Another copy of the babysitter may have just finished
+                // promoting this job's output, so double-check.
+                conn.transaction(|conn| -> Result<()> {
+                    job.lock_for_update(conn)?;
+                    if job.status == Status::Running {
+                        job.mark_as_error(conn)?;
+                    }
+                    Ok(())
+                })?;
+            } else {
+                error!(
+                    "could not promote staged output files for job {} (will retry later): {}",
+                    job.job_name,
+                    err.display_causes_and_backtrace()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check for datums whose worker has stopped sending heartbeats, or which
+/// claim to be running in a pod that no longer exists.
 #[tracing::instrument(skip(conn), level = "debug")]
 fn check_for_zombie_datums(conn: &mut PgConnection) -> Result<()> {
     let zombies = Datum::zombies(conn)?;
     for mut zombie in zombies {
         // We may be racing a second copy of the babysitter here, so start a
         // transaction, take a lock, and double-check that our status is still
-        // `Status::Running`.
+        // `Status::Running`, and that the worker hasn't renewed its lease in the
+        // meantime.
         conn.transaction(|conn| -> Result<()> {
             zombie.lock_for_update(conn)?;
-            if zombie.status == Status::Running {
+            let has_lease = zombie.lease_expires_at.is_some();
+            let now = Utc::now().naive_utc();
+            if zombie.status != Status::Running {
+                warn!("someone beat us to zombie datum {}", zombie.id);
+            } else if has_lease && !zombie.lease_has_expired(now) {
+                debug!("worker renewed its lease on datum {}", zombie.id);
+            } else {
                 warn!(
                     "found zombie datum {}, which was supposed to be running on pod {:?}",
                     zombie.id, zombie.pod_name
                 );
+                let error_message = if has_lease {
+                    "worker stopped sending heartbeats while working on datum"
+                } else {
+                    "worker pod disappeared while working on datum"
+                };
+                // Keep whatever output the worker streamed to us before it
+                // disappeared.
+                let attempt = zombie.attempted_run_count;
+                let streamed_output =
+                    DatumLogChunk::inline_output_for_datum_attempt(&zombie, attempt, conn)?;
+                let (output, output_format) = match &streamed_output {
+                    Some(output) => (output.as_str(), OutputFormat::Tagged),
+                    None => ("(did not capture output)", OutputFormat::Plain),
+                };
                 zombie.mark_as_error(
-                    "(did not capture output)",
-                    "worker pod disappeared while working on datum",
+                    output,
+                    None,
+                    output_format,
+                    error_message,
                     "(no backtrace available)",
                     conn,
                 )?;
-            } else {
-                warn!("someone beat us to zombie datum {}", zombie.id);
             }
             Ok(())
         })?;
@@ -160,44 +232,122 @@ fn check_for_zombie_datums(conn: &mut PgConnection) -> Result<()> {
 fn check_for_datums_which_can_be_rerun(conn: &mut PgConnection) -> Result<()> {
     let rerunable_datums = Datum::rerunable(conn)?;
     for mut datum in rerunable_datums {
-        // We may be racing a second copy of the babysitter here, so start a
-        // transaction, take a lock, and double-check that we're still eligible
-        // for a re-run.
-        conn.transaction(|conn| -> Result<()> {
+        if let Err(err) = reschedule_datum(&mut datum, conn) {
+            error!(
+                "could not reschedule datum {} (will retry later): {}",
+                datum.id,
+                err.display_causes_and_backtrace()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Clean up after a failed attempt to process `datum`, and make it eligible
+/// to be run again.
+#[tracing::instrument(skip(conn), level = "debug")]
+fn reschedule_datum(datum: &mut Datum, conn: &mut PgConnection) -> Result<()> {
+    // Workers create `OutputFile` records before they start uploading, so
+    // these records describe every file which an earlier attempt might have
+    // uploaded. Delete those files, so that outputs with random names don't
+    // pile up in the bucket.
+    //
+    // We do this outside of any transaction, because cloud storage may be
+    // slow, and we don't want to hold a lock on the datum while we wait.
+    // Deleting files twice is harmless, so it's fine if another copy of the
+    // babysitter does the same thing. If we can't delete the files, we leave
+    // the datum alone and try again next time.
+    let output_files = OutputFile::for_datum(datum, conn)?;
+    if !output_files.is_empty() {
+        let job = Job::find(datum.job_id, conn)?;
+        let storage = job.egress_storage()?;
+        delete_output_objects(&*storage, &output_files)?;
+    }
+
+    // We may be racing a second copy of the babysitter here, so start a
+    // transaction, take a lock, and double-check that we're still eligible
+    // for a re-run.
+    conn.transaction(|conn| -> Result<()> {
+        datum.lock_for_update(conn)?;
+        if datum.is_rerunable() {
+            warn!(
+                "rescheduling errored datum {} (previously on try {}/{})",
+                datum.id, datum.attempted_run_count, datum.maximum_allowed_run_count
+            );
+
+            // Delete the records of the files we just deleted, so we can
+            // upload the same output files again.
+            OutputFile::delete_ids(
+                &output_files.iter().map(|f| f.id).collect::<Vec<_>>(),
+                conn,
+            )?;
+
             // Mark our datum as re-runnable.
-            datum.lock_for_update(conn)?;
-            if datum.is_rerunable() {
-                warn!(
-                    "rescheduling errored datum {} (previously on try {}/{})",
-                    datum.id,
-                    datum.attempted_run_count,
-                    datum.maximum_allowed_run_count
-                );
-                datum.mark_as_eligible_for_rerun(conn)?;
-            } else {
-                warn!("someone beat us to rerunable datum {}", datum.id);
-            }
+            datum.mark_as_eligible_for_rerun(conn)?;
+        } else {
+            warn!("someone beat us to rerunable datum {}", datum.id);
+        }
+        Ok(())
+    })
+}
 
-            // Remove `OutputFile` records for this datum, so we can upload the
-            // same output files again.
-            //
-            // TODO: Unfortunately, there's an issue here. It takes one of two
-            // forms:
-            //
-            // 1. Workers use deterministic file names. In this case, we
-            //    _should_ be fine, because we'll just overwrite any files we
-            //    did manage to upload.
-            // 2. Workers use random filenames. Here, there are two subcases: a.
-            //    We have successfully created an `OutputFile` record. b. We
-            //    have yet to create an `OutputFile` record.
-            //
-            // We need to fix (2b) by pre-creating all our `OutputFile` records
-            // _before_ uploading, and then updating them later to show that the
-            // output succeeded. Which them into case (2a). And then we can fix (2a)
-            // by deleting any S3/GCS files corresponding to `OutputFile::uri`.
-            OutputFile::delete_for_datum(&datum, conn)?;
-            Ok(())
+/// Delete the output streamed by workers for attempts which finished a while
+/// ago.
+#[tracing::instrument(skip(conn), level = "debug")]
+fn check_for_expired_log_chunks(conn: &mut PgConnection) -> Result<()> {
+    let count = DatumLogChunk::delete_expired(conn)?;
+    if count > 0 {
+        debug!("deleted {} expired log chunks", count);
+    }
+    Ok(())
+}
+
+/// Delete the objects described by `output_files` from `storage`. It's fine if
+/// some of them were never uploaded.
+#[tracing::instrument(skip(storage, output_files), level = "debug")]
+fn delete_output_objects(
+    storage: &dyn CloudStorage,
+    output_files: &[OutputFile],
+) -> Result<()> {
+    for output_file in output_files {
+        debug!(
+            "deleting {} uploaded by datum {}",
+            output_file.uri, output_file.datum_id
+        );
+        storage.delete(&output_file.uri).with_context(|| {
+            format!("could not delete old output {}", output_file.uri)
         })?;
     }
     Ok(())
 }
+
+#[test]
+fn deletes_output_objects() {
+    use falconeri_common::storage::mem::MemoryStorage;
+
+    let job = Job::factory();
+    let datum = Datum::factory(&job);
+    let output_file = |uri: &str| {
+        let mut output_file = OutputFile::factory(&datum);
+        output_file.status = Status::Running;
+        output_file.uri = uri.to_owned();
+        output_file
+    };
+    MemoryStorage::put("mem://babysitter-outputs/out/a1b2.csv", b"partial");
+    MemoryStorage::put("mem://babysitter-outputs/out/keep.csv", b"other datum");
+    let output_files = vec![
+        output_file("mem://babysitter-outputs/out/a1b2.csv"),
+        // This upload never started.
+        output_file("mem://babysitter-outputs/out/c3d4.csv"),
+    ];
+
+    let storage =
+        <dyn CloudStorage>::for_uri("mem://babysitter-outputs/", &[]).unwrap();
+    delete_output_objects(&*storage, &output_files).unwrap();
+    assert_eq!(
+        MemoryStorage::uris_with_prefix("mem://babysitter-outputs/"),
+        &["mem://babysitter-outputs/out/keep.csv"],
+    );
+
+    MemoryStorage::clear("mem://babysitter-outputs/");
+}
@@ -1,11 +1,14 @@
 //! Convert JSON `"input"` clauses to datums which will be assigned to workers.
 
+use std::collections::{BTreeMap, HashSet};
+
 use falconeri_common::{
+    compression::Compression,
     models::{NewDatum, NewInputFile},
-    pipeline::{Glob, Input},
+    pipeline::{DatumSetSpec, Glob, Input},
     prelude::*,
     secret::Secret,
-    storage::CloudStorage,
+    storage::{CloudStorage, ObjectMetadata},
 };
 
 /// (Local helper type.) This is essentially just a `NewDatum` and a
@@ -15,6 +18,7 @@ use falconeri_common::{
 #[derive(Clone, Debug)]
 struct DatumData {
     input_files: Vec<InputFileData>,
+    group_key: Option<String>,
 }
 
 impl DatumData {
@@ -29,6 +33,7 @@ impl DatumData {
             id: datum_id,
             job_id,
             maximum_allowed_run_count,
+            group_key: self.group_key,
         };
         let input_files = self
             .input_files
@@ -45,6 +50,14 @@ impl DatumData {
 struct InputFileData {
     uri: String,
     local_path: String,
+    /// The size of this file (or the contents of this directory), in bytes.
+    /// Only used for packing datums, and not stored in the database.
+    size: u64,
+    /// The secrets specified by the `Input::Atom` containing this file, which
+    /// the worker will need to download it.
+    secrets: Vec<Secret>,
+    /// Should the worker decompress this file as it downloads it?
+    decompress: bool,
 }
 
 impl InputFileData {
@@ -55,6 +68,8 @@ impl InputFileData {
             datum_id,
             uri: self.uri,
             local_path: self.local_path,
+            secrets: serde_json::json!(self.secrets),
+            decompress: self.decompress,
         }
     }
 }
@@ -62,6 +77,9 @@ impl InputFileData {
 /// Given an `Input` from a JSON pipeline spec, convert to an actual set of
 /// "datums" (work chunks) to be assigned to a worker.
 ///
+/// If `datum_set_spec` is specified, we use it to pack several of the datums
+/// computed from `input` into a single datum.
+///
 /// Returns the datums and associated input files in a form well-suited to bulk
 /// database insert.
 pub fn input_to_datums(
@@ -69,10 +87,16 @@ pub fn input_to_datums(
     job_id: Uuid,
     maximum_allowed_run_count: i32,
     input: &Input,
+    datum_set_spec: Option<&DatumSetSpec>,
 ) -> Result<(Vec<NewDatum>, Vec<NewInputFile>)> {
+    let mut datums = input_to_datums_helper(secrets, input)?;
+    if let Some(datum_set_spec) = datum_set_spec {
+        datums = pack_datums(datums, datum_set_spec)?;
+    }
+
     let mut all_datums = vec![];
     let mut all_input_files = vec![];
-    for datum_data in input_to_datums_helper(secrets, input)? {
+    for datum_data in datums {
         let (datum, input_files) = datum_data
             .into_new_datum_and_input_files(job_id, maximum_allowed_run_count);
         all_datums.push(datum);
@@ -91,8 +115,15 @@ fn input_to_datums_helper(
     input: &Input,
 ) -> Result<Vec<DatumData>> {
     match input {
-        Input::Atom { uri, repo, glob } => {
-            atom_to_datums_helper(secrets, uri, repo, *glob)
+        Input::Atom {
+            uri,
+            repo,
+            glob,
+            secrets: atom_secrets,
+            decompress,
+            ..
+        } => {
+            atom_to_datums_helper(secrets, atom_secrets, uri, repo, glob, *decompress)
         }
         Input::Cross(inputs) => cross_to_datums_helper(secrets, inputs),
         Input::Union(inputs) => {
@@ -105,55 +136,305 @@ fn input_to_datums_helper(
             }
             Ok(datums)
         }
+        Input::Join(inputs) => join_to_datums_helper(secrets, inputs),
+        Input::Group(inputs) => group_to_datums_helper(secrets, inputs),
     }
 }
 
 /// Convert a single `Input::Atom` to a list of datums.
 fn atom_to_datums_helper(
     secrets: &[Secret],
+    atom_secrets: &[Secret],
     uri: &str,
     repo: &str,
-    glob: Glob,
+    glob: &Glob,
+    decompress: bool,
 ) -> Result<Vec<DatumData>> {
-    // Normalize our URI to always include a slash, because repositories must
-    // currently be directories.
-    let mut base = uri.to_owned();
-    if !base.ends_with('/') {
-        base.push('/');
-    }
-
-    // Figure out what files to process. We do this for _both_
-    // `Glob::TopLevelDirectoryEntries` and `Glob::WholeRepo`, because we want
-    // to verify that we can actually list the contents of a `Glob::WholeRepo`
-    // _before_ spinning up a big cluster job.
-    let storage = <dyn CloudStorage>::for_uri(uri, secrets)?;
-    let file_uris = storage.list(uri)?;
-
-    match glob {
-        // Our input file is just the entire repo, as a directory.
-        Glob::WholeRepo => Ok(vec![DatumData {
-            input_files: vec![InputFileData {
-                uri: base,
-                local_path: format!("/pfs/{}/", repo),
-            }],
-        }]),
-
-        // Each top-level file or directory in `base` should be translated into
-        // a separate datum.
-        Glob::TopLevelDirectoryEntries => {
-            let mut datums = vec![];
-            for file_uri in file_uris {
-                let local_path = uri_to_local_path(uri, &file_uri, repo)?;
-                datums.push(DatumData {
-                    input_files: vec![InputFileData {
-                        uri: file_uri,
-                        local_path,
-                    }],
-                });
+    Ok(
+        atom_matches(secrets, atom_secrets, uri, repo, glob, decompress, None)?
+            .into_iter()
+            .map(|(input_file, _key)| DatumData {
+                input_files: vec![input_file],
+                group_key: None,
+            })
+            .collect(),
+    )
+}
+
+/// Find all the files and directories in an `Input::Atom` which match `glob`.
+/// If `key_template` is specified, also compute a key for each match.
+///
+/// We list the files using `atom_secrets` if present, and our pipeline-wide
+/// `secrets` otherwise. If `decompress` is set, we strip any compression
+/// extension from the local paths of matching files, because the worker will
+/// decompress them as it downloads them.
+fn atom_matches(
+    secrets: &[Secret],
+    atom_secrets: &[Secret],
+    uri: &str,
+    repo: &str,
+    glob: &Glob,
+    decompress: bool,
+    key_template: Option<&str>,
+) -> Result<Vec<(InputFileData, Option<String>)>> {
+    // Normalize our URI to always include a slash, because repositories are
+    // normally directories. But HTTP(S) servers can't list a directory without
+    // a manifest, so there, a URI without a slash names a single file, and we
+    // match it as if it were the only file in its parent directory.
+    let single_file = !uri.ends_with('/')
+        && (uri.starts_with("http://") || uri.starts_with("https://"));
+    let base = if single_file {
+        uri[..=uri.rfind('/').expect("http URI should contain '/'")].to_owned()
+    } else if uri.ends_with('/') {
+        uri.to_owned()
+    } else {
+        format!("{}/", uri)
+    };
+
+    // Figure out what files to process. We do this for _all_ globs, including
+    // `Glob::WholeRepo`, because we want to verify that we can actually list
+    // the contents of a `Glob::WholeRepo` _before_ spinning up a big cluster
+    // job.
+    let list_secrets = if atom_secrets.is_empty() {
+        secrets
+    } else {
+        atom_secrets
+    };
+    let storage = <dyn CloudStorage>::for_uri(uri, list_secrets)?;
+    let objects = storage.list(if single_file { uri } else { &base })?;
+
+    let mut matches = vec![];
+    for mut glob_match in glob_matches(&base, &objects, glob, key_template)? {
+        if single_file && glob_match.uri == base {
+            // Our "entire repo" is just our one file.
+            glob_match.uri = uri.to_owned();
+        }
+        let local_path = if glob_match.uri == base {
+            // Our input file is just the entire repo, as a directory.
+            format!("/pfs/{}/", repo)
+        } else {
+            let local_path = uri_to_local_path(&base, &glob_match.uri, repo)?;
+            match Compression::split_extension(&local_path) {
+                Some((_, stripped)) if decompress => stripped.to_owned(),
+                _ => local_path,
             }
-            Ok(datums)
+        };
+        let input_file = InputFileData {
+            uri: glob_match.uri,
+            local_path,
+            size: glob_match.size,
+            secrets: atom_secrets.to_vec(),
+            decompress,
+        };
+        matches.push((input_file, glob_match.key));
+    }
+    Ok(matches)
+}
+
+/// (Local helper type.) A file or directory which matched a `Glob`.
+#[derive(Clone, Debug, PartialEq)]
+struct GlobMatch {
+    /// The URI of the matching file or directory. Directories end in `/`.
+    uri: String,
+    /// A key built from the glob's capture groups, if we were given a
+    /// template.
+    key: Option<String>,
+    /// The total size of the matching file or directory, in bytes.
+    size: u64,
+}
+
+/// Given all the files stored under `base_uri` (recursively), find the files
+/// and directories which match `glob`.
+///
+/// We match `glob` against the path of each file relative to `base_uri`, and
+/// against the paths of all the directories containing it. If a directory
+/// matches, we return the directory (with a trailing `/`) instead of the
+/// files it contains. The returned matches are sorted by URI and contain no
+/// duplicates. `Glob::WholeRepo` always matches `base_uri` itself. The size of
+/// a directory is the total size of all the files it contains.
+///
+/// If `key_template` is specified, we expand it using the capture groups in
+/// `glob` (as in `"$1"` or `"${1}_${2}"`), and include it in each match.
+fn glob_matches(
+    base_uri: &str,
+    objects: &[ObjectMetadata],
+    glob: &Glob,
+    key_template: Option<&str>,
+) -> Result<Vec<GlobMatch>> {
+    let re = glob.to_regex()?;
+    let expand_key = |path: &str| -> Option<String> {
+        let template = key_template?;
+        let caps = re.captures(path)?;
+        let mut key = String::new();
+        caps.expand(template, &mut key);
+        Some(key)
+    };
+
+    if *glob == Glob::WholeRepo {
+        return Ok(vec![GlobMatch {
+            uri: base_uri.to_owned(),
+            key: expand_key("/"),
+            size: objects.iter().map(|obj| obj.size).sum(),
+        }]);
+    }
+
+    let mut matches = BTreeMap::<String, (Option<String>, u64)>::new();
+    for obj in objects {
+        let rel_path = obj.uri.strip_prefix(base_uri).ok_or_else(|| {
+            format_err!("expected {} to be in {}", obj.uri, base_uri)
+        })?;
+
+        // Check each directory containing our file, outermost first, and then
+        // the file itself. Some storage backends return placeholder objects
+        // for directories, which will end in `/`, and we treat those as just
+        // another directory.
+        let file_end = if rel_path.ends_with('/') {
+            None
+        } else {
+            Some(rel_path.len())
+        };
+        let candidate_ends = rel_path
+            .match_indices('/')
+            .map(|(idx, _)| idx)
+            .chain(file_end);
+        for end in candidate_ends {
+            let candidate = format!("/{}", &rel_path[..end]);
+            if end == 0 || !re.is_match(&candidate) {
+                continue;
+            }
+            let mut matched_uri = format!("{}{}", base_uri, &rel_path[..end]);
+            if end < rel_path.len() {
+                matched_uri.push('/');
+            }
+            let entry = matches
+                .entry(matched_uri)
+                .or_insert_with(|| (expand_key(&candidate), 0));
+            entry.1 += obj.size;
+            break;
         }
     }
+    Ok(matches
+        .into_iter()
+        .map(|(uri, (key, size))| GlobMatch { uri, key, size })
+        .collect())
+}
+
+/// Convert an inner join into a list of datums.
+fn join_to_datums_helper(
+    secrets: &[Secret],
+    inputs: &[Input],
+) -> Result<Vec<DatumData>> {
+    let mut keyed_inputs = vec![];
+    for input in inputs {
+        match input {
+            Input::Atom {
+                uri,
+                repo,
+                glob,
+                join_on: Some(join_on),
+                secrets: atom_secrets,
+                decompress,
+                ..
+            } => {
+                let mut files_by_key = BTreeMap::<_, Vec<_>>::new();
+                for (input_file, key) in atom_matches(
+                    secrets,
+                    atom_secrets,
+                    uri,
+                    repo,
+                    glob,
+                    *decompress,
+                    Some(join_on),
+                )? {
+                    let key = key.expect("should always have a key with a template");
+                    files_by_key.entry(key).or_default().push(input_file);
+                }
+                keyed_inputs.push(files_by_key);
+            }
+            _ => {
+                return Err(format_err!(
+                    "every input in a join must be an atom with join_on"
+                ))
+            }
+        }
+    }
+    Ok(join_keyed_inputs(&keyed_inputs))
+}
+
+/// Given the files in each input of a join, grouped by join key, build one
+/// datum for each key which appears in every input.
+fn join_keyed_inputs(
+    keyed_inputs: &[BTreeMap<String, Vec<InputFileData>>],
+) -> Vec<DatumData> {
+    let (first, rest) = match keyed_inputs.split_first() {
+        Some(split) => split,
+        None => return vec![],
+    };
+    let mut datums = vec![];
+    for (key, files) in first {
+        if !rest
+            .iter()
+            .all(|files_by_key| files_by_key.contains_key(key))
+        {
+            trace!("join key {:?} is missing from some inputs", key);
+            continue;
+        }
+        let mut input_files = files.clone();
+        for files_by_key in rest {
+            input_files.extend(files_by_key[key].iter().cloned());
+        }
+        datums.push(DatumData {
+            input_files,
+            group_key: None,
+        });
+    }
+    datums
+}
+
+/// Convert a group into a list of datums.
+fn group_to_datums_helper(
+    secrets: &[Secret],
+    inputs: &[Input],
+) -> Result<Vec<DatumData>> {
+    let mut files_by_key = BTreeMap::<_, Vec<_>>::new();
+    for input in inputs {
+        match input {
+            Input::Atom {
+                uri,
+                repo,
+                glob,
+                group_by: Some(group_by),
+                secrets: atom_secrets,
+                decompress,
+                ..
+            } => {
+                for (input_file, key) in atom_matches(
+                    secrets,
+                    atom_secrets,
+                    uri,
+                    repo,
+                    glob,
+                    *decompress,
+                    Some(group_by),
+                )? {
+                    let key = key.expect("should always have a key with a template");
+                    files_by_key.entry(key).or_default().push(input_file);
+                }
+            }
+            _ => {
+                return Err(format_err!(
+                    "every input in a group must be an atom with group_by"
+                ))
+            }
+        }
+    }
+    Ok(files_by_key
+        .into_iter()
+        .map(|(key, input_files)| DatumData {
+            input_files,
+            group_key: Some(key),
+        })
+        .collect())
 }
 
 /// Convert a cross product into a list of datums.
@@ -193,6 +474,10 @@ fn cross_to_datums_helper(
                     combined.extend(input_files_1.iter().cloned());
                     output.push(DatumData {
                         input_files: combined,
+                        group_key: combine_group_keys(
+                            &datum_0.group_key,
+                            &datum_1.group_key,
+                        ),
                     })
                 }
             }
@@ -201,6 +486,78 @@ fn cross_to_datums_helper(
     }
 }
 
+/// When building a cross product, combine the group keys of two datums.
+fn combine_group_keys(
+    key_0: &Option<String>,
+    key_1: &Option<String>,
+) -> Option<String> {
+    match (key_0, key_1) {
+        (Some(key_0), Some(key_1)) => Some(format!("{},{}", key_0, key_1)),
+        (Some(key), None) | (None, Some(key)) => Some(key.to_owned()),
+        (None, None) => None,
+    }
+}
+
+/// Pack `datums` into larger datums, according to `datum_set_spec`.
+///
+/// We keep the datums in order, and we only include each local path once per
+/// packed datum, since a cross product may contain the same file many times.
+fn pack_datums(
+    datums: Vec<DatumData>,
+    datum_set_spec: &DatumSetSpec,
+) -> Result<Vec<DatumData>> {
+    if datum_set_spec.number == Some(0) {
+        return Err(format_err!("datum_set_spec.number must be at least 1"));
+    }
+    let max_number = datum_set_spec.number.map(u64::from).unwrap_or(u64::MAX);
+    let max_size = datum_set_spec.size_bytes.unwrap_or(u64::MAX);
+
+    let mut packed = vec![];
+    let mut current: Option<DatumData> = None;
+    let mut current_number = 0;
+    let mut current_size = 0u64;
+    let mut current_paths = HashSet::new();
+    for datum in datums {
+        // Only count the size of files we haven't already included.
+        let new_size = datum
+            .input_files
+            .iter()
+            .filter(|f| !current_paths.contains(&f.local_path))
+            .map(|f| f.size)
+            .sum::<u64>();
+
+        // Start a new datum if this one won't fit.
+        if current.is_some()
+            && (current_number >= max_number
+                || current_size.saturating_add(new_size) > max_size)
+        {
+            packed.extend(current.take());
+            current_number = 0;
+            current_size = 0;
+            current_paths.clear();
+        }
+
+        let batch = current.get_or_insert_with(|| DatumData {
+            input_files: vec![],
+            group_key: None,
+        });
+        if current_number == 0 {
+            batch.group_key = datum.group_key;
+        } else {
+            batch.group_key = combine_group_keys(&batch.group_key, &datum.group_key);
+        }
+        for input_file in datum.input_files {
+            if current_paths.insert(input_file.local_path.clone()) {
+                current_size = current_size.saturating_add(input_file.size);
+                batch.input_files.push(input_file);
+            }
+        }
+        current_number += 1;
+    }
+    packed.extend(current);
+    Ok(packed)
+}
+
 /// Given a URI and a repo name, construct a local path starting with "/pfs"
 /// pointing to where we should download the file.
 fn uri_to_local_path(base_uri: &str, uri: &str, repo: &str) -> Result<String> {
@@ -235,3 +592,488 @@ fn uri_to_local_path_works() {
             .unwrap();
     assert_eq!(dpath, "/pfs/myrepo/data1/");
 }
+
+#[test]
+fn glob_matches_works() {
+    use std::convert::TryFrom;
+
+    let base = "gs://bucket/path/";
+    let objects = [
+        "gs://bucket/path/2023-12/a.csv",
+        "gs://bucket/path/2024-01/a.csv",
+        "gs://bucket/path/2024-01/b.tsv",
+        "gs://bucket/path/2024-01/nested/c.csv",
+        "gs://bucket/path/2024-02/",
+        "gs://bucket/path/top.parquet",
+        "gs://bucket/path/2024-02/d.parquet",
+    ]
+    .iter()
+    .map(|&s| ObjectMetadata::new(s, 10))
+    .collect::<Vec<_>>();
+    let check = |pattern: &str, expected: &[&str]| {
+        let glob = Glob::try_from(pattern.to_owned()).unwrap();
+        let matches = glob_matches(base, &objects, &glob, None)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.uri)
+            .collect::<Vec<_>>();
+        let expected = expected
+            .iter()
+            .map(|rel| format!("{}{}", base, rel))
+            .collect::<Vec<_>>();
+        assert_eq!(matches, expected, "matching {:?}", pattern);
+    };
+
+    check("/*", &["2023-12/", "2024-01/", "2024-02/", "top.parquet"]);
+    check(
+        "/*/*",
+        &[
+            "2023-12/a.csv",
+            "2024-01/a.csv",
+            "2024-01/b.tsv",
+            "2024-01/nested/",
+            "2024-02/d.parquet",
+        ],
+    );
+    check("/2024-*/*.csv", &["2024-01/a.csv"]);
+    check("/**.parquet", &["2024-02/d.parquet", "top.parquet"]);
+    let sizes = glob_matches(base, &objects, &Glob::TopLevelDirectoryEntries, None)
+        .unwrap()
+        .into_iter()
+        .map(|m| m.size)
+        .collect::<Vec<_>>();
+    assert_eq!(sizes, &[10, 30, 20, 10]);
+    assert_eq!(
+        glob_matches(base, &objects, &Glob::WholeRepo, None).unwrap()[0].size,
+        70,
+    );
+    assert!(glob_matches(
+        "gs://other/",
+        &objects,
+        &Glob::TopLevelDirectoryEntries,
+        None
+    )
+    .is_err());
+}
+
+#[test]
+fn glob_matches_computes_keys() {
+    use std::convert::TryFrom;
+
+    let base = "s3://bucket/";
+    let objects = ["s3://bucket/2024/01/a.csv", "s3://bucket/2024/02/b.csv"]
+        .iter()
+        .map(|&s| ObjectMetadata::new(s, 5))
+        .collect::<Vec<_>>();
+    let glob = Glob::try_from("/(*)/(*)/*.csv".to_owned()).unwrap();
+    let matches = glob_matches(base, &objects, &glob, Some("${1}-$2")).unwrap();
+    assert_eq!(
+        matches,
+        vec![
+            GlobMatch {
+                uri: "s3://bucket/2024/01/a.csv".to_owned(),
+                key: Some("2024-01".to_owned()),
+                size: 5,
+            },
+            GlobMatch {
+                uri: "s3://bucket/2024/02/b.csv".to_owned(),
+                key: Some("2024-02".to_owned()),
+                size: 5,
+            },
+        ]
+    );
+}
+
+#[test]
+fn join_keyed_inputs_works() {
+    let file = |uri: &str| InputFileData {
+        uri: uri.to_owned(),
+        local_path: uri.replace("gs://bucket/", "/pfs/"),
+        size: 0,
+        secrets: vec![],
+        decompress: false,
+    };
+    let mut books = BTreeMap::new();
+    books.insert("a".to_owned(), vec![file("gs://bucket/books/a.txt")]);
+    books.insert("b".to_owned(), vec![file("gs://bucket/books/b.txt")]);
+    let mut metadata = BTreeMap::new();
+    metadata.insert(
+        "a".to_owned(),
+        vec![
+            file("gs://bucket/metadata/a.json"),
+            file("gs://bucket/metadata/extra/a.json"),
+        ],
+    );
+    metadata.insert("c".to_owned(), vec![file("gs://bucket/metadata/c.json")]);
+
+    let datums = join_keyed_inputs(&[books, metadata]);
+    assert_eq!(datums.len(), 1);
+    let uris = datums[0]
+        .input_files
+        .iter()
+        .map(|f| &f.uri[..])
+        .collect::<Vec<_>>();
+    assert_eq!(
+        uris,
+        &[
+            "gs://bucket/books/a.txt",
+            "gs://bucket/metadata/a.json",
+            "gs://bucket/metadata/extra/a.json",
+        ]
+    );
+    assert!(join_keyed_inputs(&[]).is_empty());
+}
+
+#[test]
+fn pack_datums_works() {
+    let datum = |names: &[&str], size: u64| DatumData {
+        input_files: names
+            .iter()
+            .map(|name| InputFileData {
+                uri: format!("gs://bucket/{}", name),
+                local_path: format!("/pfs/{}", name),
+                size,
+                secrets: vec![],
+                decompress: false,
+            })
+            .collect(),
+        group_key: None,
+    };
+    let datums = vec![
+        datum(&["a"], 10),
+        datum(&["b"], 10),
+        datum(&["c"], 30),
+        datum(&["d"], 10),
+        datum(&["e"], 10),
+    ];
+    let local_paths = |packed: &[DatumData]| {
+        packed
+            .iter()
+            .map(|d| {
+                d.input_files
+                    .iter()
+                    .map(|f| f.local_path.clone())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let by_number = DatumSetSpec {
+        number: Some(2),
+        size_bytes: None,
+    };
+    let packed = pack_datums(datums.clone(), &by_number).unwrap();
+    assert_eq!(
+        local_paths(&packed),
+        &["/pfs/a /pfs/b", "/pfs/c /pfs/d", "/pfs/e"],
+    );
+
+    let by_size = DatumSetSpec {
+        number: None,
+        size_bytes: Some(25),
+    };
+    let packed = pack_datums(datums.clone(), &by_size).unwrap();
+    assert_eq!(
+        local_paths(&packed),
+        &["/pfs/a /pfs/b", "/pfs/c", "/pfs/d /pfs/e"],
+    );
+
+    // Files shared between datums (as in a cross product) are only included
+    // and counted once.
+    let crossed = vec![
+        datum(&["x", "a"], 10),
+        datum(&["x", "b"], 10),
+        datum(&["x", "c"], 10),
+    ];
+    let packed = pack_datums(crossed, &by_size).unwrap();
+    assert_eq!(
+        local_paths(&packed),
+        &["/pfs/x /pfs/a", "/pfs/x /pfs/b", "/pfs/x /pfs/c"]
+    );
+    let both = DatumSetSpec {
+        number: Some(3),
+        size_bytes: Some(40),
+    };
+    let crossed = vec![
+        datum(&["x", "a"], 10),
+        datum(&["x", "b"], 10),
+        datum(&["x", "c"], 10),
+    ];
+    let packed = pack_datums(crossed, &both).unwrap();
+    assert_eq!(local_paths(&packed), &["/pfs/x /pfs/a /pfs/b /pfs/c"]);
+
+    let zero = DatumSetSpec {
+        number: Some(0),
+        size_bytes: None,
+    };
+    assert!(pack_datums(datums, &zero).is_err());
+}
+
+#[test]
+fn input_to_datums_works_with_local_files() {
+    use std::fs;
+
+    let root = std::env::temp_dir().join(format!("falconeri-{}", Uuid::new_v4()));
+    fs::create_dir_all(root.join("books/nested")).unwrap();
+    fs::create_dir_all(root.join("categories")).unwrap();
+    fs::write(root.join("books/a.txt"), "a").unwrap();
+    fs::write(root.join("books/nested/b.txt"), "b").unwrap();
+    fs::write(root.join("categories/dewey.csv"), "c").unwrap();
+
+    let json = serde_json::json!({
+        "cross": [{
+            "atom": {
+                "URI": format!("file://{}/books/", root.display()),
+                "repo": "books",
+                "glob": "/*",
+            }
+        }, {
+            "atom": {
+                "URI": format!("file://{}/categories/", root.display()),
+                "repo": "categories",
+                "glob": "/",
+                "secrets": [{ "name": "categories", "mount_path": "/categories" }],
+            }
+        }]
+    });
+    let input: Input = serde_json::from_value(json).unwrap();
+    let job_id = Uuid::new_v4();
+    let (datums, input_files) = input_to_datums(&[], job_id, 2, &input, None).unwrap();
+    assert_eq!(datums.len(), 2);
+    assert!(datums.iter().all(|d| d.maximum_allowed_run_count == 2));
+    let mut local_paths = input_files
+        .iter()
+        .map(|f| (f.datum_id, f.local_path.clone()))
+        .collect::<Vec<_>>();
+    local_paths.sort();
+    let mut expected = vec![];
+    for datum in &datums {
+        let files = local_paths
+            .iter()
+            .filter(|(id, _)| *id == datum.id)
+            .map(|(_, path)| &path[..])
+            .collect::<Vec<_>>();
+        expected.push(files.join(" "));
+    }
+    expected.sort();
+    assert_eq!(
+        expected,
+        &[
+            "/pfs/books/a.txt /pfs/categories/",
+            "/pfs/books/nested/ /pfs/categories/",
+        ],
+    );
+    for input_file in &input_files {
+        let expected_secrets = if input_file.local_path == "/pfs/categories/" {
+            serde_json::json!([{ "name": "categories", "mount_path": "/categories" }])
+        } else {
+            serde_json::json!([])
+        };
+        assert_eq!(input_file.secrets, expected_secrets);
+    }
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn cross_to_datums_helper_works() {
+    use falconeri_common::storage::mem::MemoryStorage;
+
+    MemoryStorage::put("mem://cross/books/a.txt", "a");
+    MemoryStorage::put("mem://cross/books/b.txt", "b");
+    MemoryStorage::put("mem://cross/more-books/c.txt", "c");
+    MemoryStorage::put("mem://cross/langs/en/words.txt", "en");
+    MemoryStorage::put("mem://cross/langs/fr/words.txt", "fr");
+    let json = serde_json::json!([{
+        "union": [{
+            "atom": {
+                "URI": "mem://cross/books/",
+                "repo": "books",
+                "glob": "/*",
+            }
+        }, {
+            "atom": {
+                "URI": "mem://cross/more-books/",
+                "repo": "books",
+                "glob": "/*",
+            }
+        }]
+    }, {
+        "atom": {
+            "URI": "mem://cross/langs/",
+            "repo": "langs",
+            "glob": "/*",
+        }
+    }]);
+    let inputs: Vec<Input> = serde_json::from_value(json).unwrap();
+    let datums = cross_to_datums_helper(&[], &inputs).unwrap();
+    let local_paths = datums
+        .iter()
+        .map(|d| {
+            d.input_files
+                .iter()
+                .map(|f| &f.local_path[..])
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        local_paths,
+        &[
+            "/pfs/books/a.txt /pfs/langs/en/",
+            "/pfs/books/a.txt /pfs/langs/fr/",
+            "/pfs/books/b.txt /pfs/langs/en/",
+            "/pfs/books/b.txt /pfs/langs/fr/",
+            "/pfs/books/c.txt /pfs/langs/en/",
+            "/pfs/books/c.txt /pfs/langs/fr/",
+        ],
+    );
+    assert!(cross_to_datums_helper(&[], &[]).unwrap().is_empty());
+    MemoryStorage::clear("mem://cross/");
+}
+
+#[test]
+fn input_to_datums_groups_and_packs() {
+    use falconeri_common::storage::mem::MemoryStorage;
+
+    for name in &[
+        "2024-01-01-00",
+        "2024-01-01-01",
+        "2024-01-02-00",
+        "2024-01-03-00",
+    ] {
+        MemoryStorage::put(&format!("mem://packing/hourly/{}.csv", name), "data");
+    }
+    let json = serde_json::json!({
+        "group": [{
+            "atom": {
+                "URI": "mem://packing/hourly/",
+                "repo": "hourly",
+                "glob": "/(????-??-??)-??.csv",
+                "group_by": "$1",
+            }
+        }]
+    });
+    let input: Input = serde_json::from_value(json).unwrap();
+    let job_id = Uuid::new_v4();
+
+    let (datums, input_files) = input_to_datums(&[], job_id, 1, &input, None).unwrap();
+    let keys = datums
+        .iter()
+        .map(|d| d.group_key.as_deref().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(keys, &["2024-01-01", "2024-01-02", "2024-01-03"]);
+    assert_eq!(input_files.len(), 4);
+    assert!(input_files.iter().all(|f| f.job_id == job_id));
+
+    // The first group is 8 bytes, and the others are 4 bytes each.
+    let datum_set_spec = DatumSetSpec {
+        number: None,
+        size_bytes: Some(12),
+    };
+    let (datums, input_files) =
+        input_to_datums(&[], job_id, 1, &input, Some(&datum_set_spec)).unwrap();
+    assert_eq!(datums.len(), 2);
+    assert_eq!(
+        datums[0].group_key.as_deref(),
+        Some("2024-01-01,2024-01-02")
+    );
+    assert_eq!(datums[1].group_key.as_deref(), Some("2024-01-03"));
+    assert_eq!(
+        input_files
+            .iter()
+            .filter(|f| f.datum_id == datums[0].id)
+            .count(),
+        3,
+    );
+    MemoryStorage::clear("mem://packing/");
+}
+
+#[test]
+fn input_to_datums_strips_compression_extensions() {
+    use falconeri_common::storage::mem::MemoryStorage;
+
+    MemoryStorage::put("mem://decompress/logs/a.log.gz", "a");
+    MemoryStorage::put("mem://decompress/logs/b.log.zst", "b");
+    MemoryStorage::put("mem://decompress/logs/c.log", "c");
+    let json = serde_json::json!({
+        "atom": {
+            "URI": "mem://decompress/logs/",
+            "repo": "logs",
+            "glob": "/*",
+            "decompress": true,
+        }
+    });
+    let input: Input = serde_json::from_value(json).unwrap();
+
+    let (_, input_files) =
+        input_to_datums(&[], Uuid::new_v4(), 1, &input, None).unwrap();
+    let mut paths = input_files
+        .iter()
+        .map(|f| (&f.uri[..], &f.local_path[..], f.decompress))
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert_eq!(
+        paths,
+        &[
+            ("mem://decompress/logs/a.log.gz", "/pfs/logs/a.log", true),
+            ("mem://decompress/logs/b.log.zst", "/pfs/logs/b.log", true),
+            ("mem://decompress/logs/c.log", "/pfs/logs/c.log", true),
+        ]
+    );
+    MemoryStorage::clear("mem://decompress/");
+}
+
+#[test]
+fn input_to_datums_supports_single_file_http_atoms() {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    // Serve a single file, which has no manifest.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+            }
+            let response = if request_line.starts_with("HEAD /data/ref.csv.gz ") {
+                "HTTP/1.1 200 OK\r\nContent-Length: 7\r\nConnection: close\r\n\r\n"
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            };
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+
+    let uri = format!("http://{}/data/ref.csv.gz", addr);
+    for glob in &["/", "/*"] {
+        let json = serde_json::json!({
+            "atom": {
+                "URI": uri,
+                "repo": "ref",
+                "glob": glob,
+                "decompress": true,
+            }
+        });
+        let input: Input = serde_json::from_value(json).unwrap();
+        let (datums, input_files) =
+            input_to_datums(&[], Uuid::new_v4(), 1, &input, None).unwrap();
+        assert_eq!(datums.len(), 1);
+        assert_eq!(input_files.len(), 1);
+        assert_eq!(input_files[0].uri, uri);
+        assert_eq!(input_files[0].local_path, "/pfs/ref/ref.csv");
+    }
+}
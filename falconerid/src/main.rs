@@ -6,7 +6,8 @@ use falconeri_common::{
     pipeline::PipelineSpec,
     prelude::*,
     rest_api::{
-        DatumPatch, DatumReservationRequest, DatumReservationResponse, OutputFilePatch,
+        DatumHeartbeatRequest, DatumLogChunkRequest, DatumPatch,
+        DatumReservationRequest, DatumReservationResponse, OutputFilePatch,
     },
     tracing_support::initialize_tracing,
 };
@@ -14,7 +15,7 @@ use rocket::{
     get, http::Status as HttpStatus, launch, patch, post, routes, serde::json::Json,
     Config,
 };
-use std::{env, process::exit};
+use std::{collections::HashSet, env, process::exit};
 
 mod babysitter;
 pub(crate) mod inputs;
@@ -115,6 +116,56 @@ fn job_reserve_next_datum(
     }
 }
 
+/// Renew the lease on a running datum, and return the datum. If the datum is
+/// no longer running on the requesting pod, we return it unchanged, and the
+/// worker should notice that it has lost its lease.
+#[post("/datums/<datum_id>/heartbeat", data = "<request>")]
+fn datum_heartbeat(
+    _user: User,
+    mut conn: DbConn,
+    datum_id: Uuid,
+    request: Json<DatumHeartbeatRequest>,
+) -> FalconeridResult<Json<Datum>> {
+    let mut datum = Datum::find(datum_id, &mut conn)?;
+    if !datum.renew_lease(&request.pod_name, &mut conn)? {
+        warn!(
+            "pod {} sent a heartbeat for datum {}, which it no longer holds",
+            request.pod_name, datum.id,
+        );
+    }
+    Ok(Json(datum))
+}
+
+/// Append a chunk of output to the log of a running datum, keeping only the
+/// beginning and end of long logs.
+#[post("/datums/<datum_id>/log_chunks", data = "<request>")]
+fn datum_append_log_chunk(
+    _user: User,
+    mut conn: DbConn,
+    datum_id: Uuid,
+    request: Json<DatumLogChunkRequest>,
+) -> FalconeridResult<HttpStatus> {
+    let datum = Datum::find(datum_id, &mut conn)?;
+    if !datum.is_running_on(&request.pod_name) {
+        return Err(format_err!(
+            "pod {} tried to append to the log of datum {}, which it does not hold",
+            request.pod_name,
+            datum.id,
+        )
+        .into());
+    }
+    let request = request.into_inner();
+    NewDatumLogChunk {
+        datum_id: datum.id,
+        attempt: datum.attempted_run_count,
+        seq: request.seq,
+        data: request.data,
+    }
+    .insert(&mut conn)?;
+    DatumLogChunk::trim_datum_attempt(&datum, datum.attempted_run_count, &mut conn)?;
+    Ok(HttpStatus::NoContent)
+}
+
 /// Update a datum when it's done.
 #[patch("/datums/<datum_id>", data = "<patch>")]
 fn patch_datum(
@@ -131,20 +182,36 @@ fn patch_datum(
         DatumPatch {
             status: Status::Done,
             output,
+            output_log_uri,
+            output_format,
             error_message: None,
             backtrace: None,
         } => {
-            datum.mark_as_done(output, &mut conn)?;
+            datum.mark_as_done(
+                output,
+                output_log_uri.as_deref(),
+                *output_format,
+                &mut conn,
+            )?;
         }
 
         // Set status to `Status::Error`.
         DatumPatch {
             status: Status::Error,
             output,
+            output_log_uri,
+            output_format,
             error_message: Some(error_message),
             backtrace: Some(backtrace),
         } => {
-            datum.mark_as_error(output, error_message, backtrace, &mut conn)?;
+            datum.mark_as_error(
+                output,
+                output_log_uri.as_deref(),
+                *output_format,
+                error_message,
+                backtrace,
+                &mut conn,
+            )?;
         }
 
         // All other combinations are forbidden.
@@ -160,17 +227,44 @@ fn patch_datum(
     Ok(Json(datum))
 }
 
-/// Create a batch of output files.
+/// Create a batch of output files. The datums which created them must still
+/// be running on `pod_name`, so that a worker which has lost its lease can't
+/// add output to a datum which has been given to someone else. Older workers
+/// don't send `pod_name`, so we only check that their datums are running.
 ///
 /// TODO: These include `job_id` and `datum_id` values that might be nicer to
 /// move to our URL at some point.
-#[post("/output_files", data = "<new_output_files>")]
+#[post("/output_files?<pod_name>", data = "<new_output_files>")]
 fn create_output_files(
     _user: User,
     mut conn: DbConn,
+    pod_name: Option<String>,
     new_output_files: Json<Vec<NewOutputFile>>,
 ) -> FalconeridResult<Json<Vec<OutputFile>>> {
-    let created = NewOutputFile::insert_all(&new_output_files, &mut conn)?;
+    let created = conn.transaction(|conn| -> Result<Vec<OutputFile>> {
+        // Lock our datums, so that the babysitter can't take them away from
+        // us until we're done.
+        let datum_ids = new_output_files
+            .iter()
+            .map(|f| f.datum_id)
+            .collect::<HashSet<_>>();
+        for datum_id in datum_ids {
+            let mut datum = Datum::find(datum_id, conn)?;
+            datum.lock_for_update(conn)?;
+            let holds_datum = match &pod_name {
+                Some(pod_name) => datum.is_running_on(pod_name),
+                None => datum.status == Status::Running,
+            };
+            if !holds_datum {
+                return Err(format_err!(
+                    "pod {} tried to create output files for datum {}, which it does not hold",
+                    pod_name.as_deref().unwrap_or("(unknown)"),
+                    datum.id,
+                ));
+            }
+        }
+        NewOutputFile::insert_all(&new_output_files, conn)
+    })?;
     Ok(Json(created))
 }
 
@@ -233,6 +327,8 @@ fn rocket() -> _ {
                 get_job_by_name,
                 job_reserve_next_datum,
                 job_retry,
+                datum_heartbeat,
+                datum_append_log_chunk,
                 patch_datum,
                 create_output_files,
                 patch_output_files,
@@ -1,8 +1,13 @@
 // ! Code for starting a job on the server.
 
 use falconeri_common::{
-    cast, diesel::Connection, kubernetes, manifest::render_manifest, pipeline::*,
+    cast,
+    diesel::Connection,
+    kubernetes,
+    manifest::render_manifest,
+    pipeline::*,
     prelude::*,
+    storage::{CloudStorage, ObjectMetadata},
 };
 use serde_json::{self, json};
 use std::cmp::min;
@@ -11,6 +16,44 @@ use crate::inputs::input_to_datums;
 
 /// Run a new job on our cluster.
 pub fn run_job(pipeline_spec: &PipelineSpec, conn: &mut PgConnection) -> Result<Job> {
+    let prepared = prepare_job(pipeline_spec)?;
+
+    // Insert everthing into the database.
+    let mut job = conn.transaction(|conn| -> Result<Job> {
+        let job = prepared.new_job.insert(conn)?;
+        NewDatum::insert_all(&prepared.new_datums, conn)?;
+        NewInputFile::insert_all(&prepared.new_input_files, conn)?;
+        Ok(job)
+    })?;
+
+    // Now that our job exists, delete any old output we were asked to clear.
+    // If this fails, our job can't run, so mark it as failed.
+    if let Err(err) =
+        clear_egress(&*prepared.egress_storage, &prepared.stale_egress_files)
+    {
+        job.mark_as_error(conn)?;
+        return Err(err);
+    }
+
+    // Launch our batch job on the cluster.
+    start_batch_job(pipeline_spec, &job)?;
+    Ok(job)
+}
+
+/// A new job which we've checked and are ready to insert into the database.
+struct PreparedJob {
+    new_job: NewJob,
+    new_datums: Vec<NewDatum>,
+    new_input_files: Vec<NewInputFile>,
+    egress_storage: Box<dyn CloudStorage>,
+    /// Existing files in our egress, which we need to delete once our job
+    /// has been created.
+    stale_egress_files: Vec<ObjectMetadata>,
+}
+
+/// Check `pipeline_spec` and list our inputs, without changing anything. If
+/// this fails, our egress is left untouched.
+fn prepare_job(pipeline_spec: &PipelineSpec) -> Result<PreparedJob> {
     // Build our job.
     let job_id = Uuid::new_v4();
     let job_name = unique_kubernetes_job_name(&pipeline_spec.pipeline.name);
@@ -31,7 +74,9 @@ pub fn run_job(pipeline_spec: &PipelineSpec, conn: &mut PgConnection) -> Result<
             "transform": transform,
             "parallelism_spec": pipeline_spec.parallelism_spec,
             "resource_requests": pipeline_spec.resource_requests,
+            "datum_set_spec": pipeline_spec.datum_set_spec,
             "job_timeout": pipeline_spec.job_timeout.map(|timeout| timeout.as_secs()),
+            "datum_timeout": pipeline_spec.datum_timeout.map(|timeout| timeout.as_secs()),
             "node_selector": pipeline_spec.node_selector,
             "input": pipeline_spec.input,
             "egress": pipeline_spec.egress,
@@ -41,6 +86,22 @@ pub fn run_job(pipeline_spec: &PipelineSpec, conn: &mut PgConnection) -> Result<
         egress_uri: pipeline_spec.egress.uri.clone(),
     };
 
+    // Make sure we won't mix our output with the output of an older job. We
+    // also check that our egress supports any upload options we were given,
+    // so that we fail now instead of when the first datum finishes.
+    let egress_secrets = if pipeline_spec.egress.secrets.is_empty() {
+        &pipeline_spec.transform.secrets
+    } else {
+        &pipeline_spec.egress.secrets
+    };
+    let mut egress_storage =
+        <dyn CloudStorage>::for_uri(&pipeline_spec.egress.uri, egress_secrets)?;
+    egress_storage.set_upload_options(pipeline_spec.egress.upload_options()?)?;
+    let stale_egress_files = check_egress(&*egress_storage, &pipeline_spec.egress)?;
+    if pipeline_spec.egress.atomic {
+        pipeline_spec.egress.job_staging_uri(job_id)?;
+    }
+
     // Calculate how many times we're allowed to retry a datum.
     let maximum_allowed_run_count = cast::i32(pipeline_spec.datum_tries.unwrap_or(1))?;
 
@@ -50,30 +111,97 @@ pub fn run_job(pipeline_spec: &PipelineSpec, conn: &mut PgConnection) -> Result<
         job_id,
         maximum_allowed_run_count,
         &pipeline_spec.input,
+        pipeline_spec.datum_set_spec.as_ref(),
     )?;
 
-    // Insert everthing into the database.
-    let job = conn.transaction(|conn| -> Result<Job> {
-        let job = new_job.insert(conn)?;
-        NewDatum::insert_all(&new_datums, conn)?;
-        NewInputFile::insert_all(&new_input_files, conn)?;
-        Ok(job)
-    })?;
+    Ok(PreparedJob {
+        new_job,
+        new_datums,
+        new_input_files,
+        egress_storage,
+        stale_egress_files,
+    })
+}
 
-    // Launch our batch job on the cluster.
-    start_batch_job(pipeline_spec, &job)?;
-    Ok(job)
+/// Check to see whether `egress.uri` already contains files. If it does, either
+/// return them so that they can be deleted by `clear_egress` (if
+/// `egress.clear` is set), ignore them (if `egress.overwrite` is set), or
+/// return an error.
+///
+/// We ignore anything under `_falconeri_staging/`, which belongs to other
+/// jobs using `egress.atomic` with an egress URI inside ours.
+///
+/// We also refuse to upload datum logs to an `egress.log_uri` inside
+/// `egress.uri`, where they would be mixed up with our output.
+fn check_egress(
+    storage: &dyn CloudStorage,
+    egress: &Egress,
+) -> Result<Vec<ObjectMetadata>> {
+    let mut egress_dir = egress.uri.clone();
+    if !egress_dir.ends_with('/') {
+        egress_dir.push('/');
+    }
+    if let Some(log_uri) = &egress.log_uri {
+        let log_dir = format!("{}/", log_uri.trim_end_matches('/'));
+        if log_dir.starts_with(&egress_dir) {
+            return Err(format_err!(
+                "egress log URI {} must not be inside egress URI {}",
+                log_uri,
+                egress_dir,
+            ));
+        }
+    }
+    let staging_dir = format!("{}{}", egress_dir, EGRESS_STAGING_DIR);
+    let existing = storage
+        .list(&egress_dir)?
+        .into_iter()
+        .filter(|obj| !obj.uri.starts_with(&staging_dir))
+        .collect::<Vec<_>>();
+    if existing.is_empty() {
+        Ok(vec![])
+    } else if egress.clear {
+        Ok(existing)
+    } else if egress.overwrite {
+        warn!(
+            "writing to {}, which already contains {} files",
+            egress_dir,
+            existing.len()
+        );
+        Ok(vec![])
+    } else {
+        Err(format_err!(
+            "egress URI {} already contains {} files, including {}. Set \"overwrite\" or \"clear\" in \"egress\", or pass --overwrite or --clear-egress to `falconeri job run`",
+            egress_dir,
+            existing.len(),
+            existing[0].uri,
+        ))
+    }
+}
+
+/// Delete the `stale` files returned by `check_egress`.
+fn clear_egress(storage: &dyn CloudStorage, stale: &[ObjectMetadata]) -> Result<()> {
+    if !stale.is_empty() {
+        warn!("deleting {} existing files from egress", stale.len());
+    }
+    for obj in stale {
+        storage.delete(&obj.uri)?;
+    }
+    Ok(())
 }
 
 /// The `job retry` subcommand.
 pub fn retry_job(job: &Job, conn: &mut PgConnection) -> Result<Job> {
     let (pipeline_spec, new_job) = conn.transaction(|conn| -> Result<_> {
-        // Load the original job, failed datums, and input files.
+        // Load the original job, the datums we need to rerun, and their input
+        // files.
         if job.status != Status::Error {
             return Err(format_err!("can only retry jobs with status 'error'"));
         }
-        let error_datums = job.datums_with_status(Status::Error, conn)?;
-        let input_files = InputFile::for_datums(&error_datums, conn)?;
+        let mut retry_datums = vec![];
+        for &status in statuses_to_retry(job)? {
+            retry_datums.extend(job.datums_with_status(status, conn)?);
+        }
+        let input_files = InputFile::for_datums(&retry_datums, conn)?;
 
         // Recover the original pipeline specification.
         let mut pipeline_spec: PipelineSpec =
@@ -81,7 +209,7 @@ pub fn retry_job(job: &Job, conn: &mut PgConnection) -> Result<Job> {
                 .context("could not parse original pipeline spec")?;
         pipeline_spec.parallelism_spec.constant = min(
             pipeline_spec.parallelism_spec.constant,
-            cast::u32(error_datums.len())?,
+            cast::u32(retry_datums.len())?,
         );
 
         // Create a new job record.
@@ -98,7 +226,7 @@ pub fn retry_job(job: &Job, conn: &mut PgConnection) -> Result<Job> {
         // Create new datums and input files.
         let mut new_datums = vec![];
         let mut new_input_files = vec![];
-        for (old_datum, input_files) in error_datums.into_iter().zip(input_files) {
+        for (old_datum, input_files) in retry_datums.into_iter().zip(input_files) {
             let datum_id = Uuid::new_v4();
             new_datums.push(NewDatum {
                 id: datum_id,
@@ -106,6 +234,7 @@ pub fn retry_job(job: &Job, conn: &mut PgConnection) -> Result<Job> {
                 // I guess we'll give this the same number of retries it was
                 // allowed before?
                 maximum_allowed_run_count: old_datum.maximum_allowed_run_count,
+                group_key: old_datum.group_key.clone(),
             });
             for input_file in input_files {
                 new_input_files.push(NewInputFile {
@@ -113,6 +242,8 @@ pub fn retry_job(job: &Job, conn: &mut PgConnection) -> Result<Job> {
                     uri: input_file.uri.clone(),
                     local_path: input_file.local_path.clone(),
                     job_id: new_job.id,
+                    secrets: input_file.secrets.clone(),
+                    decompress: input_file.decompress,
                 });
             }
         }
@@ -127,6 +258,24 @@ pub fn retry_job(job: &Job, conn: &mut PgConnection) -> Result<Job> {
     Ok(new_job)
 }
 
+/// The statuses of the datums in `job` which `retry_job` should run again.
+/// Normally, we only rerun the datums which failed. But when a job using
+/// atomic egress fails, we delete the output of every datum, including the
+/// ones which succeeded, so we need to run all of them again.
+fn statuses_to_retry(job: &Job) -> Result<&'static [Status]> {
+    if job.staging_uri()?.is_some() {
+        Ok(&[
+            Status::Ready,
+            Status::Running,
+            Status::Done,
+            Status::Error,
+            Status::Canceled,
+        ])
+    } else {
+        Ok(&[Status::Error])
+    }
+}
+
 /// Generate a unique name for our job. To keep Kubernetes happy, this
 /// must be a legal DNS name component (but we have a database constraint
 /// to enforce that).
@@ -145,6 +294,10 @@ const RUN_MANIFEST_TEMPLATE: &str = include_str!("job_manifest.yml.hbs");
 struct JobParams<'a> {
     pipeline_spec: &'a PipelineSpec,
     job_timeout: Option<u64>,
+    /// Secrets used by our inputs and egress, which we mount under
+    /// `kubernetes::STORAGE_SECRETS_DIR`.
+    storage_secrets: Vec<String>,
+    storage_secrets_dir: &'static str,
     job: &'a Job,
 }
 
@@ -154,6 +307,8 @@ impl<'a> JobParams<'a> {
         Self {
             pipeline_spec,
             job_timeout,
+            storage_secrets: pipeline_spec.storage_secret_names(),
+            storage_secrets_dir: kubernetes::STORAGE_SECRETS_DIR,
             job,
         }
     }
@@ -172,6 +327,126 @@ pub fn start_batch_job(pipeline_spec: &PipelineSpec, job: &Job) -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn refuses_non_empty_egress() {
+    use falconeri_common::storage::mem::MemoryStorage;
+
+    let storage = <dyn CloudStorage>::for_uri("mem://start-job-egress/", &[]).unwrap();
+    let mut egress: Egress =
+        serde_json::from_str(r#"{ "URI": "mem://start-job-egress/out" }"#).unwrap();
+
+    // Empty and missing directories are fine, as are staged files.
+    check_egress(&*storage, &egress).unwrap();
+    MemoryStorage::put(
+        "mem://start-job-egress/out/_falconeri_staging/123/a.txt",
+        "staged",
+    );
+    check_egress(&*storage, &egress).unwrap();
+
+    // But we refuse to run if there are existing files.
+    MemoryStorage::put("mem://start-job-egress/out/old.txt", "old");
+    MemoryStorage::put("mem://start-job-egress/outside.txt", "outside");
+    let err = check_egress(&*storage, &egress).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("mem://start-job-egress/out/old.txt"));
+
+    // Unless we're allowed to overwrite them.
+    egress.overwrite = true;
+    check_egress(&*storage, &egress).unwrap();
+    assert!(MemoryStorage::get("mem://start-job-egress/out/old.txt").is_some());
+
+    // Or to clear them.
+    egress.overwrite = false;
+    egress.clear = true;
+    let stale = check_egress(&*storage, &egress).unwrap();
+    assert_eq!(stale.len(), 1);
+    assert!(MemoryStorage::get("mem://start-job-egress/out/old.txt").is_some());
+    clear_egress(&*storage, &stale).unwrap();
+    assert_eq!(
+        MemoryStorage::uris_with_prefix("mem://start-job-egress/"),
+        &[
+            "mem://start-job-egress/out/_falconeri_staging/123/a.txt",
+            "mem://start-job-egress/outside.txt",
+        ],
+    );
+
+    MemoryStorage::clear("mem://start-job-egress/");
+}
+
+#[test]
+fn refuses_log_uri_inside_egress() {
+    let storage = <dyn CloudStorage>::for_uri("mem://start-job-logs/", &[]).unwrap();
+    let mut egress: Egress =
+        serde_json::from_str(r#"{ "URI": "mem://start-job-logs/out" }"#).unwrap();
+
+    egress.log_uri = Some("mem://start-job-logs/out-logs/".to_owned());
+    check_egress(&*storage, &egress).unwrap();
+
+    for log_uri in &["mem://start-job-logs/out", "mem://start-job-logs/out/logs"] {
+        egress.log_uri = Some((*log_uri).to_owned());
+        let err = check_egress(&*storage, &egress).unwrap_err();
+        assert!(err.to_string().contains("must not be inside"));
+    }
+}
+
+#[test]
+fn clears_egress_only_after_listing_inputs() {
+    use falconeri_common::storage::mem::MemoryStorage;
+
+    let json = include_str!("../../falconeri_common/src/example_pipeline_spec.json");
+    let mut pipeline_spec: PipelineSpec = serde_json::from_str(json).unwrap();
+    pipeline_spec.egress = serde_json::from_str(
+        r#"{ "URI": "mem://start-job-clear/out/", "clear": true }"#,
+    )
+    .unwrap();
+    MemoryStorage::put("mem://start-job-clear/out/old.txt", "old");
+
+    // If we can't list our inputs, we leave the egress alone.
+    pipeline_spec.input = serde_json::from_str(
+        r#"{ "atom": { "URI": "bogus://in/", "repo": "in", "glob": "/*" } }"#,
+    )
+    .unwrap();
+    assert!(prepare_job(&pipeline_spec).is_err());
+    assert!(MemoryStorage::get("mem://start-job-clear/out/old.txt").is_some());
+
+    // And even if we can, we don't clear it until our job exists.
+    MemoryStorage::put("mem://start-job-clear/in/a.txt", "a");
+    pipeline_spec.input = serde_json::from_str(
+        r#"{ "atom": { "URI": "mem://start-job-clear/in/", "repo": "in", "glob": "/*" } }"#,
+    )
+    .unwrap();
+    let prepared = prepare_job(&pipeline_spec).unwrap();
+    assert_eq!(prepared.new_datums.len(), 1);
+    assert_eq!(prepared.stale_egress_files.len(), 1);
+    assert!(MemoryStorage::get("mem://start-job-clear/out/old.txt").is_some());
+
+    MemoryStorage::clear("mem://start-job-clear/");
+}
+
+#[test]
+fn retrying_atomic_jobs_reruns_every_datum() {
+    let mut job = Job::factory();
+    job.status = Status::Error;
+    assert_eq!(statuses_to_retry(&job).unwrap(), &[Status::Error]);
+
+    // After an atomic job fails, none of its output survives, so we need to
+    // rerun the datums which succeeded, too.
+    job.pipeline_spec = json!({
+        "egress": { "URI": &job.egress_uri, "atomic": true },
+    });
+    assert_eq!(
+        statuses_to_retry(&job).unwrap(),
+        &[
+            Status::Ready,
+            Status::Running,
+            Status::Done,
+            Status::Error,
+            Status::Canceled,
+        ],
+    );
+}
+
 #[test]
 fn render_template() {
     use serde_json;
@@ -189,3 +464,21 @@ fn render_template() {
     let _parsed: serde_json::Value =
         serde_yaml::from_str(&manifest).expect("rendered invalid YAML");
 }
+
+#[test]
+fn storage_secret_volume_names_are_valid() {
+    let json = include_str!("../../falconeri_common/src/example_pipeline_spec.json");
+    let mut pipeline_spec: PipelineSpec = serde_json::from_str(json).unwrap();
+    pipeline_spec.egress.secrets = serde_json::from_str(
+        r#"[{ "name": "gcs.writer", "key": "KEY", "env_var": "KEY" }]"#,
+    )
+    .unwrap();
+
+    // Secret names may contain `.`, but volume names must be DNS labels.
+    let job = Job::factory();
+    let params = JobParams::new(&pipeline_spec, &job);
+    let manifest = render_manifest(RUN_MANIFEST_TEMPLATE, &params).unwrap();
+    assert!(manifest.contains(r#"name: "storage-secret-0""#));
+    assert!(manifest.contains(r#"secretName: "gcs.writer""#));
+    assert!(!manifest.contains("storage-secret-gcs"));
+}